@@ -0,0 +1,221 @@
+#![allow(unused)]
+/* The following code is a tiny 6502 assembler, mostly to make test programs readable */
+
+use std::collections::HashMap;
+use crate::hardware::opcodes::{is_unofficial, AddressingMode, OpCode, OPCODES_MAP};
+
+// Operand as written in the source, before labels are resolved
+enum Operand {
+  None,
+  Immediate(u8),
+  Address(u16, AddressingMode), // Zero page or absolute form picked from the value's size
+  Label(String, AddressingMode),
+}
+
+// Parse a "$FF" / "255" style number
+fn parse_number(text: &str) -> Option<u16> {
+  match text.strip_prefix('$') {
+    Some(hex) => u16::from_str_radix(hex, 16).ok(),
+    None => text.parse::<u16>().ok()
+  }
+}
+
+fn is_label(text: &str) -> bool {
+  text.chars().next().is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+    && text.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+// Work out the addressing mode from the operand's syntax
+fn parse_operand(text: &str) -> Result<Operand, String> {
+  let text = text.replace(' ', "").to_uppercase();
+  if text.is_empty() {
+    return Ok(Operand::None);
+  }
+  if let Some(value) = text.strip_prefix('#') {
+    return match parse_number(value) {
+      Some(value) if value <= 0xFF => Ok(Operand::Immediate(value as u8)),
+      _ => Err(format!("bad immediate value '{}'", text))
+    };
+  }
+  if let Some(inner) = text.strip_prefix('(').and_then(|t| t.strip_suffix(",X)")) {
+    return match parse_number(inner) {
+      Some(value) if value <= 0xFF => Ok(Operand::Address(value, AddressingMode::Indirect_X)),
+      _ => Err(format!("bad indirect operand '{}'", text))
+    };
+  }
+  if let Some(inner) = text.strip_prefix('(').and_then(|t| t.strip_suffix("),Y")) {
+    return match parse_number(inner) {
+      Some(value) if value <= 0xFF => Ok(Operand::Address(value, AddressingMode::Indirect_Y)),
+      _ => Err(format!("bad indirect operand '{}'", text))
+    };
+  }
+
+  let (base, index) = match text.split_once(',') {
+    Some((base, "X")) => (base, Some('X')),
+    Some((base, "Y")) => (base, Some('Y')),
+    Some(_) => return Err(format!("bad index register in '{}'", text)),
+    None => (text.as_str(), None)
+  };
+  let absolute_mode = match index {
+    Some('X') => AddressingMode::Absolute_X,
+    Some('Y') => AddressingMode::Absolute_Y,
+    _ => AddressingMode::Absolute
+  };
+
+  if let Some(value) = parse_number(base) {
+    let zero_page = base.len() <= 3 && value <= 0xFF; // "$FF" or "255" but not "$00FF"
+    let mode = match (zero_page, index) {
+      (true, Some('X')) => AddressingMode::ZeroPage_X,
+      (true, Some('Y')) => AddressingMode::ZeroPage_Y,
+      (true, _) => AddressingMode::ZeroPage,
+      (false, _) => absolute_mode
+    };
+    return Ok(Operand::Address(value, mode));
+  }
+  if is_label(base) {
+    return Ok(Operand::Label(base.to_string(), absolute_mode));
+  }
+  Err(format!("can't parse operand '{}'", text))
+}
+
+// Find the opcode byte for a mnemonic/addressing mode pair, preferring the official encoding
+// (then the lowest byte) when several opcodes do the same thing
+fn find_opcode(name: &str, mode: AddressingMode) -> Option<(u8, u8)> {
+  OPCODES_MAP.iter()
+    .filter(|(_, OpCode(op_name, _, _, op_mode))| *op_name == name && *op_mode == mode)
+    .min_by_key(|(code, _)| (is_unofficial(**code), **code))
+    .map(|(code, OpCode(_, byte_count, _, _))| (*code, *byte_count))
+}
+
+// Zero page operands fall back to absolute addressing when the instruction has no zero page form
+fn select_opcode(name: &str, mode: AddressingMode) -> Option<(u8, u8)> {
+  find_opcode(name, mode).or_else(|| match mode {
+    AddressingMode::ZeroPage => find_opcode(name, AddressingMode::Absolute),
+    AddressingMode::ZeroPage_X => find_opcode(name, AddressingMode::Absolute_X),
+    AddressingMode::ZeroPage_Y => find_opcode(name, AddressingMode::Absolute_Y),
+    _ => None
+  })
+}
+
+// Assemble source into machine code to be loaded at `origin`
+// Supports one instruction per line, `label:` definitions and `;` comments
+pub fn assemble(source: &str, origin: u16) -> Result<Vec<u8>, String> {
+  // First pass parses every line and assigns labels their addresses
+  let mut instructions = vec![];
+  let mut labels = HashMap::new();
+  let mut pc = origin;
+
+  for (line_number, line) in source.lines().enumerate() {
+    let error = |message: String| format!("line {}: {}", line_number + 1, message);
+    let mut line = line.split(';').next().unwrap().trim();
+
+    if let Some((label, rest)) = line.split_once(':') {
+      let label = label.trim().to_uppercase();
+      if !is_label(&label) {
+        return Err(error(format!("bad label '{}'", label)));
+      }
+      labels.insert(label, pc);
+      line = rest.trim();
+    }
+    if line.is_empty() {
+      continue;
+    }
+
+    let (name, operand_text) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+    let name = name.to_uppercase();
+    let operand = parse_operand(operand_text).map_err(error)?;
+    let mode = match &operand {
+      Operand::None => AddressingMode::NoneAddressing,
+      Operand::Immediate(_) => AddressingMode::Immediate,
+      Operand::Address(_, mode) | Operand::Label(_, mode) => *mode,
+    };
+    let (code, byte_count) = select_opcode(&name, mode)
+      .ok_or_else(|| error(format!("no {:?} form of {}", mode, name)))?;
+
+    instructions.push((line_number, code, byte_count, operand));
+    pc = pc.wrapping_add(byte_count as u16);
+  }
+
+  // Second pass emits bytes now that every label is known
+  let mut program = vec![];
+  for (line_number, code, byte_count, operand) in instructions {
+    let value = match operand {
+      Operand::None => 0,
+      Operand::Immediate(value) => value as u16,
+      Operand::Address(value, _) => value,
+      Operand::Label(label, _) => *labels.get(&label)
+        .ok_or_else(|| format!("line {}: unknown label '{}'", line_number + 1, label))?
+    };
+
+    program.push(code);
+    if byte_count >= 2 {
+      program.push((value & 0xff) as u8);
+    }
+    if byte_count == 3 {
+      program.push((value >> 8) as u8);
+    }
+  }
+
+  Ok(program)
+}
+
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn test_assemble_addressing_modes() {
+    let program = assemble("
+      LDA #$C0
+      LDA $10
+      LDA $10,X
+      LDA $1234
+      LDA $1234,X
+      LDA $1234,Y
+      LDA ($20,X)
+      LDA ($20),Y
+      TAX
+      BRK
+    ", 0x8000).unwrap();
+
+    assert_eq!(program, vec![
+      0xa9, 0xc0,
+      0xa5, 0x10,
+      0xb5, 0x10,
+      0xad, 0x34, 0x12,
+      0xbd, 0x34, 0x12,
+      0xb9, 0x34, 0x12,
+      0xa1, 0x20,
+      0xb1, 0x20,
+      0xaa,
+      0x00,
+    ]);
+  }
+
+  #[test]
+  fn test_assemble_labels_and_comments() {
+    let program = assemble("
+      start:
+        INC $10 ; count up
+        JMP start
+      done: BRK
+        JMP done
+    ", 0x8000).unwrap();
+
+    assert_eq!(program, vec![0xe6, 0x10, 0x4c, 0x00, 0x80, 0x00, 0x4c, 0x05, 0x80]);
+  }
+
+  #[test]
+  fn test_assemble_promotes_zero_page_when_missing() {
+    // JMP only has an absolute form
+    assert_eq!(assemble("JMP $10", 0x8000).unwrap(), vec![0x4c, 0x10, 0x00]);
+  }
+
+  #[test]
+  fn test_assemble_errors() {
+    assert_eq!(assemble("FOO", 0x8000), Err(String::from("line 1: no NoneAddressing form of FOO")));
+    assert_eq!(assemble("\nJMP nowhere", 0x8000), Err(String::from("line 2: unknown label 'NOWHERE'")));
+    assert!(assemble("LDA #$100", 0x8000).is_err());
+  }
+}
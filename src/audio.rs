@@ -0,0 +1,228 @@
+#![allow(unused)]
+/* The following code hands APU samples to an audio device through a bounded queue */
+
+use std::collections::VecDeque;
+use std::fs;
+use std::io;
+use std::path::Path;
+use crate::hardware::cpu::CPU;
+
+// Square wave "beep" for checking audio output without a game: pulse 1 at about 440Hz,
+// 50% duty, constant volume 15, then spin forever
+pub const BEEP_PROGRAM: &str = "
+  LDA #$01
+  STA $4015
+  LDA #$BF
+  STA $4000
+  LDA #$FD
+  STA $4002
+  LDA #$00
+  STA $4003
+  loop: JMP loop
+";
+
+// Samples produced by the APU wait here until the device asks for them. The emulator pushes
+// once per frame and the device callback (or queue feeder) fills its buffers with fill
+pub struct AudioQueue {
+  samples: VecDeque<f32>,
+  capacity: usize, // Most samples held, more latency but fewer underruns
+  last_sample: f32, // Repeated while the queue is empty so underruns don't pop
+  underruns: u64, // Device requests that ran out of samples
+}
+
+impl AudioQueue {
+  // AudioQueue constructor
+  pub fn new(capacity: usize) -> Self {
+    AudioQueue {
+      samples: VecDeque::with_capacity(capacity),
+      capacity,
+      last_sample: 0.0,
+      underruns: 0,
+    }
+  }
+
+  // Size the queue to hold latency_ms worth of audio at sample_rate
+  pub fn with_latency(sample_rate: u32, latency_ms: u32) -> Self {
+    AudioQueue::new((sample_rate as u64 * latency_ms as u64 / 1000).max(1) as usize)
+  }
+
+  pub fn capacity(&self) -> usize {
+    self.capacity
+  }
+
+  pub fn len(&self) -> usize {
+    self.samples.len()
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.samples.is_empty()
+  }
+
+  pub fn underruns(&self) -> u64 {
+    self.underruns
+  }
+
+  // Queue new samples. When the device falls behind the oldest samples are dropped to keep latency bounded
+  pub fn push(&mut self, samples: &[f32]) {
+    self.samples.extend(samples);
+    let excess = self.samples.len().saturating_sub(self.capacity);
+    self.samples.drain(..excess);
+  }
+
+  // Fill a device buffer, repeating the last sample played if the queue runs dry
+  pub fn fill(&mut self, out: &mut [f32]) {
+    let available = out.len().min(self.samples.len());
+    for (slot, sample) in out.iter_mut().zip(self.samples.drain(..available)) {
+      *slot = sample;
+    }
+    if let Some(sample) = out[..available].last() {
+      self.last_sample = *sample;
+    }
+    if available < out.len() {
+      self.underruns += 1;
+      out[available..].fill(self.last_sample);
+    }
+  }
+}
+
+// Encode mono samples (-1.0 to 1.0) as a 16 bit PCM WAV file
+pub fn encode_wav(samples: &[f32], sample_rate: u32) -> Vec<u8> {
+  let data_len = samples.len() as u32 * 2;
+  let mut wav = Vec::with_capacity(44 + data_len as usize);
+  wav.extend(b"RIFF");
+  wav.extend((36 + data_len).to_le_bytes());
+  wav.extend(b"WAVE");
+
+  wav.extend(b"fmt ");
+  wav.extend(16u32.to_le_bytes());
+  wav.extend(1u16.to_le_bytes()); // PCM
+  wav.extend(1u16.to_le_bytes()); // Mono
+  wav.extend(sample_rate.to_le_bytes());
+  wav.extend((sample_rate * 2).to_le_bytes()); // Byte rate
+  wav.extend(2u16.to_le_bytes()); // Block align
+  wav.extend(16u16.to_le_bytes()); // Bits per sample
+
+  wav.extend(b"data");
+  wav.extend(data_len.to_le_bytes());
+  for sample in samples {
+    wav.extend(((sample.clamp(-1.0, 1.0) * i16::MAX as f32) as i16).to_le_bytes());
+  }
+  wav
+}
+
+// Run the loaded program for the given number of frames (or until BRK) and write the audio it
+// made to a WAV file, returning the number of samples written
+pub fn dump_audio(cpu: &mut CPU, frames: u64, path: &Path) -> io::Result<usize> {
+  let start_frame = cpu.bus.ppu.frame_count;
+  let mut samples = cpu.bus.apu.take_samples();
+  while cpu.bus.ppu.frame_count - start_frame < frames {
+    let running = cpu.step().map_err(io::Error::other)?;
+    if !running {
+      break;
+    }
+  }
+  samples.extend(cpu.bus.apu.take_samples());
+
+  fs::write(path, encode_wav(&samples, cpu.bus.apu.sample_rate()))?;
+  Ok(samples.len())
+}
+
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use crate::hardware::cpu::CPU;
+  use crate::assembler;
+
+  #[test]
+  fn test_fill_and_underrun() {
+    let mut queue = AudioQueue::new(8);
+    queue.push(&[0.1, 0.2, 0.3]);
+
+    let mut out = [0.0; 2];
+    queue.fill(&mut out);
+    assert_eq!(out, [0.1, 0.2]);
+    assert_eq!(queue.underruns(), 0);
+
+    // Running dry holds the last sample instead of dropping to silence
+    let mut out = [0.0; 4];
+    queue.fill(&mut out);
+    assert_eq!(out, [0.3, 0.3, 0.3, 0.3]);
+    assert_eq!(queue.underruns(), 1);
+    assert!(queue.is_empty());
+  }
+
+  #[test]
+  fn test_overflow_drops_oldest() {
+    let mut queue = AudioQueue::with_latency(1000, 5);
+    assert_eq!(queue.capacity(), 5);
+
+    queue.push(&[1.0, 2.0, 3.0, 4.0]);
+    queue.push(&[5.0, 6.0, 7.0]);
+    assert_eq!(queue.len(), 5);
+
+    let mut out = [0.0; 5];
+    queue.fill(&mut out);
+    assert_eq!(out, [3.0, 4.0, 5.0, 6.0, 7.0]);
+  }
+
+  #[test]
+  fn test_beep_program_plays_square_wave() {
+    let mut cpu = CPU::new();
+    cpu.load(assembler::assemble(BEEP_PROGRAM, 0x8000).unwrap());
+    cpu.reset();
+    let mut queue = AudioQueue::with_latency(cpu.bus.apu.sample_rate(), 100);
+
+    // Run a little over 3 frames (56ms), about 24 periods of the beep
+    while cpu.total_cycles() < 100_000 {
+      cpu.step().unwrap();
+    }
+    queue.push(&cpu.bus.apu.take_samples());
+    let mut out = vec![0.0; queue.len()];
+    queue.fill(&mut out);
+
+    // 440Hz at 44.1kHz is about 100 samples per period, so each period has a clean rising edge
+    let high = out.iter().cloned().fold(f32::MIN, f32::max);
+    let low = out.iter().cloned().fold(f32::MAX, f32::min);
+    assert!(high - low > 0.1);
+    let middle = (high + low) / 2.0;
+    let rising_edges = out.windows(2).filter(|pair| pair[0] < middle && pair[1] >= middle).count();
+    assert!((22..=26).contains(&rising_edges), "{} rising edges", rising_edges);
+  }
+
+  #[test]
+  fn test_wav_header() {
+    let wav = encode_wav(&[0.0, 1.0, -1.0, 0.5], 44_100);
+    let read_u32 = |at: usize| u32::from_le_bytes([wav[at], wav[at + 1], wav[at + 2], wav[at + 3]]);
+    let read_u16 = |at: usize| u16::from_le_bytes([wav[at], wav[at + 1]]);
+
+    assert_eq!(wav.len(), 44 + 8);
+    assert_eq!(&wav[0..4], b"RIFF");
+    assert_eq!(read_u32(4), 36 + 8);
+    assert_eq!(&wav[8..16], b"WAVEfmt ");
+    assert_eq!((read_u16(20), read_u16(22)), (1, 1)); // PCM, mono
+    assert_eq!((read_u32(24), read_u32(28)), (44_100, 88_200));
+    assert_eq!((read_u16(32), read_u16(34)), (2, 16));
+    assert_eq!(&wav[36..40], b"data");
+    assert_eq!(read_u32(40), 8);
+    assert_eq!(&wav[44..], &[0x00, 0x00, 0xFF, 0x7F, 0x01, 0x80, 0xFF, 0x3F]);
+  }
+
+  #[test]
+  fn test_dump_audio() {
+    let mut cpu = CPU::new();
+    cpu.load(assembler::assemble(BEEP_PROGRAM, 0x8000).unwrap());
+    cpu.reset();
+    let file = tempfile::NamedTempFile::new().unwrap();
+
+    let written = dump_audio(&mut cpu, 10, file.path()).unwrap();
+    let wav = fs::read(file.path()).unwrap();
+
+    // 10 frames at 44.1kHz, and the emulation is deterministic so the payload never changes
+    assert_eq!(written, 7337);
+    assert_eq!(wav.len(), 44 + written * 2);
+    let checksum = wav[44..].iter().fold(0u32, |sum, byte| sum.wrapping_mul(31).wrapping_add(*byte as u32));
+    assert_eq!(checksum, 0x6A85_A529);
+    assert!(wav[44..].iter().any(|byte| *byte != 0));
+  }
+}
@@ -0,0 +1,329 @@
+#![allow(unused)]
+/* The following code drives the CPU from short text commands for interactive debugging */
+
+use std::collections::HashSet;
+use crate::hardware::apu::Channel;
+use crate::hardware::cpu::{AddressingMode, CPU};
+
+const DISASSEMBLY_LINES: usize = 8; // Instructions listed by "d"
+const RUN_LIMIT: usize = 10_000_000; // Instructions "r" executes before giving up on reaching a stop
+const DUMP_ROW: usize = 16; // Bytes per line in "m" output
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DebugResponse {
+  Ok(String),
+  Error(String),
+  Halted, // The program hit BRK (or jammed), so there is nothing left to step
+}
+
+pub struct Debugger {
+  pub cpu: CPU,
+  breakpoints: HashSet<u16>,
+  watchpoints: HashSet<u16>, // Addresses whose value changing stops "r"
+  running: bool,
+}
+
+// Channels by the number "mute" and "solo" take: 1-2 pulse, 3 triangle, 4 noise, 5 DMC
+fn channel(number: u16) -> Result<Channel, String> {
+  Channel::ALL.get((number as usize).wrapping_sub(1))
+    .copied()
+    .ok_or(format!("no channel {}, expected 1-5", number))
+}
+
+// Parse a hex address or byte count, with or without a leading $
+fn parse_hex(text: &str) -> Result<u16, String> {
+  u16::from_str_radix(text.trim_start_matches('$'), 16)
+    .map_err(|_| format!("bad hex value '{}'", text))
+}
+
+impl Debugger {
+  // Debugger constructor, the CPU should already be loaded and reset
+  pub fn new(cpu: CPU) -> Self {
+    Debugger {
+      cpu,
+      breakpoints: HashSet::new(),
+      watchpoints: HashSet::new(),
+      running: true,
+    }
+  }
+
+  // Run one command and describe the result
+  pub fn command(&mut self, cmd: &str) -> DebugResponse {
+    let args: Vec<&str> = cmd.split_whitespace().collect();
+    let parsed: Result<Vec<u16>, String> = args.iter().skip(1).map(|arg| parse_hex(arg)).collect();
+    let values = match parsed {
+      Ok(values) => values,
+      Err(err) => return DebugResponse::Error(err)
+    };
+
+    match (args.first().copied(), values.as_slice()) {
+      (Some("s"), []) => self.step(),
+      (Some("r"), []) => self.run(),
+      (Some("b"), [addr]) => {
+        self.breakpoints.insert(*addr);
+        DebugResponse::Ok(format!("breakpoint set at ${:04X}", addr))
+      },
+      (Some("db"), [addr]) => {
+        if self.breakpoints.remove(addr) {
+          DebugResponse::Ok(format!("breakpoint removed at ${:04X}", addr))
+        }
+        else {
+          DebugResponse::Error(format!("no breakpoint at ${:04X}", addr))
+        }
+      },
+      (Some("w"), [addr]) => {
+        self.watchpoints.insert(*addr);
+        DebugResponse::Ok(format!("watchpoint set at ${:04X}", addr))
+      },
+      (Some("dw"), [addr]) => {
+        if self.watchpoints.remove(addr) {
+          DebugResponse::Ok(format!("watchpoint removed at ${:04X}", addr))
+        }
+        else {
+          DebugResponse::Error(format!("no watchpoint at ${:04X}", addr))
+        }
+      },
+      (Some("m"), [addr, count]) => DebugResponse::Ok(self.dump(*addr, *count)),
+      (Some("regs"), []) => DebugResponse::Ok(self.cpu.to_string()),
+      (Some("d"), [addr]) => DebugResponse::Ok(self.disassemble(*addr)),
+      (Some("mute"), [number]) => match channel(*number) {
+        Ok(channel) => {
+          let muted = !self.cpu.bus.apu.is_channel_muted(channel);
+          self.cpu.bus.apu.set_channel_mute(channel, muted);
+          DebugResponse::Ok(format!("{:?} {}", channel, if muted { "muted" } else { "unmuted" }))
+        },
+        Err(err) => DebugResponse::Error(err)
+      },
+      (Some("solo"), [number]) => match channel(*number) {
+        Ok(channel) => {
+          self.cpu.bus.apu.solo_channel(channel);
+          DebugResponse::Ok(format!("{:?} solo", channel))
+        },
+        Err(err) => DebugResponse::Error(err)
+      },
+      (Some("unmute"), []) => {
+        self.cpu.bus.apu.unmute_all();
+        DebugResponse::Ok(String::from("all channels unmuted"))
+      },
+      (Some(_), _) => DebugResponse::Error(format!("bad command '{}'", cmd.trim())),
+      (None, _) => DebugResponse::Error(String::from("empty command"))
+    }
+  }
+
+  fn step(&mut self) -> DebugResponse {
+    if !self.running {
+      return DebugResponse::Halted;
+    }
+    match self.cpu.step() {
+      Ok(true) => DebugResponse::Ok(format!("PC: ${:04X}", self.cpu.program_counter)),
+      Ok(false) => {
+        self.running = false;
+        DebugResponse::Halted
+      },
+      Err(err) => {
+        self.running = false;
+        DebugResponse::Error(err.to_string())
+      }
+    }
+  }
+
+  // Step until the PC lands on a breakpoint, a watched byte changes, or the program stops
+  fn run(&mut self) -> DebugResponse {
+    let addrs: Vec<u16> = self.watchpoints.iter().copied().collect();
+    let watched: Vec<(u16, u8)> = addrs.into_iter().map(|addr| (addr, self.cpu.bus.peek(addr))).collect();
+    for _ in 0..RUN_LIMIT {
+      match self.step() {
+        DebugResponse::Ok(_) => {},
+        stopped => return stopped
+      }
+
+      for (addr, old) in &watched {
+        let new = self.cpu.bus.peek(*addr);
+        if new != *old {
+          return DebugResponse::Ok(format!(
+            "watchpoint ${:04X} changed ${:02X} -> ${:02X}, PC: ${:04X}", addr, old, new, self.cpu.program_counter
+          ));
+        }
+      }
+      if self.breakpoints.contains(&self.cpu.program_counter) {
+        return DebugResponse::Ok(format!("breakpoint at ${:04X}", self.cpu.program_counter));
+      }
+    }
+    DebugResponse::Error(format!("no stop after {} instructions, PC: ${:04X}", RUN_LIMIT, self.cpu.program_counter))
+  }
+
+  // Hex dump of count bytes, DUMP_ROW per line
+  fn dump(&mut self, addr: u16, count: u16) -> String {
+    let bytes: Vec<u8> = (0..count).map(|i| self.cpu.bus.peek(addr.wrapping_add(i))).collect();
+    bytes.chunks(DUMP_ROW)
+      .enumerate()
+      .map(|(row, chunk)| {
+        let hex: Vec<String> = chunk.iter().map(|byte| format!("{:02X}", byte)).collect();
+        format!("${:04X}: {}", addr.wrapping_add((row * DUMP_ROW) as u16), hex.join(" "))
+      })
+      .collect::<Vec<String>>()
+      .join("\n")
+  }
+
+  // List DISASSEMBLY_LINES instructions starting at addr, in the assembler's syntax
+  fn disassemble(&mut self, addr: u16) -> String {
+    let mut lines = vec![];
+    let mut pc = addr;
+    for _ in 0..DISASSEMBLY_LINES {
+      let (text, length) = match self.cpu.peek_instruction_at(pc) {
+        Ok(peeked) => {
+          let lo = peeked.operands.first().copied().unwrap_or(0);
+          let hi = peeked.operands.get(1).copied().unwrap_or(0);
+          let operand = match (peeked.mode, peeked.operands.len()) {
+            (AddressingMode::Accumulator, _) => String::from(" A"),
+            (_, 0) => String::new(),
+            (AddressingMode::Immediate, _) => format!(" #${:02X}", lo),
+            (AddressingMode::ZeroPage, _) => format!(" ${:02X}", lo),
+            (AddressingMode::ZeroPage_X, _) => format!(" ${:02X},X", lo),
+            (AddressingMode::ZeroPage_Y, _) => format!(" ${:02X},Y", lo),
+            (AddressingMode::Absolute, _) => format!(" ${:02X}{:02X}", hi, lo),
+            (AddressingMode::Absolute_X, _) => format!(" ${:02X}{:02X},X", hi, lo),
+            (AddressingMode::Absolute_Y, _) => format!(" ${:02X}{:02X},Y", hi, lo),
+            (AddressingMode::Indirect_X, _) => format!(" (${:02X},X)", lo),
+            (AddressingMode::Indirect_Y, _) => format!(" (${:02X}),Y", lo),
+            (AddressingMode::Indirect, _) => format!(" (${:02X}{:02X})", hi, lo),
+            (AddressingMode::Relative, _) => {
+              format!(" ${:04X}", pc.wrapping_add(2).wrapping_add(lo as i8 as u16))
+            },
+            (AddressingMode::NoneAddressing, _) => String::new(),
+          };
+          (format!("{}{}", peeked.mnemonic, operand), 1 + peeked.operands.len() as u16)
+        },
+        Err(_) => (format!(".byte ${:02X}", self.cpu.bus.peek(pc)), 1)
+      };
+      lines.push(format!("${:04X}: {}", pc, text));
+      pc = pc.wrapping_add(length);
+    }
+    lines.join("\n")
+  }
+}
+
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use crate::assembler;
+
+  fn debugger(source: &str) -> Debugger {
+    let mut cpu = CPU::new();
+    cpu.load(assembler::assemble(source, 0x8000).unwrap());
+    cpu.reset();
+    Debugger::new(cpu)
+  }
+
+  #[test]
+  fn test_step_and_registers() {
+    let mut debugger = debugger("
+      LDA #$42
+      TAX
+      BRK
+    ");
+
+    assert_eq!(debugger.command("regs"), DebugResponse::Ok(String::from("A:00 X:00 Y:00 P:24 SP:FD PC:8000 CYC:7")));
+    assert_eq!(debugger.command("s"), DebugResponse::Ok(String::from("PC: $8002")));
+    assert_eq!(debugger.command("s"), DebugResponse::Ok(String::from("PC: $8003")));
+    assert_eq!(debugger.command("regs"), DebugResponse::Ok(String::from("A:42 X:42 Y:00 P:24 SP:FD PC:8003 CYC:11")));
+    assert_eq!(debugger.command("s"), DebugResponse::Halted);
+    assert_eq!(debugger.command("s"), DebugResponse::Halted);
+  }
+
+  #[test]
+  fn test_breakpoints_and_run() {
+    let mut debugger = debugger("
+      INX
+      INX
+      INX
+      BRK
+    ");
+
+    assert_eq!(debugger.command("b 8002"), DebugResponse::Ok(String::from("breakpoint set at $8002")));
+    assert_eq!(debugger.command("r"), DebugResponse::Ok(String::from("breakpoint at $8002")));
+    assert_eq!(debugger.cpu.register_x, 2);
+
+    assert_eq!(debugger.command("db $8002"), DebugResponse::Ok(String::from("breakpoint removed at $8002")));
+    assert_eq!(debugger.command("db 8002"), DebugResponse::Error(String::from("no breakpoint at $8002")));
+    assert_eq!(debugger.command("r"), DebugResponse::Halted);
+    assert_eq!(debugger.cpu.register_x, 3);
+  }
+
+  #[test]
+  fn test_watchpoints() {
+    let mut debugger = debugger("
+      INC $10
+      INX
+      INC $11
+      BRK
+    ");
+
+    debugger.command("w 11");
+    assert_eq!(debugger.command("r"), DebugResponse::Ok(String::from("watchpoint $0011 changed $00 -> $01, PC: $8005")));
+    assert_eq!(debugger.cpu.register_x, 1);
+  }
+
+  #[test]
+  fn test_memory_dump_and_disassembly() {
+    let mut debugger = debugger("
+      LDA #$01
+      STA $0200,X
+      ADC ($20),Y
+      JMP $8000
+    ");
+    for i in 0..18 {
+      debugger.cpu.bus.mem_write(0x0300 + i, i as u8);
+    }
+
+    assert_eq!(debugger.command("m 0300 12"), DebugResponse::Ok(String::from(
+      "$0300: 00 01 02 03 04 05 06 07 08 09 0A 0B 0C 0D 0E 0F\n$0310: 10 11"
+    )));
+
+    let listing = match debugger.command("d 8000") {
+      DebugResponse::Ok(listing) => listing,
+      other => panic!("unexpected response {:?}", other)
+    };
+    let lines: Vec<&str> = listing.lines().collect();
+    assert_eq!(lines.len(), DISASSEMBLY_LINES);
+    assert_eq!(&lines[..4], &["$8000: LDA #$01", "$8002: STA $0200,X", "$8005: ADC ($20),Y", "$8007: JMP $8000"]);
+  }
+
+  #[test]
+  fn test_memory_dump_has_no_side_effects() {
+    let mut debugger = debugger("BRK");
+    debugger.cpu.bus.ppu.status |= 0x80; // Vblank, which a real PPUSTATUS read would clear
+
+    assert_eq!(debugger.command("m 2002 1"), DebugResponse::Ok(String::from("$2002: 00")));
+    assert_ne!(debugger.cpu.bus.ppu.status & 0x80, 0);
+  }
+
+  #[test]
+  fn test_channel_mute_commands() {
+    let mut debugger = debugger("BRK");
+
+    assert_eq!(debugger.command("mute 3"), DebugResponse::Ok(String::from("Triangle muted")));
+    assert!(debugger.cpu.bus.apu.is_channel_muted(Channel::Triangle));
+    assert_eq!(debugger.command("mute 3"), DebugResponse::Ok(String::from("Triangle unmuted")));
+
+    assert_eq!(debugger.command("solo 1"), DebugResponse::Ok(String::from("Pulse1 solo")));
+    assert!(debugger.cpu.bus.apu.is_channel_muted(Channel::Dmc));
+    assert!(!debugger.cpu.bus.apu.is_channel_muted(Channel::Pulse1));
+    assert_eq!(debugger.command("unmute"), DebugResponse::Ok(String::from("all channels unmuted")));
+    assert!(!debugger.cpu.bus.apu.is_channel_muted(Channel::Dmc));
+
+    assert_eq!(debugger.command("mute 6"), DebugResponse::Error(String::from("no channel 6, expected 1-5")));
+    assert_eq!(debugger.command("solo 0"), DebugResponse::Error(String::from("no channel 0, expected 1-5")));
+  }
+
+  #[test]
+  fn test_bad_commands() {
+    let mut debugger = debugger("BRK");
+
+    assert_eq!(debugger.command(""), DebugResponse::Error(String::from("empty command")));
+    assert_eq!(debugger.command("x"), DebugResponse::Error(String::from("bad command 'x'")));
+    assert_eq!(debugger.command("b"), DebugResponse::Error(String::from("bad command 'b'")));
+    assert_eq!(debugger.command("b zz"), DebugResponse::Error(String::from("bad hex value 'zz'")));
+  }
+}
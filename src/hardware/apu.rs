@@ -0,0 +1,1674 @@
+#![allow(unused)]
+/* The following code emulates the Audio Processing Unit (inside the 2A03) */
+
+use std::sync::LazyLock;
+
+// $4015 bits, one per channel
+const STATUS_PULSE_1: u8 = 0b0000_0001;
+const STATUS_PULSE_2: u8 = 0b0000_0010;
+const STATUS_TRIANGLE: u8 = 0b0000_0100;
+const STATUS_NOISE: u8 = 0b0000_1000;
+const STATUS_DMC: u8 = 0b0001_0000;
+
+// $4017 bits
+const FRAME_COUNTER_FIVE_STEP: u8 = 0b1000_0000;
+const FRAME_COUNTER_IRQ_INHIBIT: u8 = 0b0100_0000;
+const FRAME_STATUS_IRQ: u8 = 0b0100_0000; // $4015 read bit for the frame interrupt
+
+// Frame counter step positions, in CPU cycles since the sequence started
+const FRAME_STEP_1: u32 = 7457;
+const FRAME_STEP_2: u32 = 14913;
+const FRAME_STEP_3: u32 = 22371;
+const FRAME_FOUR_STEP_IRQ: u32 = 29828; // The IRQ flag is raised on this cycle and the next two
+const FRAME_FOUR_STEP_4: u32 = 29829;
+const FRAME_FOUR_STEP_END: u32 = 29830;
+const FRAME_FIVE_STEP_5: u32 = 37281;
+const FRAME_FIVE_STEP_END: u32 = 37282;
+
+// Output sample clock, derived from the CPU clock so audio stays locked to emulated time
+const CPU_CLOCK_HZ: u64 = 1_789_773; // NTSC
+pub const DEFAULT_SAMPLE_RATE: u32 = 44_100;
+
+// The console's output stage: two high-pass filters and a low-pass, as (kind, cutoff in Hz)
+const OUTPUT_STAGE: [(FilterKind, f32); 3] = [
+  (FilterKind::HighPass, 90.0),
+  (FilterKind::HighPass, 440.0),
+  (FilterKind::LowPass, 14_000.0),
+];
+
+// Waveforms for the four pulse duty cycles (12.5%, 25%, 50%, 25% negated), in output order
+const DUTY_SEQUENCES: [[u8; 8]; 4] = [
+  [0, 1, 0, 0, 0, 0, 0, 0],
+  [0, 1, 1, 0, 0, 0, 0, 0],
+  [0, 1, 1, 1, 1, 0, 0, 0],
+  [1, 0, 0, 1, 1, 1, 1, 1],
+];
+
+// Length counter values selected by the top 5 bits of $4003/$4007/$400B/$400F
+const LENGTH_TABLE: [u8; 32] = [
+  10, 254, 20, 2, 40, 4, 80, 6, 160, 8, 60, 10, 14, 12, 26, 14,
+  12, 16, 24, 18, 48, 20, 96, 22, 192, 24, 72, 26, 16, 28, 32, 30,
+];
+
+// Counts a note's duration down on half frames, silencing its channel at zero.
+// Shared by every channel except the DMC, whose samples end on their own
+pub struct LengthCounter {
+  pub value: u8,
+  pub halt: bool, // Stops the count (the same bit also loops the envelope or controls the linear counter)
+  enabled: bool, // Set through $4015
+}
+
+impl LengthCounter {
+  // LengthCounter constructor
+  pub fn new() -> Self {
+    LengthCounter { value: 0, halt: false, enabled: false }
+  }
+
+  // Load from the top 5 bits of a channel's fourth register (ignored while the channel is disabled)
+  pub fn load(&mut self, data: u8) {
+    if self.enabled {
+      self.value = LENGTH_TABLE[(data >> 3) as usize];
+    }
+  }
+
+  // Enable or disable the channel through $4015 (disabling clears the counter immediately)
+  pub fn set_enabled(&mut self, enabled: bool) {
+    self.enabled = enabled;
+    if !enabled {
+      self.value = 0;
+    }
+  }
+
+  // Count down on half frames, unless halted
+  pub fn clock(&mut self) {
+    if !self.halt && self.value > 0 {
+      self.value -= 1;
+    }
+  }
+
+  pub fn is_active(&self) -> bool {
+    self.value > 0
+  }
+}
+
+// Volume generator shared by the pulse and noise channels: either a constant volume, or a
+// decay from 15 to 0 (optionally looping) stepped every volume + 1 quarter frames
+pub struct Envelope {
+  pub constant_volume: bool,
+  pub volume: u8, // Constant volume, or the divider's period
+  pub looping: bool, // Same bit as the length counter halt
+  pub decay: u8,
+  start: bool, // Set by fourth register writes, restarts the decay on the next quarter frame
+  divider: u8,
+}
+
+impl Envelope {
+  // Envelope constructor
+  pub fn new() -> Self {
+    Envelope {
+      constant_volume: false,
+      volume: 0,
+      looping: false,
+      decay: 0,
+      start: false,
+      divider: 0,
+    }
+  }
+
+  // Take the loop, constant volume and volume bits from a channel's first register
+  pub fn write_control(&mut self, data: u8) {
+    self.looping = data & 0b0010_0000 != 0;
+    self.constant_volume = data & 0b0001_0000 != 0;
+    self.volume = data & 0b0000_1111;
+  }
+
+  pub fn restart(&mut self) {
+    self.start = true;
+  }
+
+  // Clock on quarter frames
+  pub fn clock(&mut self) {
+    if self.start {
+      self.start = false;
+      self.decay = 15;
+      self.divider = self.volume;
+    }
+    else if self.divider == 0 {
+      self.divider = self.volume;
+      if self.decay > 0 {
+        self.decay -= 1;
+      }
+      else if self.looping {
+        self.decay = 15;
+      }
+    }
+    else {
+      self.divider -= 1;
+    }
+  }
+
+  // Current volume (0-15)
+  pub fn output(&self) -> u8 {
+    if self.constant_volume { self.volume } else { self.decay }
+  }
+}
+
+// Pulse sweep settings from $4001/$4005
+pub struct Sweep {
+  pub enabled: bool,
+  pub period: u8, // Divider period in half frames, minus one
+  pub negate: bool,
+  pub shift: u8,
+  pub ones_complement: bool, // Pulse 1 negates with one's complement (subtracting one extra)
+  reload: bool, // Set by register writes, reloads the divider on the next half frame
+  divider: u8,
+}
+
+impl Sweep {
+  // Sweep constructor
+  pub fn new(ones_complement: bool) -> Self {
+    Sweep {
+      enabled: false,
+      period: 0,
+      negate: false,
+      shift: 0,
+      ones_complement,
+      reload: false,
+      divider: 0,
+    }
+  }
+
+  pub fn write(&mut self, data: u8) {
+    self.enabled = data & 0b1000_0000 != 0;
+    self.period = (data >> 4) & 0b111;
+    self.negate = data & 0b0000_1000 != 0;
+    self.shift = data & 0b0000_0111;
+    self.reload = true;
+  }
+}
+
+// One of the two square wave channels
+pub struct Pulse {
+  pub duty: u8, // Index into DUTY_SEQUENCES
+  pub envelope: Envelope,
+  pub sweep: Sweep,
+  pub timer_period: u16, // 11 bits
+  pub length: LengthCounter,
+  timer: u16,
+  sequence_step: usize,
+}
+
+impl Pulse {
+  // Pulse constructor, channel is 1 or 2 (they differ in how the sweep negates)
+  pub fn new(channel: u8) -> Self {
+    Pulse {
+      duty: 0,
+      envelope: Envelope::new(),
+      sweep: Sweep::new(channel == 1),
+      timer_period: 0,
+      length: LengthCounter::new(),
+      timer: 0,
+      sequence_step: 0,
+    }
+  }
+
+  // Handle a write to one of the channel's four registers
+  pub fn write_register(&mut self, register: usize, data: u8) {
+    match register {
+      0 => {
+        self.duty = data >> 6;
+        self.length.halt = data & 0b0010_0000 != 0;
+        self.envelope.write_control(data);
+      },
+      1 => { self.sweep.write(data); },
+      2 => { self.timer_period = (self.timer_period & 0x0700) | data as u16; },
+      _ => {
+        self.timer_period = (self.timer_period & 0x00FF) | ((data as u16 & 0b111) << 8);
+        self.length.load(data);
+        // Restart the envelope and the duty sequence
+        self.envelope.restart();
+        self.sequence_step = 0;
+      }
+    }
+  }
+
+  // Clock the timer once per APU cycle, stepping the sequencer when it runs out
+  pub fn clock_timer(&mut self) {
+    if self.timer == 0 {
+      self.timer = self.timer_period;
+      self.sequence_step = (self.sequence_step + 1) % 8;
+    }
+    else {
+      self.timer -= 1;
+    }
+  }
+
+  // Period the sweep unit is heading for: the current one plus or minus itself shifted right
+  pub fn sweep_target_period(&self) -> u16 {
+    let change = self.timer_period >> self.sweep.shift;
+    if !self.sweep.negate {
+      self.timer_period + change
+    }
+    else if self.sweep.ones_complement {
+      self.timer_period.saturating_sub(change + 1)
+    }
+    else {
+      self.timer_period.saturating_sub(change)
+    }
+  }
+
+  // The sweep silences the channel for periods under 8 (which would be ultrasonic) and for targets
+  // past 11 bits, even while it isn't enabled
+  pub fn sweep_muting(&self) -> bool {
+    self.timer_period < 8 || self.sweep_target_period() > 0x7FF
+  }
+
+  // Clock on half frames, moving the period to the target each time the divider runs out
+  pub fn clock_sweep(&mut self) {
+    if self.sweep.divider == 0 && self.sweep.enabled && self.sweep.shift > 0 && !self.sweep_muting() {
+      self.timer_period = self.sweep_target_period();
+    }
+    if self.sweep.divider == 0 || self.sweep.reload {
+      self.sweep.divider = self.sweep.period;
+      self.sweep.reload = false;
+    }
+    else {
+      self.sweep.divider -= 1;
+    }
+  }
+
+  // Current output level (0-15)
+  pub fn output(&self) -> u8 {
+    if !self.length.is_active() || self.sweep_muting() || DUTY_SEQUENCES[self.duty as usize][self.sequence_step] == 0 {
+      return 0;
+    }
+    self.envelope.output()
+  }
+}
+
+// The triangle steps through 15 down to 0 and back up
+const TRIANGLE_SEQUENCE: [u8; 32] = [
+  15, 14, 13, 12, 11, 10, 9, 8, 7, 6, 5, 4, 3, 2, 1, 0,
+  0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15,
+];
+
+// Triangle wave channel, gated by both its length counter and a finer grained linear counter
+pub struct Triangle {
+  pub linear_counter_reload: u8, // $4008 low 7 bits
+  pub linear_counter: u8,
+  pub timer_period: u16, // 11 bits
+  pub length: LengthCounter, // Halted by the $4008 control bit
+  linear_reload: bool, // Set by $400B writes
+  timer: u16,
+  sequence_step: usize,
+}
+
+impl Triangle {
+  // Triangle constructor
+  pub fn new() -> Self {
+    Triangle {
+      linear_counter_reload: 0,
+      linear_counter: 0,
+      timer_period: 0,
+      length: LengthCounter::new(),
+      linear_reload: false,
+      timer: 0,
+      sequence_step: 0,
+    }
+  }
+
+  // Handle a write to one of the channel's four registers ($4009 is unused)
+  pub fn write_register(&mut self, register: usize, data: u8) {
+    match register {
+      0 => {
+        self.length.halt = data & 0b1000_0000 != 0;
+        self.linear_counter_reload = data & 0b0111_1111;
+      },
+      1 => {},
+      2 => { self.timer_period = (self.timer_period & 0x0700) | data as u16; },
+      _ => {
+        self.timer_period = (self.timer_period & 0x00FF) | ((data as u16 & 0b111) << 8);
+        self.length.load(data);
+        self.linear_reload = true;
+      }
+    }
+  }
+
+  // Clock the linear counter on quarter frames (the control bit keeps it reloading)
+  pub fn clock_linear_counter(&mut self) {
+    if self.linear_reload {
+      self.linear_counter = self.linear_counter_reload;
+    }
+    else if self.linear_counter > 0 {
+      self.linear_counter -= 1;
+    }
+    if !self.length.halt {
+      self.linear_reload = false;
+    }
+  }
+
+  // Clock the timer once per CPU cycle, stepping the sequence while both counters are running
+  pub fn clock_timer(&mut self) {
+    if self.timer == 0 {
+      self.timer = self.timer_period;
+      if self.linear_counter > 0 && self.length.is_active() {
+        self.sequence_step = (self.sequence_step + 1) % 32;
+      }
+    }
+    else {
+      self.timer -= 1;
+    }
+  }
+
+  // Current output level (0-15). Silencing only stops the sequencer, so the level holds
+  pub fn output(&self) -> u8 {
+    TRIANGLE_SEQUENCE[self.sequence_step]
+  }
+}
+
+// Mixer lookup tables from the nonlinear DAC approximation, indexed by summed channel levels
+static PULSE_TABLE: LazyLock<[f32; 31]> = LazyLock::new(|| {
+  let mut table = [0.0; 31];
+  for (n, entry) in table.iter_mut().enumerate().skip(1) {
+    *entry = 95.52 / (8128.0 / n as f32 + 100.0);
+  }
+  table
+});
+
+static TND_TABLE: LazyLock<[f32; 203]> = LazyLock::new(|| {
+  let mut table = [0.0; 203];
+  for (n, entry) in table.iter_mut().enumerate().skip(1) {
+    *entry = 163.67 / (24329.0 / n as f32 + 100.0);
+  }
+  table
+});
+
+// Noise timer periods in CPU cycles, selected by the low 4 bits of $400E
+const NOISE_PERIODS: [u16; 16] = [4, 8, 16, 32, 64, 96, 128, 160, 202, 254, 380, 508, 762, 1016, 2034, 4068];
+
+// Pseudo-random channel driven by a 15 bit linear-feedback shift register
+pub struct Noise {
+  pub envelope: Envelope,
+  pub short_mode: bool, // Feedback from bit 6 instead of bit 1, for a 93 step metallic loop
+  pub timer_period: u16, // In CPU cycles
+  pub length: LengthCounter,
+  pub shift_register: u16,
+  timer: u16,
+}
+
+impl Noise {
+  // Noise constructor
+  pub fn new() -> Self {
+    Noise {
+      envelope: Envelope::new(),
+      short_mode: false,
+      timer_period: NOISE_PERIODS[0],
+      length: LengthCounter::new(),
+      shift_register: 1, // Loaded with 1 at power on
+      timer: 0,
+    }
+  }
+
+  // Handle a write to one of the channel's four registers ($400D is unused)
+  pub fn write_register(&mut self, register: usize, data: u8) {
+    match register {
+      0 => {
+        self.length.halt = data & 0b0010_0000 != 0;
+        self.envelope.write_control(data);
+      },
+      1 => {},
+      2 => {
+        self.short_mode = data & 0b1000_0000 != 0;
+        self.timer_period = NOISE_PERIODS[(data & 0b0000_1111) as usize];
+      },
+      _ => {
+        self.length.load(data);
+        self.envelope.restart();
+      }
+    }
+  }
+
+  // Clock the timer once per CPU cycle, shifting the LFSR when it runs out
+  pub fn clock_timer(&mut self) {
+    if self.timer == 0 {
+      self.timer = self.timer_period - 1;
+      self.clock_lfsr();
+    }
+    else {
+      self.timer -= 1;
+    }
+  }
+
+  // Shift the LFSR once, feeding bit 0 XOR bit 1 (or bit 6 in short mode) into bit 14
+  pub fn clock_lfsr(&mut self) {
+    let tap = if self.short_mode { 6 } else { 1 };
+    let feedback = (self.shift_register ^ (self.shift_register >> tap)) & 1;
+    self.shift_register = (self.shift_register >> 1) | (feedback << 14);
+  }
+
+  // Current output level (0-15), silent while bit 0 of the LFSR is set
+  pub fn output(&self) -> u8 {
+    if !self.length.is_active() || self.shift_register & 1 != 0 {
+      return 0;
+    }
+    self.envelope.output()
+  }
+}
+
+// DMC output rates in CPU cycles per bit, selected by the low 4 bits of $4010
+const DMC_RATES: [u16; 16] = [428, 380, 340, 320, 286, 254, 226, 214, 190, 160, 142, 128, 106, 84, 72, 54];
+const DMC_STATUS_IRQ: u8 = 0b1000_0000; // $4015 read bit for the DMC interrupt
+
+// Delta modulation channel, plays 1 bit delta samples fetched from CPU memory
+pub struct Dmc {
+  pub irq_enabled: bool,
+  pub loop_flag: bool,
+  pub timer_period: u16, // In CPU cycles
+  pub output_level: u8, // 7 bits
+  pub sample_address: u16, // Start of the sample, $C000-$FFC0
+  pub sample_length: u16, // In bytes
+  pub current_address: u16,
+  pub bytes_remaining: u16,
+  pub irq_flag: bool,
+  sample_buffer: Option<u8>,
+  shift_register: u8,
+  bits_remaining: u8,
+  silence: bool,
+  timer: u16,
+}
+
+impl Dmc {
+  // Dmc constructor
+  pub fn new() -> Self {
+    Dmc {
+      irq_enabled: false,
+      loop_flag: false,
+      timer_period: DMC_RATES[0],
+      output_level: 0,
+      sample_address: 0xC000,
+      sample_length: 1,
+      current_address: 0xC000,
+      bytes_remaining: 0,
+      irq_flag: false,
+      sample_buffer: None,
+      shift_register: 0,
+      bits_remaining: 8,
+      silence: true,
+      timer: 0,
+    }
+  }
+
+  // Handle a write to one of the channel's four registers
+  pub fn write_register(&mut self, register: usize, data: u8) {
+    match register {
+      0 => {
+        self.irq_enabled = data & 0b1000_0000 != 0;
+        self.loop_flag = data & 0b0100_0000 != 0;
+        self.timer_period = DMC_RATES[(data & 0b0000_1111) as usize];
+        if !self.irq_enabled {
+          self.irq_flag = false;
+        }
+      },
+      1 => { self.output_level = data & 0b0111_1111; },
+      2 => { self.sample_address = 0xC000 + data as u16 * 64; },
+      _ => { self.sample_length = data as u16 * 16 + 1; }
+    }
+  }
+
+  // Enable ($4015 bit 4) restarts the sample if it had finished, disable stops it after the buffered byte
+  pub fn set_enabled(&mut self, enabled: bool) {
+    self.irq_flag = false;
+    if !enabled {
+      self.bytes_remaining = 0;
+    }
+    else if self.bytes_remaining == 0 {
+      self.restart();
+    }
+  }
+
+  fn restart(&mut self) {
+    self.current_address = self.sample_address;
+    self.bytes_remaining = self.sample_length;
+  }
+
+  // Address the memory reader needs a byte from, if its buffer is empty and the sample isn't over
+  pub fn fetch_address(&self) -> Option<u16> {
+    if self.sample_buffer.is_none() && self.bytes_remaining > 0 {
+      Some(self.current_address)
+    }
+    else {
+      None
+    }
+  }
+
+  // Hand the reader the byte fetched from fetch_address
+  pub fn load_sample_byte(&mut self, data: u8) {
+    self.sample_buffer = Some(data);
+    self.current_address = if self.current_address == 0xFFFF { 0x8000 } else { self.current_address + 1 };
+    self.bytes_remaining -= 1;
+    if self.bytes_remaining == 0 {
+      if self.loop_flag {
+        self.restart();
+      }
+      else if self.irq_enabled {
+        self.irq_flag = true;
+      }
+    }
+  }
+
+  // Clock the timer once per CPU cycle, moving the output level one bit's delta when it runs out
+  pub fn clock_timer(&mut self) {
+    if self.timer > 0 {
+      self.timer -= 1;
+      return;
+    }
+    self.timer = self.timer_period - 1;
+
+    if !self.silence {
+      if self.shift_register & 1 != 0 {
+        if self.output_level <= 125 {
+          self.output_level += 2;
+        }
+      }
+      else if self.output_level >= 2 {
+        self.output_level -= 2;
+      }
+    }
+    self.shift_register >>= 1;
+
+    self.bits_remaining -= 1;
+    if self.bits_remaining == 0 {
+      self.bits_remaining = 8;
+      match self.sample_buffer.take() {
+        Some(data) => {
+          self.shift_register = data;
+          self.silence = false;
+        },
+        None => { self.silence = true; }
+      }
+    }
+  }
+
+  // Current output level (0-127)
+  pub fn output(&self) -> u8 {
+    self.output_level
+  }
+}
+
+// Frame counter sequence length
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FrameCounterMode {
+  FourStep,
+  FiveStep,
+}
+
+// Which unit clocks a frame counter step produced (a half frame also clocks the quarter frame units)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FrameClock {
+  None,
+  Quarter, // Envelopes and the triangle's linear counter
+  Half, // Plus length counters and sweeps
+}
+
+// The five sound channels, in register order
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Channel {
+  Pulse1,
+  Pulse2,
+  Triangle,
+  Noise,
+  Dmc,
+}
+
+impl Channel {
+  pub const ALL: [Channel; 5] = [Channel::Pulse1, Channel::Pulse2, Channel::Triangle, Channel::Noise, Channel::Dmc];
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FilterKind {
+  HighPass,
+  LowPass,
+}
+
+// First-order RC filter run on output samples
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AudioFilter {
+  pub kind: FilterKind,
+  pub cutoff_hz: f32,
+  alpha: f32,
+  prev_input: f32,
+  prev_output: f32,
+}
+
+impl AudioFilter {
+  // AudioFilter constructor
+  pub fn new(kind: FilterKind, cutoff_hz: f32, sample_rate: u32) -> Self {
+    let rc = 1.0 / (2.0 * std::f32::consts::PI * cutoff_hz);
+    let dt = 1.0 / sample_rate as f32;
+    let alpha = match kind {
+      FilterKind::HighPass => rc / (rc + dt),
+      FilterKind::LowPass => dt / (rc + dt),
+    };
+    AudioFilter { kind, cutoff_hz, alpha, prev_input: 0.0, prev_output: 0.0 }
+  }
+
+  pub fn process(&mut self, input: f32) -> f32 {
+    let output = match self.kind {
+      FilterKind::HighPass => self.alpha * (self.prev_output + input - self.prev_input),
+      FilterKind::LowPass => self.prev_output + self.alpha * (input - self.prev_output),
+    };
+    self.prev_input = input;
+    self.prev_output = output;
+    output
+  }
+}
+
+pub struct Apu {
+  pub pulse_1: Pulse, // $4000-$4003
+  pub pulse_2: Pulse, // $4004-$4007
+  pub triangle: Triangle, // $4008-$400B
+  pub noise: Noise, // $400C-$400F
+  pub dmc: Dmc, // $4010-$4013
+  pub frame_counter_mode: FrameCounterMode,
+  pub irq_inhibit: bool,
+  pub frame_irq: bool,
+  pub cycles: u64, // CPU cycles since power on
+  frame_cycle: u32, // CPU cycles into the current frame counter sequence
+  frame_reset_delay: u8, // CPU cycles until a $4017 write restarts the sequence (0 when none is pending)
+  sample_rate: u32, // Output samples per second
+  sample_phase: u64, // Accumulates sample_rate per CPU cycle, a sample is due every CPU_CLOCK_HZ
+  sample_sum: f32, // Mixer output summed over the cycles since the last sample
+  sample_cycles: u32,
+  samples: Vec<f32>, // Produced samples waiting for take_samples
+  filters: Vec<AudioFilter>, // Applied to each sample in order, the console's output stage by default
+  pub filtering: bool,
+  muted: [bool; 5], // Indexed like Channel::ALL, only silences the channel in the mix
+}
+
+impl Apu {
+  // APU constructor
+  pub fn new() -> Self {
+    Apu {
+      pulse_1: Pulse::new(1),
+      pulse_2: Pulse::new(2),
+      triangle: Triangle::new(),
+      noise: Noise::new(),
+      dmc: Dmc::new(),
+      frame_counter_mode: FrameCounterMode::FourStep,
+      irq_inhibit: false,
+      frame_irq: false,
+      cycles: 0,
+      frame_cycle: 0,
+      frame_reset_delay: 0,
+      sample_rate: DEFAULT_SAMPLE_RATE,
+      sample_phase: 0,
+      sample_sum: 0.0,
+      sample_cycles: 0,
+      samples: vec![],
+      filters: Apu::output_stage(DEFAULT_SAMPLE_RATE),
+      filtering: true,
+      muted: [false; 5],
+    }
+  }
+
+  // Mute or unmute a channel in the mixer. The channel keeps running, so nothing the CPU can
+  // see ($4015, IRQs, DMC fetches) changes
+  pub fn set_channel_mute(&mut self, channel: Channel, muted: bool) {
+    self.muted[channel as usize] = muted;
+  }
+
+  pub fn is_channel_muted(&self, channel: Channel) -> bool {
+    self.muted[channel as usize]
+  }
+
+  // Mute every channel except one
+  pub fn solo_channel(&mut self, channel: Channel) {
+    for other in Channel::ALL {
+      self.set_channel_mute(other, other != channel);
+    }
+  }
+
+  pub fn unmute_all(&mut self) {
+    self.muted = [false; 5];
+  }
+
+  fn output_stage(sample_rate: u32) -> Vec<AudioFilter> {
+    OUTPUT_STAGE.iter().map(|(kind, cutoff_hz)| AudioFilter::new(*kind, *cutoff_hz, sample_rate)).collect()
+  }
+
+  // Replace the filter chain (an empty chain or filtering = false passes the mixer output through)
+  pub fn set_filters(&mut self, filters: Vec<AudioFilter>) {
+    self.filters = filters;
+  }
+
+  // Change the output sample rate, dropping any partially built sample. The filter chain is
+  // rebuilt for the new rate with the same kinds and cutoffs
+  pub fn set_sample_rate(&mut self, sample_rate: u32) {
+    self.filters = self.filters.iter().map(|filter| AudioFilter::new(filter.kind, filter.cutoff_hz, sample_rate)).collect();
+    self.sample_rate = sample_rate;
+    self.sample_phase = 0;
+    self.sample_sum = 0.0;
+    self.sample_cycles = 0;
+  }
+
+  pub fn sample_rate(&self) -> u32 {
+    self.sample_rate
+  }
+
+  // Drain the samples produced since the last call
+  pub fn take_samples(&mut self) -> Vec<f32> {
+    std::mem::take(&mut self.samples)
+  }
+
+  // Resample by averaging the mixer output over every CPU cycle of a sample period (a box filter,
+  // which keeps harmonics above the output rate from aliasing the way picking every Nth value
+  // would), then run the average through the filter chain
+  fn accumulate_sample(&mut self) {
+    self.sample_sum += self.output();
+    self.sample_cycles += 1;
+    self.sample_phase += self.sample_rate as u64;
+    if self.sample_phase >= CPU_CLOCK_HZ {
+      self.sample_phase -= CPU_CLOCK_HZ;
+      let mut sample = self.sample_sum / self.sample_cycles as f32;
+      if self.filtering {
+        for filter in self.filters.iter_mut() {
+          sample = filter.process(sample);
+        }
+      }
+      self.samples.push(sample);
+      self.sample_sum = 0.0;
+      self.sample_cycles = 0;
+    }
+  }
+
+  // Handle CPU writes to $4000-$4013, $4015 and $4017
+  pub fn cpu_write(&mut self, addr: u16, data: u8) {
+    let register = (addr & 0b11) as usize;
+    match addr {
+      0x4000 ..= 0x4003 => { self.pulse_1.write_register(register, data); },
+      0x4004 ..= 0x4007 => { self.pulse_2.write_register(register, data); },
+      0x4008 ..= 0x400B => { self.triangle.write_register(register, data); },
+      0x400C ..= 0x400F => { self.noise.write_register(register, data); },
+      0x4010 ..= 0x4013 => { self.dmc.write_register(register, data); },
+      0x4015 => {
+        self.pulse_1.length.set_enabled(data & STATUS_PULSE_1 != 0);
+        self.pulse_2.length.set_enabled(data & STATUS_PULSE_2 != 0);
+        self.triangle.length.set_enabled(data & STATUS_TRIANGLE != 0);
+        self.noise.length.set_enabled(data & STATUS_NOISE != 0);
+        self.dmc.set_enabled(data & STATUS_DMC != 0);
+      },
+      0x4017 => {
+        self.frame_counter_mode = if data & FRAME_COUNTER_FIVE_STEP != 0 {
+          FrameCounterMode::FiveStep
+        }
+        else {
+          FrameCounterMode::FourStep
+        };
+        self.irq_inhibit = data & FRAME_COUNTER_IRQ_INHIBIT != 0;
+        if self.irq_inhibit {
+          self.frame_irq = false;
+        }
+        // The sequence restarts 3 CPU cycles later if the write lands on an APU cycle, 4 if between
+        self.frame_reset_delay = if self.cycles.is_multiple_of(2) { 3 } else { 4 };
+      },
+      _ => {}
+    }
+  }
+
+  // Handle CPU reads from $4015 (everything else in the APU is write-only)
+  // Channels report whether their length counter is nonzero and the DMC whether its sample still
+  // has bytes left, then come both interrupt flags. Reading acknowledges the frame interrupt
+  pub fn cpu_read(&mut self, addr: u16) -> u8 {
+    match addr {
+      0x4015 => {
+        let mut status = 0;
+        for (bit, length) in [
+          (STATUS_PULSE_1, &self.pulse_1.length),
+          (STATUS_PULSE_2, &self.pulse_2.length),
+          (STATUS_TRIANGLE, &self.triangle.length),
+          (STATUS_NOISE, &self.noise.length),
+        ] {
+          if length.is_active() {
+            status |= bit;
+          }
+        }
+        if self.dmc.bytes_remaining > 0 {
+          status |= STATUS_DMC;
+        }
+        if self.frame_irq {
+          status |= FRAME_STATUS_IRQ;
+        }
+        if self.dmc.irq_flag {
+          status |= DMC_STATUS_IRQ;
+        }
+        self.frame_irq = false;
+        status
+      },
+      _ => 0
+    }
+  }
+
+  // Returns true while the APU is holding the CPU's IRQ line low
+  pub fn irq_pending(&self) -> bool {
+    self.frame_irq || self.dmc.irq_flag
+  }
+
+  // Advance the frame counter by one CPU cycle, clocking the channels' units on its steps
+  pub fn step_frame_counter(&mut self) -> FrameClock {
+    let restart = self.frame_reset_delay == 1;
+    self.frame_reset_delay = self.frame_reset_delay.saturating_sub(1);
+
+    let clock = if restart {
+      // Restarting in 5-step mode clocks everything straight away
+      self.frame_cycle = 0;
+      match self.frame_counter_mode {
+        FrameCounterMode::FiveStep => FrameClock::Half,
+        FrameCounterMode::FourStep => FrameClock::None
+      }
+    }
+    else {
+      self.frame_cycle += 1;
+      self.frame_sequence_step()
+    };
+
+    if clock != FrameClock::None {
+      self.pulse_1.envelope.clock();
+      self.pulse_2.envelope.clock();
+      self.noise.envelope.clock();
+      self.triangle.clock_linear_counter();
+    }
+    match clock {
+      FrameClock::Half => {
+        self.pulse_1.length.clock();
+        self.pulse_2.length.clock();
+        self.pulse_1.clock_sweep();
+        self.pulse_2.clock_sweep();
+        self.triangle.length.clock();
+        self.noise.length.clock();
+      },
+      FrameClock::Quarter | FrameClock::None => {}
+    }
+    clock
+  }
+
+  // Clocks for the current position in the sequence, raising the IRQ and wrapping around at the end
+  fn frame_sequence_step(&mut self) -> FrameClock {
+    match (self.frame_counter_mode, self.frame_cycle) {
+      (_, FRAME_STEP_1) | (_, FRAME_STEP_3) => FrameClock::Quarter,
+      (_, FRAME_STEP_2) => FrameClock::Half,
+      (FrameCounterMode::FourStep, FRAME_FOUR_STEP_IRQ ..= FRAME_FOUR_STEP_END) => {
+        if !self.irq_inhibit {
+          self.frame_irq = true;
+        }
+        if self.frame_cycle == FRAME_FOUR_STEP_END {
+          self.frame_cycle = 0;
+        }
+        if self.frame_cycle == FRAME_FOUR_STEP_4 { FrameClock::Half } else { FrameClock::None }
+      },
+      (FrameCounterMode::FiveStep, FRAME_FIVE_STEP_5) => FrameClock::Half,
+      (FrameCounterMode::FiveStep, FRAME_FIVE_STEP_END) => {
+        self.frame_cycle = 0;
+        FrameClock::None
+      },
+      _ => FrameClock::None
+    }
+  }
+
+  // Mix the channels into one sample between 0.0 and 1.0 with the nonlinear DAC approximation
+  pub fn output(&self) -> f32 {
+    let level = |channel: Channel, output: u8| if self.is_channel_muted(channel) { 0 } else { output as usize };
+    let pulse = level(Channel::Pulse1, self.pulse_1.output()) + level(Channel::Pulse2, self.pulse_2.output());
+    let tnd = 3 * level(Channel::Triangle, self.triangle.output())
+      + 2 * level(Channel::Noise, self.noise.output())
+      + level(Channel::Dmc, self.dmc.output());
+    PULSE_TABLE[pulse] + TND_TABLE[tnd]
+  }
+
+  // Instantaneous mix of every channel (ignoring mutes) with the exact nonlinear DAC formulas
+  // rather than the lookup tables, for inspecting channel levels in tests
+  pub fn apu_output_level(&self) -> f32 {
+    let pulse = (self.pulse_1.output() + self.pulse_2.output()) as f32;
+    let pulse_out = if pulse == 0.0 { 0.0 } else { 95.88 / (8128.0 / pulse + 100.0) };
+
+    let tnd = self.triangle.output() as f32 / 8227.0
+      + self.noise.output() as f32 / 12241.0
+      + self.dmc.output() as f32 / 22638.0;
+    let tnd_out = if tnd == 0.0 { 0.0 } else { 159.79 / (1.0 / tnd + 100.0) };
+    pulse_out + tnd_out
+  }
+
+  // Advance the APU's clocks by the given number of CPU cycles (the pulse timers run every other cycle)
+  pub fn tick(&mut self, cpu_cycles: u16) {
+    for _ in 0..cpu_cycles {
+      self.cycles += 1;
+      self.step_frame_counter();
+      self.triangle.clock_timer();
+      self.noise.clock_timer();
+      self.dmc.clock_timer();
+      if self.cycles.is_multiple_of(2) {
+        self.pulse_1.clock_timer();
+        self.pulse_2.clock_timer();
+      }
+      self.accumulate_sample();
+    }
+  }
+}
+
+impl Default for Apu {
+  fn default() -> Self {
+    Apu::new()
+  }
+}
+
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn test_channel_registers_are_stored() {
+    let mut apu = Apu::new();
+    apu.cpu_write(0x4000, 0xBF);
+    apu.cpu_write(0x4007, 0x08);
+    apu.cpu_write(0x400A, 0x42);
+    apu.cpu_write(0x400E, 0x05);
+    apu.cpu_write(0x4013, 0x10);
+
+    assert_eq!(apu.pulse_1.duty, 2);
+    assert!(apu.pulse_1.length.halt);
+    assert!(apu.pulse_1.envelope.constant_volume);
+    assert_eq!(apu.pulse_1.envelope.volume, 0x0F);
+    assert_eq!(apu.pulse_2.timer_period, 0x0000);
+    assert_eq!(apu.triangle.timer_period, 0x42);
+    assert_eq!(apu.noise.timer_period, 96);
+    assert!(!apu.noise.short_mode);
+    assert_eq!(apu.dmc.sample_length, 0x10 * 16 + 1);
+  }
+
+  #[test]
+  fn test_status_write_and_read() {
+    let mut apu = Apu::new();
+    apu.cpu_write(0x4015, 0xFF);
+    // Only the DMC is active until the other channels load their length counters
+    assert_eq!(apu.cpu_read(0x4015), STATUS_DMC);
+    for addr in [0x4003, 0x4007, 0x400B, 0x400F] {
+      apu.cpu_write(addr, 0x08);
+    }
+    assert_eq!(apu.cpu_read(0x4015), STATUS_PULSE_1 | STATUS_PULSE_2 | STATUS_TRIANGLE | STATUS_NOISE | STATUS_DMC);
+
+    apu.cpu_write(0x4015, STATUS_NOISE);
+    assert_eq!(apu.cpu_read(0x4015), STATUS_NOISE);
+  }
+
+  #[test]
+  fn test_status_write_toggles_channels() {
+    let mut apu = Apu::new();
+
+    // Length loads are ignored while a channel is disabled
+    apu.cpu_write(0x4003, 0x08);
+    assert_eq!(apu.pulse_1.length.value, 0);
+    apu.cpu_write(0x4015, STATUS_PULSE_1 | STATUS_TRIANGLE);
+    apu.cpu_write(0x4003, 0x08);
+    apu.cpu_write(0x400B, 0x08);
+    assert_eq!(apu.cpu_read(0x4015), STATUS_PULSE_1 | STATUS_TRIANGLE);
+
+    // Disabling clears the counter, and enabling again doesn't bring it back
+    apu.cpu_write(0x4015, STATUS_TRIANGLE);
+    assert_eq!(apu.pulse_1.length.value, 0);
+    apu.cpu_write(0x4015, STATUS_PULSE_1 | STATUS_TRIANGLE);
+    assert_eq!(apu.cpu_read(0x4015), STATUS_TRIANGLE);
+
+    // The DMC starts its sample when enabled, stops when disabled, and only restarts once finished
+    apu.cpu_write(0x4013, 0x01);
+    apu.cpu_write(0x4015, STATUS_DMC);
+    assert_eq!(apu.dmc.bytes_remaining, 17);
+    apu.dmc.bytes_remaining = 5;
+    apu.cpu_write(0x4015, STATUS_DMC);
+    assert_eq!(apu.dmc.bytes_remaining, 5);
+    apu.cpu_write(0x4015, 0x00);
+    assert_eq!(apu.dmc.bytes_remaining, 0);
+    assert_eq!(apu.cpu_read(0x4015), 0);
+  }
+
+  #[test]
+  fn test_status_read_clears_frame_irq_only() {
+    let mut apu = Apu::new();
+    apu.frame_irq = true;
+    apu.dmc.irq_flag = true;
+
+    assert_eq!(apu.cpu_read(0x4015), FRAME_STATUS_IRQ | DMC_STATUS_IRQ);
+    assert_eq!(apu.cpu_read(0x4015), DMC_STATUS_IRQ);
+    assert!(apu.irq_pending());
+
+    // Only a $4015 write acknowledges the DMC interrupt
+    apu.cpu_write(0x4015, 0x00);
+    assert_eq!(apu.cpu_read(0x4015), 0);
+    assert!(!apu.irq_pending());
+  }
+
+  #[test]
+  fn test_frame_counter_mode_bit() {
+    let mut apu = Apu::new();
+    assert_eq!(apu.frame_counter_mode, FrameCounterMode::FourStep);
+
+    apu.cpu_write(0x4017, FRAME_COUNTER_FIVE_STEP);
+    assert_eq!(apu.frame_counter_mode, FrameCounterMode::FiveStep);
+    assert!(!apu.irq_inhibit);
+
+    apu.cpu_write(0x4017, FRAME_COUNTER_IRQ_INHIBIT);
+    assert_eq!(apu.frame_counter_mode, FrameCounterMode::FourStep);
+    assert!(apu.irq_inhibit);
+  }
+
+  // Run the APU one CPU cycle at a time, recording pulse 1's output after each
+  fn pulse_1_samples(apu: &mut Apu, cycles: usize) -> Vec<u8> {
+    (0..cycles).map(|_| { apu.tick(1); apu.pulse_1.output() }).collect()
+  }
+
+  // CPU cycles between consecutive rising edges, and how long each high stretch lasts
+  fn square_wave_shape(samples: &[u8]) -> (Vec<usize>, Vec<usize>) {
+    let rises: Vec<usize> = (1..samples.len()).filter(|&i| samples[i - 1] == 0 && samples[i] != 0).collect();
+    let falls: Vec<usize> = (1..samples.len()).filter(|&i| samples[i - 1] != 0 && samples[i] == 0).collect();
+    let periods = rises.windows(2).map(|pair| pair[1] - pair[0]).collect();
+    let highs = rises.iter().filter_map(|&rise| falls.iter().find(|&&fall| fall > rise).map(|fall| fall - rise)).collect();
+    (periods, highs)
+  }
+
+  #[test]
+  fn test_pulse_square_wave_period() {
+    let mut apu = Apu::new();
+    apu.cpu_write(0x4015, STATUS_PULSE_1);
+    apu.cpu_write(0x4000, 0b1011_1010); // 50% duty, halt, constant volume 10
+    apu.cpu_write(0x4002, 100);
+    apu.cpu_write(0x4003, 0b0000_1000); // Length index 1, timer high bits 0
+
+    assert_eq!(apu.pulse_1.timer_period, 100);
+    assert_eq!(apu.pulse_1.length.value, 254);
+
+    let samples = pulse_1_samples(&mut apu, 16 * 101 * 5);
+    assert!(samples.iter().all(|&level| level == 0 || level == 10));
+
+    // One period is 8 sequencer steps of (t + 1) APU cycles, 16 * (t + 1) CPU cycles
+    let (periods, highs) = square_wave_shape(&samples);
+    assert!(periods.len() >= 3);
+    assert!(periods.iter().all(|&period| period == 16 * 101));
+    assert!(highs.iter().all(|&high| high == 8 * 101));
+
+    // 25% duty keeps the period but shortens the high part
+    apu.cpu_write(0x4000, 0b0111_1010);
+    let (periods, highs) = square_wave_shape(&pulse_1_samples(&mut apu, 16 * 101 * 5));
+    assert!(periods.iter().all(|&period| period == 16 * 101));
+    assert!(highs.iter().all(|&high| high == 4 * 101));
+  }
+
+  #[test]
+  fn test_pulse_silenced() {
+    let mut apu = Apu::new();
+    apu.cpu_write(0x4000, 0b1011_1111);
+    apu.cpu_write(0x4002, 100);
+    apu.cpu_write(0x4003, 0x08);
+
+    // Not enabled in $4015, so the length counter never loaded
+    assert_eq!(apu.pulse_1.length.value, 0);
+    assert!(pulse_1_samples(&mut apu, 2000).iter().all(|&level| level == 0));
+
+    // Timer periods under 8 are muted
+    apu.cpu_write(0x4015, STATUS_PULSE_1);
+    apu.cpu_write(0x4002, 7);
+    apu.cpu_write(0x4003, 0x08);
+    assert!(pulse_1_samples(&mut apu, 2000).iter().all(|&level| level == 0));
+
+    // Clearing the enable bit clears the length counter
+    apu.cpu_write(0x4015, 0);
+    assert_eq!(apu.pulse_1.length.value, 0);
+  }
+
+  #[test]
+  fn test_pulse_restart_and_envelope_volume() {
+    let mut apu = Apu::new();
+    apu.cpu_write(0x4015, STATUS_PULSE_2);
+    apu.cpu_write(0x4004, 0b1000_0011); // 50% duty, envelope mode
+    apu.cpu_write(0x4006, 50);
+    apu.cpu_write(0x4007, 0x08);
+
+    apu.tick(4);
+    assert_eq!(apu.pulse_2.output(), 0); // Silent until a quarter frame restarts the envelope
+    apu.pulse_2.envelope.clock();
+    assert_eq!(apu.pulse_2.output(), 15);
+
+    // Writing $4007 restarts the sequence from step 0, which is low for 50% duty
+    apu.tick(16 * 51 / 2);
+    apu.cpu_write(0x4007, 0x08);
+    assert_eq!(apu.pulse_2.output(), 0);
+  }
+
+  // Clock a fresh LFSR count times, collecting bit 0 after each shift
+  fn lfsr_bits(short_mode: bool, count: usize) -> String {
+    let mut noise = Noise::new();
+    noise.short_mode = short_mode;
+    (0..count).map(|_| { noise.clock_lfsr(); if noise.shift_register & 1 != 0 { '1' } else { '0' } }).collect()
+  }
+
+  #[test]
+  fn test_noise_lfsr_sequences() {
+    assert_eq!(lfsr_bits(false, 32), "00000000000000100000000000001100");
+    assert_eq!(lfsr_bits(true, 32), "00000000000000100000000100000100");
+
+    // The long mode visits every nonzero 15 bit state, the short mode loops after 93 shifts
+    let mut noise = Noise::new();
+    for _ in 0..32767 {
+      noise.clock_lfsr();
+    }
+    assert_eq!(noise.shift_register, 1);
+
+    noise.short_mode = true;
+    for _ in 0..93 {
+      noise.clock_lfsr();
+    }
+    assert_eq!(noise.shift_register, 1);
+  }
+
+  #[test]
+  fn test_noise_registers_and_output() {
+    let mut apu = Apu::new();
+    apu.cpu_write(0x4015, STATUS_NOISE);
+    apu.cpu_write(0x400C, 0b0011_0110); // Constant volume 6
+    apu.cpu_write(0x400E, 0b1000_0000); // Short mode, period 4
+    apu.cpu_write(0x400F, 0x08);
+
+    assert!(apu.noise.short_mode);
+    assert_eq!(apu.noise.timer_period, 4);
+    assert_eq!(apu.noise.length.value, 254);
+
+    // The LFSR shifts every 4 CPU cycles and the channel is silent while bit 0 is set
+    apu.tick(1);
+    assert_eq!(apu.noise.shift_register, 0x4000);
+    apu.tick(4 * 14);
+    assert_eq!(apu.noise.shift_register & 1, 1);
+    assert_eq!(apu.noise.output(), 0);
+    apu.tick(4);
+    assert_eq!(apu.noise.output(), 6);
+
+    apu.cpu_write(0x4015, 0);
+    assert_eq!(apu.noise.output(), 0);
+  }
+
+  // Step the frame counter for the given number of CPU cycles, listing (cycle, clock) for every clock
+  fn frame_clocks(apu: &mut Apu, cycles: u32) -> Vec<(u32, FrameClock)> {
+    (1..=cycles)
+      .map(|cycle| (cycle, apu.step_frame_counter()))
+      .filter(|(_, clock)| *clock != FrameClock::None)
+      .collect()
+  }
+
+  #[test]
+  fn test_frame_counter_four_step_timing() {
+    let mut apu = Apu::new();
+    apu.cpu_write(0x4017, 0x00); // Written on an even cycle, so the sequence restarts 3 cycles later
+
+    let reset = 3;
+    let clocks = frame_clocks(&mut apu, reset + 29830 + 7457);
+    assert_eq!(clocks, vec![
+      (reset + 7457, FrameClock::Quarter),
+      (reset + 14913, FrameClock::Half),
+      (reset + 22371, FrameClock::Quarter),
+      (reset + 29829, FrameClock::Half),
+      (reset + 29830 + 7457, FrameClock::Quarter),
+    ]);
+  }
+
+  #[test]
+  fn test_frame_counter_four_step_irq() {
+    let mut apu = Apu::new();
+    apu.cpu_write(0x4017, 0x00);
+    frame_clocks(&mut apu, 3 + 29827);
+    assert!(!apu.irq_pending());
+
+    apu.step_frame_counter();
+    assert!(apu.irq_pending());
+
+    // Reading $4015 reports and clears the flag, but it's raised again on the next two cycles
+    assert_eq!(apu.cpu_read(0x4015) & FRAME_STATUS_IRQ, FRAME_STATUS_IRQ);
+    assert_eq!(apu.cpu_read(0x4015) & FRAME_STATUS_IRQ, 0);
+    apu.step_frame_counter();
+    apu.step_frame_counter();
+    assert_eq!(apu.cpu_read(0x4015) & FRAME_STATUS_IRQ, FRAME_STATUS_IRQ);
+    apu.step_frame_counter();
+    assert_eq!(apu.cpu_read(0x4015) & FRAME_STATUS_IRQ, 0);
+
+    // Setting the inhibit bit clears a pending flag and stops new ones
+    frame_clocks(&mut apu, 29830 - 1);
+    assert!(apu.irq_pending());
+    apu.cpu_write(0x4017, FRAME_COUNTER_IRQ_INHIBIT);
+    assert!(!apu.irq_pending());
+    frame_clocks(&mut apu, 29830 * 2);
+    assert!(!apu.irq_pending());
+  }
+
+  #[test]
+  fn test_frame_counter_five_step_timing() {
+    let mut apu = Apu::new();
+    apu.tick(1);
+    apu.cpu_write(0x4017, FRAME_COUNTER_FIVE_STEP); // Odd cycle, 4 cycle delay
+
+    let reset = 4;
+    let clocks = frame_clocks(&mut apu, reset + 37282 + 7457);
+    assert_eq!(clocks, vec![
+      (reset, FrameClock::Half), // Immediate clock from the write
+      (reset + 7457, FrameClock::Quarter),
+      (reset + 14913, FrameClock::Half),
+      (reset + 22371, FrameClock::Quarter),
+      (reset + 37281, FrameClock::Half),
+      (reset + 37282 + 7457, FrameClock::Quarter),
+    ]);
+    assert!(!apu.irq_pending());
+  }
+
+  #[test]
+  fn test_half_frames_clock_length_counters() {
+    let mut apu = Apu::new();
+    apu.cpu_write(0x4015, STATUS_PULSE_1 | STATUS_PULSE_2);
+    apu.cpu_write(0x4000, 0b0001_0000);
+    apu.cpu_write(0x4003, 0b0001_1000); // Length 2
+    apu.cpu_write(0x4004, 0b0011_0000); // Halted
+    apu.cpu_write(0x4007, 0b0001_1000);
+    apu.cpu_write(0x4017, FRAME_COUNTER_FIVE_STEP | FRAME_COUNTER_IRQ_INHIBIT);
+
+    apu.tick(3);
+    assert_eq!(apu.pulse_1.length.value, 1);
+    assert_eq!(apu.pulse_2.length.value, 2);
+    apu.tick(14913);
+    assert_eq!(apu.pulse_1.length.value, 0);
+    assert_eq!(apu.pulse_2.length.value, 2);
+  }
+
+  #[test]
+  fn test_length_counter_table() {
+    let mut length = LengthCounter::new();
+    length.set_enabled(true);
+    for (index, expected) in [(0, 10), (1, 254), (2, 20), (15, 14), (16, 12), (23, 22), (31, 30)] {
+      length.load(index << 3);
+      assert_eq!(length.value, expected);
+    }
+  }
+
+  #[test]
+  fn test_length_counter_halt_and_enable() {
+    let mut length = LengthCounter::new();
+    length.load(0x00);
+    assert!(!length.is_active()); // Disabled counters ignore loads
+
+    length.set_enabled(true);
+    length.load(0x18); // 2
+    length.halt = true;
+    length.clock();
+    assert_eq!(length.value, 2);
+
+    length.halt = false;
+    length.clock();
+    length.clock();
+    assert!(!length.is_active());
+    length.clock();
+    assert_eq!(length.value, 0);
+
+    length.load(0x08);
+    length.set_enabled(false);
+    assert_eq!(length.value, 0);
+  }
+
+  #[test]
+  fn test_length_counters_silence_channels() {
+    let mut apu = Apu::new();
+    apu.cpu_write(0x4015, STATUS_TRIANGLE | STATUS_NOISE);
+    apu.cpu_write(0x4008, 0x00); // Triangle not halted
+    apu.cpu_write(0x400B, 0x18); // Length 2
+    apu.cpu_write(0x400C, 0b0001_1111); // Noise not halted, constant volume 15
+    apu.cpu_write(0x400F, 0x18);
+    apu.cpu_write(0x4017, FRAME_COUNTER_FIVE_STEP | FRAME_COUNTER_IRQ_INHIBIT);
+    assert_eq!(apu.cpu_read(0x4015), STATUS_TRIANGLE | STATUS_NOISE);
+
+    // Two half frames: the immediate one from the $4017 write, then the one at step 2
+    apu.tick(3 + 14913);
+    assert_eq!(apu.cpu_read(0x4015), 0);
+    assert!((0..100).all(|_| { apu.tick(1); apu.noise.output() == 0 }));
+  }
+
+  // Clock the envelope count times, collecting its output after each clock
+  fn envelope_outputs(envelope: &mut Envelope, count: usize) -> Vec<u8> {
+    (0..count).map(|_| { envelope.clock(); envelope.output() }).collect()
+  }
+
+  #[test]
+  fn test_envelope_decay() {
+    let mut envelope = Envelope::new();
+    envelope.write_control(0b0000_0001); // Period 1: a step every 2 quarter frames
+    envelope.restart();
+
+    let outputs = envelope_outputs(&mut envelope, 34);
+    let expected: Vec<u8> = std::iter::once(15)
+      .chain((0..15).rev().flat_map(|level| [level + 1, level]))
+      .chain([0, 0, 0])
+      .collect();
+    assert_eq!(outputs, expected);
+  }
+
+  #[test]
+  fn test_envelope_loop() {
+    let mut envelope = Envelope::new();
+    envelope.write_control(0b0010_0000); // Loop, period 0: a step every quarter frame
+    envelope.restart();
+
+    let outputs = envelope_outputs(&mut envelope, 20);
+    let expected: Vec<u8> = (0..=15).rev().chain([15, 14, 13, 12]).collect();
+    assert_eq!(outputs, expected);
+
+    // Constant volume ignores the decay
+    envelope.write_control(0b0001_0111);
+    assert_eq!(envelope_outputs(&mut envelope, 3), vec![7, 7, 7]);
+  }
+
+  #[test]
+  fn test_envelope_runs_on_quarter_frames() {
+    let mut apu = Apu::new();
+    apu.cpu_write(0x4015, STATUS_NOISE);
+    apu.cpu_write(0x400C, 0b0010_0000); // Halted length, looping envelope with period 0
+    apu.cpu_write(0x400F, 0x08);
+    apu.cpu_write(0x4017, FRAME_COUNTER_FIVE_STEP | FRAME_COUNTER_IRQ_INHIBIT);
+
+    apu.tick(3); // Restart clocks a quarter frame, which handles the start flag
+    assert_eq!(apu.noise.envelope.decay, 15);
+    apu.tick(7457);
+    assert_eq!(apu.noise.envelope.decay, 14);
+    apu.tick(14913 - 7457);
+    assert_eq!(apu.noise.envelope.decay, 13);
+  }
+
+  #[test]
+  fn test_sweep_negate_differs_between_channels() {
+    let mut apu = Apu::new();
+    apu.cpu_write(0x4002, 0x00);
+    apu.cpu_write(0x4003, 0x01); // Period $100
+    apu.cpu_write(0x4006, 0x00);
+    apu.cpu_write(0x4007, 0x01);
+    apu.cpu_write(0x4001, 0b1000_1001); // Enabled, divider period 0, negate, shift 1
+    apu.cpu_write(0x4005, 0b1000_1001);
+
+    // Pulse 1 subtracts the change and one more, pulse 2 just the change
+    assert_eq!(apu.pulse_1.sweep_target_period(), 0x100 - 0x80 - 1);
+    assert_eq!(apu.pulse_2.sweep_target_period(), 0x100 - 0x80);
+
+    apu.pulse_1.clock_sweep();
+    apu.pulse_2.clock_sweep();
+    assert_eq!(apu.pulse_1.timer_period, 0x7F);
+    assert_eq!(apu.pulse_2.timer_period, 0x80);
+  }
+
+  #[test]
+  fn test_sweep_adds_on_its_divider_period() {
+    let mut pulse = Pulse::new(2);
+    pulse.timer_period = 0x200;
+    pulse.write_register(1, 0b1001_0010); // Enabled, divider period 1, shift 2
+
+    let periods: Vec<u16> = (0..6).map(|_| { pulse.clock_sweep(); pulse.timer_period }).collect();
+    assert_eq!(periods, vec![0x280, 0x280, 0x320, 0x320, 0x3E8, 0x3E8]);
+
+    // A shift of 0 never changes the period
+    pulse.write_register(1, 0b1000_0000);
+    pulse.clock_sweep();
+    pulse.clock_sweep();
+    assert_eq!(pulse.timer_period, 0x3E8);
+  }
+
+  #[test]
+  fn test_sweep_muting() {
+    let mut pulse = Pulse::new(1);
+    pulse.length.set_enabled(true);
+    pulse.write_register(0, 0b1001_1111); // 50% duty, constant volume 15
+    pulse.write_register(2, 0xF0);
+    pulse.write_register(3, 0x0F); // Period $7F0
+    pulse.write_register(1, 0b0000_0001); // Disabled, shift 1
+
+    // The target ($7F0 + $3F8) overflows 11 bits, which mutes the channel even with the sweep off
+    assert!(pulse.sweep_muting());
+    assert!((0..20000).all(|_| { pulse.clock_timer(); pulse.output() == 0 }));
+    pulse.clock_sweep();
+    assert_eq!(pulse.timer_period, 0x7F0);
+
+    // Negating can't overflow, so the channel plays
+    pulse.write_register(1, 0b0000_1001);
+    assert!(!pulse.sweep_muting());
+    assert!((0..20000).any(|_| { pulse.clock_timer(); pulse.output() == 15 }));
+
+    // Periods under 8 are muted too
+    pulse.write_register(2, 0x07);
+    pulse.write_register(3, 0x00);
+    assert!(pulse.sweep_muting());
+  }
+
+  #[test]
+  fn test_mixer_tables() {
+    assert_eq!(PULSE_TABLE[0], 0.0);
+    assert_eq!(TND_TABLE[0], 0.0);
+    assert!(PULSE_TABLE.windows(2).all(|pair| pair[0] < pair[1]));
+    assert!(TND_TABLE.windows(2).all(|pair| pair[0] < pair[1]));
+
+    // Spot values against the reference formulas
+    let pulse_reference = |p1: f32, p2: f32| 95.88 / (8128.0 / (p1 + p2) + 100.0);
+    let tnd_reference = |t: f32, n: f32, d: f32| 159.79 / (1.0 / (t / 8227.0 + n / 12241.0 + d / 22638.0) + 100.0);
+    assert!((PULSE_TABLE[30] - pulse_reference(15.0, 15.0)).abs() < 0.002);
+    assert!((PULSE_TABLE[15] - pulse_reference(15.0, 0.0)).abs() < 0.002);
+    assert!((TND_TABLE[3 * 15 + 2 * 15 + 127] - tnd_reference(15.0, 15.0, 127.0)).abs() < 0.01);
+    assert!((TND_TABLE[3 * 15] - tnd_reference(15.0, 0.0, 0.0)).abs() < 0.01);
+    assert!((PULSE_TABLE[30] - 0.2575).abs() < 0.0001);
+    assert!((TND_TABLE[202] - 0.7425).abs() < 0.0001);
+  }
+
+  #[test]
+  fn test_mixer_output() {
+    let mut apu = Apu::new();
+    // The triangle rests at level 15 from power on
+    assert_eq!(apu.output(), TND_TABLE[3 * 15]);
+
+    apu.cpu_write(0x4011, 0x40);
+    assert_eq!(apu.output(), TND_TABLE[3 * 15 + 0x40]);
+
+    // Non-linear: two channels at 15 are quieter than twice one channel at 15
+    assert!(PULSE_TABLE[30] < 2.0 * PULSE_TABLE[15]);
+  }
+
+  #[test]
+  fn test_apu_output_level() {
+    let mut apu = Apu::new();
+    // Only the triangle, resting at level 15 from power on
+    let resting = apu.apu_output_level();
+    assert!((resting - 159.79 / (8227.0 / 15.0 + 100.0)).abs() < 1e-6);
+
+    apu.cpu_write(0x4015, STATUS_PULSE_1);
+    apu.cpu_write(0x4000, 0b1011_1111); // 50% duty, halt, constant volume 15
+    apu.cpu_write(0x4002, 100);
+    apu.cpu_write(0x4003, 0x08);
+
+    // Over one period the pulse adds about 0.149 for the high half and nothing for the low half
+    let mut levels = vec![];
+    for _ in 0..16 * 101 {
+      apu.tick(1);
+      levels.push(apu.apu_output_level() - resting);
+    }
+    assert!(levels.iter().all(|level| level.abs() < 1e-6 || (level - 0.1494).abs() < 1e-3));
+    let highs = levels.iter().filter(|level| level.abs() > 1e-6).count();
+    assert_eq!(highs, 8 * 101);
+  }
+
+  #[test]
+  fn test_channel_mute_only_affects_the_mix() {
+    let mut apu = Apu::new();
+    apu.cpu_write(0x4015, STATUS_PULSE_1 | STATUS_TRIANGLE);
+    apu.cpu_write(0x4000, 0b1011_1111); // 50% duty, halt, constant volume 15
+    apu.cpu_write(0x4002, 100);
+    apu.cpu_write(0x4003, 0x08);
+    apu.cpu_write(0x4011, 0x20);
+    while apu.pulse_1.output() == 0 {
+      apu.tick(1);
+    }
+    let unmuted = apu.output();
+
+    apu.set_channel_mute(Channel::Pulse1, true);
+    assert!(apu.is_channel_muted(Channel::Pulse1));
+    assert_eq!(apu.output(), TND_TABLE[3 * 15 + 0x20]);
+    assert!(apu.output() < unmuted);
+    assert_eq!(apu.cpu_read(0x4015), STATUS_PULSE_1);
+    assert_eq!(apu.pulse_1.output(), 15);
+
+    apu.solo_channel(Channel::Dmc);
+    assert_eq!(apu.output(), TND_TABLE[0x20]);
+    assert!(!apu.is_channel_muted(Channel::Dmc));
+
+    apu.unmute_all();
+    assert_eq!(apu.output(), unmuted);
+  }
+
+  #[test]
+  fn test_triangle_sequence() {
+    let mut apu = Apu::new();
+    apu.cpu_write(0x4015, STATUS_TRIANGLE);
+    apu.cpu_write(0x4008, 0b1111_1111); // Control set, linear counter 127
+    apu.cpu_write(0x400A, 9);
+    apu.cpu_write(0x400B, 0x08);
+    apu.triangle.clock_linear_counter();
+    assert_eq!(apu.triangle.linear_counter, 127);
+
+    // One step every period + 1 CPU cycles
+    let levels: Vec<u8> = (0..32).map(|_| { apu.tick(10); apu.triangle.output() }).collect();
+    let expected: Vec<u8> = (0..32).map(|step| TRIANGLE_SEQUENCE[(step + 1) % 32]).collect();
+    assert_eq!(levels, expected);
+
+    // The sequencer holds its level once the linear counter runs out
+    apu.cpu_write(0x4008, 0x00);
+    apu.triangle.linear_counter = 0;
+    let level = apu.triangle.output();
+    apu.tick(100);
+    assert_eq!(apu.triangle.output(), level);
+  }
+
+  #[test]
+  fn test_sample_stream_rate() {
+    let mut apu = Apu::new();
+    // One NTSC frame is 29780.5 CPU cycles, about 734 samples at 44.1kHz
+    apu.tick(29781);
+    let samples = apu.take_samples();
+    assert!((733..=735).contains(&samples.len()), "{} samples", samples.len());
+    assert!(apu.take_samples().is_empty());
+
+    // The count stays locked to the CPU clock over many frames, without drifting
+    let mut apu = Apu::new();
+    let mut total = 0;
+    for _ in 0..30 {
+      apu.tick(29780);
+      apu.tick(29781);
+      total += apu.take_samples().len();
+    }
+    let expected = (60.0 * 29780.5 * DEFAULT_SAMPLE_RATE as f64 / CPU_CLOCK_HZ as f64) as usize;
+    assert!(total.abs_diff(expected) <= 1, "{} samples, expected {}", total, expected);
+
+    apu.set_sample_rate(48_000);
+    apu.tick(29781);
+    assert!((798..=800).contains(&apu.take_samples().len()));
+  }
+
+  #[test]
+  fn test_samples_follow_the_mixer() {
+    let mut apu = Apu::new();
+    apu.filtering = false;
+    apu.cpu_write(0x4011, 0x40);
+    apu.tick(1000);
+    let samples = apu.take_samples();
+    assert!(!samples.is_empty());
+    assert!(samples.iter().all(|&sample| (sample - apu.output()).abs() < 1e-5));
+  }
+
+  // Magnitude of one frequency in a block of samples (Goertzel)
+  fn tone_magnitude(samples: &[f32], frequency: f32, sample_rate: u32) -> f32 {
+    let coefficient = 2.0 * (2.0 * std::f32::consts::PI * frequency / sample_rate as f32).cos();
+    let (mut s1, mut s2) = (0.0, 0.0);
+    for sample in samples {
+      let s0 = sample + coefficient * s1 - s2;
+      s2 = s1;
+      s1 = s0;
+    }
+    (s1 * s1 + s2 * s2 - coefficient * s1 * s2).sqrt() / samples.len() as f32
+  }
+
+  // A second of pulse 1 playing a square wave at 1789773 / (16 * 28), about 3995Hz
+  fn square_wave_samples(filtering: bool) -> Vec<f32> {
+    let mut apu = Apu::new();
+    apu.filtering = filtering;
+    apu.cpu_write(0x4015, STATUS_PULSE_1);
+    apu.cpu_write(0x4000, 0b1011_1111); // 50% duty, halt, constant volume 15
+    apu.cpu_write(0x4002, 27);
+    apu.cpu_write(0x4003, 0x08);
+    let run = |apu: &mut Apu, seconds: u64| {
+      for _ in 0..seconds * 40 {
+        apu.tick((CPU_CLOCK_HZ / 40) as u16);
+      }
+      apu.take_samples()
+    };
+    run(&mut apu, 1); // Let the filters settle
+    run(&mut apu, 1)
+  }
+
+  #[test]
+  fn test_audio_filter_responses() {
+    let sine = |frequency: f32| -> Vec<f32> {
+      (0..DEFAULT_SAMPLE_RATE)
+        .map(|i| (2.0 * std::f32::consts::PI * frequency * i as f32 / DEFAULT_SAMPLE_RATE as f32).sin())
+        .collect()
+    };
+    let gain = |mut filter: AudioFilter, frequency: f32| {
+      let output: Vec<f32> = sine(frequency).into_iter().map(|sample| filter.process(sample)).collect();
+      let tail = &output[output.len() / 2..];
+      tail.iter().cloned().fold(0.0, f32::max)
+    };
+
+    // Roughly -3dB at the cutoff, passing well inside the band and cutting well outside it
+    let high_pass = AudioFilter::new(FilterKind::HighPass, 440.0, DEFAULT_SAMPLE_RATE);
+    assert!((gain(high_pass, 440.0) - 0.707).abs() < 0.05);
+    assert!(gain(high_pass, 5000.0) > 0.95);
+    assert!(gain(high_pass, 40.0) < 0.15);
+
+    let low_pass = AudioFilter::new(FilterKind::LowPass, 1000.0, DEFAULT_SAMPLE_RATE);
+    assert!(gain(low_pass, 100.0) > 0.95);
+    assert!(gain(low_pass, 10_000.0) < 0.15);
+
+    // DC never makes it through a high-pass
+    let mut high_pass = AudioFilter::new(FilterKind::HighPass, 90.0, DEFAULT_SAMPLE_RATE);
+    let settled = (0..DEFAULT_SAMPLE_RATE).map(|_| high_pass.process(0.5)).last().unwrap();
+    assert!(settled.abs() < 1e-3);
+  }
+
+  #[test]
+  fn test_square_wave_through_the_output_pipeline() {
+    let fundamental = CPU_CLOCK_HZ as f32 / (16.0 * 28.0);
+    let raw = square_wave_samples(false);
+    let filtered = square_wave_samples(true);
+    assert!(raw.len().abs_diff(DEFAULT_SAMPLE_RATE as usize) <= 1);
+
+    // The fundamental dominates, with the third harmonic near the ideal square's 1/3
+    let raw_fundamental = tone_magnitude(&raw, fundamental, DEFAULT_SAMPLE_RATE);
+    let raw_third = tone_magnitude(&raw, 3.0 * fundamental, DEFAULT_SAMPLE_RATE);
+    assert!(raw_third / raw_fundamental > 0.2 && raw_third / raw_fundamental < 1.0 / 3.0);
+    for off_tone in [fundamental * 0.5, fundamental * 1.5, fundamental * 2.5] {
+      assert!(tone_magnitude(&raw, off_tone, DEFAULT_SAMPLE_RATE) < raw_fundamental * 0.05);
+    }
+
+    // The output stage keeps the fundamental but takes the low-pass off the harmonics
+    let filtered_fundamental = tone_magnitude(&filtered, fundamental, DEFAULT_SAMPLE_RATE);
+    let filtered_third = tone_magnitude(&filtered, 3.0 * fundamental, DEFAULT_SAMPLE_RATE);
+    assert!(filtered_fundamental > raw_fundamental * 0.8);
+    assert!(filtered_third / filtered_fundamental < raw_third / raw_fundamental * 0.85);
+
+    // And removes the DC offset the unsigned mixer output carries
+    let mean = |samples: &[f32]| samples.iter().sum::<f32>() / samples.len() as f32;
+    assert!(mean(&raw) > 0.05);
+    assert!(mean(&filtered).abs() < 0.005);
+  }
+}
@@ -0,0 +1,554 @@
+#![allow(unused)]
+/* The following code connects the CPU to memory and the other hardware */
+
+use super::apu::Apu;
+use super::cartridge::{Cartridge, Mirroring};
+use super::joypad::Joypad;
+use super::ppu::{Ppu, PpuTickResult};
+use std::ops::Range;
+
+//  _______________ $10000  _______________
+// | PRG-ROM       |       |               |
+// | Upper Bank    |       |               |
+// |_ _ _ _ _ _ _ _| $C000 | PRG-ROM       |
+// | PRG-ROM       |       |               |
+// | Lower Bank    |       |               |
+// |_______________| $8000 |_______________|
+// | SRAM          |       | SRAM          |
+// |_______________| $6000 |_______________|
+// | Expansion ROM |       | Expansion ROM |
+// |_______________| $4020 |_______________|
+// | I/O Registers |       |               |
+// |_ _ _ _ _ _ _ _| $4000 |               |
+// | Mirrors       |       | I/O Registers |
+// | $2000-$2007   |       |               |
+// |_ _ _ _ _ _ _ _| $2008 |               |
+// | I/O Registers |       |               |
+// |_______________| $2000 |_______________|
+// | Mirrors       |       |               |
+// | $0000-$07FF   |       |               |
+// |_ _ _ _ _ _ _ _| $0800 |               |
+// | RAM           |       | RAM           |
+// |_______________| $0000 |_______________|
+
+const RAM: u16 = 0x0000;
+const RAM_MIRRORS_END: u16 = 0x1FFF;
+const PPU_REGISTERS: u16 = 0x2000;
+const PPU_REGISTERS_MIRRORS_END: u16 = 0x3FFF;
+const APU_REGISTERS: u16 = 0x4000;
+const APU_REGISTERS_END: u16 = 0x4013;
+const OAM_DMA: u16 = 0x4014;
+const APU_STATUS: u16 = 0x4015;
+const JOYPAD_1: u16 = 0x4016;
+const JOYPAD_2: u16 = 0x4017; // Reads only, writes to $4017 go to the APU frame counter
+const APU_FRAME_COUNTER: u16 = 0x4017;
+const APU_TEST_REGISTERS: u16 = 0x4018;
+const APU_TEST_REGISTERS_END: u16 = 0x401F;
+const APU_STATUS_OPEN_BUS: u8 = 0b0010_0000; // Unused $4015 bit, driven by whatever was last on the bus
+const JOYPAD_OPEN_BUS: u8 = 0b1110_0000; // Controller port bits nothing drives
+const PRG_ROM: u16 = 0x8000;
+const PRG_ROM_END: u16 = 0xFFFF;
+
+// Memory mapped device that can be attached to the bus with Bus::map_io
+pub trait IoHandler {
+  fn read(&mut self, addr: u16) -> u8;
+  fn write(&mut self, addr: u16, data: u8);
+}
+
+// What the 2KB of internal RAM holds at power on. Real RAM comes up in a loose,
+// chip-dependent pattern, and a few test ROMs treat all zeros as a sign of an emulator
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FillPattern {
+  All(u8), // Every byte the same, All(0x00) is the default
+  Random(u32), // xorshift32 bytes from the given (nonzero) seed, the same seed always gives the same RAM
+}
+
+impl Default for FillPattern {
+  fn default() -> Self {
+    FillPattern::All(0x00)
+  }
+}
+
+pub struct Bus {
+  memory: [u8; 0x10000], // Anything not mapped to hardware falls back to flat memory
+  pub ppu: Ppu,
+  pub apu: Apu,
+  pub joypad1: Joypad,
+  pub joypad2: Joypad,
+  prg_rom: Vec<u8>,
+  oam_dma_pending: bool,
+  dmc_stall_cycles: u16, // CPU cycles owed to DMC sample fetches
+  io_handlers: Vec<(Range<u16>, Box<dyn IoHandler>)>,
+  open_bus: u8, // Last value on the data bus, what reads of unmapped or write-only addresses see
+}
+
+impl Bus {
+  // Bus constructor
+  pub fn new() -> Self {
+    Bus {
+      memory: [0x00; 0x10000],
+      ppu: Ppu::new(),
+      apu: Apu::new(),
+      joypad1: Joypad::new(),
+      joypad2: Joypad::new(),
+      prg_rom: Vec::new(),
+      oam_dma_pending: false,
+      dmc_stall_cycles: 0,
+      io_handlers: Vec::new(),
+      open_bus: 0,
+    }
+  }
+
+  // Route reads and writes in range to handler, ahead of everything else on the bus
+  // (the first mapping that covers an address wins)
+  pub fn map_io(&mut self, range: Range<u16>, handler: Box<dyn IoHandler>) {
+    self.io_handlers.push((range, handler));
+  }
+
+  // Fill internal RAM ($0000-$07FF) the way it looks at power on
+  pub fn fill_ram(&mut self, pattern: FillPattern) {
+    let ram = &mut self.memory[..0x0800];
+    match pattern {
+      FillPattern::All(value) => ram.fill(value),
+      FillPattern::Random(seed) => {
+        let mut state = seed.max(1); // xorshift gets stuck at zero
+        for byte in ram.iter_mut() {
+          state ^= state << 13;
+          state ^= state >> 17;
+          state ^= state << 5;
+          *byte = state as u8;
+        }
+      }
+    }
+  }
+
+  fn io_handler(&mut self, addr: u16) -> Option<&mut Box<dyn IoHandler>> {
+    self.io_handlers.iter_mut()
+      .find(|(range, _)| range.contains(&addr))
+      .map(|(_, handler)| handler)
+  }
+
+  // Map a cartridge's PRG ROM into $8000-$FFFF (only mapper 0 / NROM banking for now) and hand CHR to the PPU
+  pub fn insert_cartridge(&mut self, cartridge: Cartridge) {
+    self.ppu.mirroring = cartridge.screen_mirroring;
+    if !cartridge.chr_rom.is_empty() {
+      self.ppu.chr_rom = cartridge.chr_rom;
+    }
+    self.prg_rom = cartridge.prg_rom;
+  }
+
+  // Read from PRG ROM, 16KB carts are mirrored into the upper bank
+  fn read_prg_rom(&self, addr: u16) -> u8 {
+    let mut addr = addr - PRG_ROM;
+    if self.prg_rom.len() == 0x4000 && addr >= 0x4000 {
+      addr %= 0x4000;
+    }
+    self.prg_rom[addr as usize]
+  }
+
+  // Read from the CPU's address space
+  pub fn mem_read(&mut self, addr: u16) -> u8 {
+    let data = if let Some(handler) = self.io_handler(addr) {
+      handler.read(addr)
+    }
+    else {
+      match addr {
+        RAM ..= RAM_MIRRORS_END => {
+          self.memory[(addr & 0b0000_0111_1111_1111) as usize]
+        },
+        PPU_REGISTERS ..= PPU_REGISTERS_MIRRORS_END => {
+          self.ppu.read_register(addr & 0b0010_0000_0000_0111)
+        },
+        // $4015 is driven internally by the CPU, so the read never reaches the bus latch
+        APU_STATUS => {
+          return self.apu.cpu_read(addr) | (self.open_bus & APU_STATUS_OPEN_BUS);
+        },
+        JOYPAD_1 => (self.open_bus & JOYPAD_OPEN_BUS) | self.joypad1.read(),
+        JOYPAD_2 => (self.open_bus & JOYPAD_OPEN_BUS) | self.joypad2.read(),
+        // Write-only registers leave the last value on the bus
+        APU_REGISTERS ..= OAM_DMA | APU_TEST_REGISTERS ..= APU_TEST_REGISTERS_END => self.open_bus,
+        PRG_ROM ..= PRG_ROM_END if !self.prg_rom.is_empty() => {
+          self.read_prg_rom(addr)
+        },
+        _ => self.memory[addr as usize]
+      }
+    };
+    self.open_bus = data;
+    data
+  }
+
+  // Last value seen on the CPU data bus
+  pub fn open_bus(&self) -> u8 {
+    self.open_bus
+  }
+
+  // Read from the CPU's address space without side effects, for debugging views that only have &self.
+  // Hardware registers and mapped devices read as 0 since reading them for real can change their state
+  pub fn peek(&self, addr: u16) -> u8 {
+    if self.io_handlers.iter().any(|(range, _)| range.contains(&addr)) {
+      return 0;
+    }
+    match addr {
+      RAM ..= RAM_MIRRORS_END => {
+        self.memory[(addr & 0b0000_0111_1111_1111) as usize]
+      },
+      PPU_REGISTERS ..= PPU_REGISTERS_MIRRORS_END | APU_REGISTERS ..= APU_FRAME_COUNTER => 0,
+      PRG_ROM ..= PRG_ROM_END if !self.prg_rom.is_empty() => {
+        self.read_prg_rom(addr)
+      },
+      _ => self.memory[addr as usize]
+    }
+  }
+
+  // Write to the CPU's address space
+  pub fn mem_write(&mut self, addr: u16, data: u8) {
+    self.open_bus = data;
+    if let Some(handler) = self.io_handler(addr) {
+      handler.write(addr, data);
+      return;
+    }
+    match addr {
+      RAM ..= RAM_MIRRORS_END => {
+        self.memory[(addr & 0b0000_0111_1111_1111) as usize] = data;
+      },
+      PPU_REGISTERS ..= PPU_REGISTERS_MIRRORS_END => {
+        self.ppu.write_register(addr & 0b0010_0000_0000_0111, data);
+      },
+      APU_REGISTERS ..= APU_REGISTERS_END | APU_STATUS | APU_FRAME_COUNTER => {
+        self.apu.cpu_write(addr, data);
+      },
+      OAM_DMA => { self.oam_dma(data); },
+      // Both controllers share the strobe line
+      JOYPAD_1 => {
+        self.joypad1.write(data);
+        self.joypad2.write(data);
+      },
+      PRG_ROM ..= PRG_ROM_END if !self.prg_rom.is_empty() => {}, // Writes to ROM are ignored
+      _ => { self.memory[addr as usize] = data; }
+    }
+  }
+
+  // Copy page $XX00-$XXFF into PPU OAM, the CPU is stalled while this happens
+  fn oam_dma(&mut self, page: u8) {
+    let mut buffer = [0u8; 256];
+    let page_start = (page as u16) << 8;
+    for (i, byte) in buffer.iter_mut().enumerate() {
+      *byte = self.mem_read(page_start + i as u16);
+    }
+
+    self.ppu.write_oam_dma(&buffer);
+    self.oam_dma_pending = true;
+  }
+
+  // Read from the PPU's address space: CHR ($0000-$1FFF), mirrored nametables ($2000-$3EFF)
+  // and mirrored palette RAM ($3F00-$3FFF). The PPU holds the CHR data and nametable RAM
+  // itself, so its rendering and $2007 paths use the same decoding through Ppu::read_vram
+  pub fn ppu_read(&self, addr: u16) -> u8 {
+    self.ppu.read_vram(addr)
+  }
+
+  // Write to the PPU's address space (see ppu_read)
+  pub fn ppu_write(&mut self, addr: u16, data: u8) {
+    self.ppu.write_vram(addr, data);
+  }
+
+  // Returns true (once) if an OAM DMA happened that the CPU still has to stall for
+  pub fn poll_oam_dma(&mut self) -> bool {
+    let pending = self.oam_dma_pending;
+    self.oam_dma_pending = false;
+    pending
+  }
+
+  // Returns (and clears) the CPU cycles DMC sample fetches have stolen since the last poll
+  pub fn poll_dmc_stall(&mut self) -> u16 {
+    let stall = self.dmc_stall_cycles;
+    self.dmc_stall_cycles = 0;
+    stall
+  }
+
+  // Advance the rest of the hardware by the given number of CPU cycles (PPU runs 3 dots per cycle)
+  pub fn tick(&mut self, cpu_cycles: u16) -> PpuTickResult {
+    for _ in 0..cpu_cycles {
+      self.apu.tick(1);
+      // The DMC's memory reader takes over the bus (4 CPU cycles) whenever its buffer empties
+      if let Some(addr) = self.apu.dmc.fetch_address() {
+        let data = self.mem_read(addr);
+        self.apu.dmc.load_sample_byte(data);
+        self.dmc_stall_cycles += 4;
+      }
+    }
+    self.ppu.tick(cpu_cycles as u32 * 3)
+  }
+
+  // Returns true while a device is asserting the IRQ line
+  pub fn irq_line(&self) -> bool {
+    self.apu.irq_pending()
+  }
+
+  // Returns true if the PPU has raised an NMI since the last poll
+  pub fn poll_nmi(&mut self) -> bool {
+    self.ppu.poll_nmi()
+  }
+}
+
+impl Default for Bus {
+  fn default() -> Self {
+    Bus::new()
+  }
+}
+
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn test_ram_is_mirrored() {
+    let mut bus = Bus::new();
+    bus.mem_write(0x0012, 0x34);
+
+    assert_eq!(bus.mem_read(0x0812), 0x34);
+    assert_eq!(bus.mem_read(0x1812), 0x34);
+  }
+
+  #[test]
+  fn test_fill_ram_patterns() {
+    let mut bus = Bus::new();
+    bus.fill_ram(FillPattern::All(0xFF));
+    assert_eq!(bus.mem_read(0x0000), 0xFF);
+    assert_eq!(bus.mem_read(0x1FFF), 0xFF);
+    assert_eq!(bus.mem_read(0x6000), 0x00); // Only internal RAM is filled
+
+    bus.fill_ram(FillPattern::Random(1));
+    let first: Vec<u8> = (0..0x800).map(|addr| bus.mem_read(addr)).collect();
+    bus.fill_ram(FillPattern::Random(1));
+    let second: Vec<u8> = (0..0x800).map(|addr| bus.mem_read(addr)).collect();
+    assert_eq!(first, second);
+    assert!(first.iter().any(|byte| *byte != first[0]));
+    assert_eq!(&first[..4], &[0x21, 0x01, 0xC5, 0x4F]);
+  }
+
+  #[test]
+  fn test_ppu_registers_are_mirrored() {
+    let mut bus = Bus::new();
+    bus.mem_write(0x3FF8, 0x80);
+
+    assert_eq!(bus.ppu.ctrl, 0x80);
+  }
+
+  #[test]
+  fn test_tick_runs_ppu_three_times_faster() {
+    let mut bus = Bus::new();
+    bus.tick(7);
+
+    assert_eq!(bus.ppu.dot, 21);
+  }
+
+  #[test]
+  fn test_oam_dma_copies_page() {
+    let mut bus = Bus::new();
+    for i in 0..256u16 {
+      bus.mem_write(0x0200 + i, 0xFF - i as u8);
+    }
+
+    bus.mem_write(0x4014, 0x02);
+
+    assert_eq!(bus.ppu.oam[0x00], 0xFF);
+    assert_eq!(bus.ppu.oam[0xFF], 0x00);
+    assert!(bus.poll_oam_dma());
+    assert!(!bus.poll_oam_dma());
+  }
+
+  #[test]
+  fn test_insert_cartridge() {
+    let mut bus = Bus::new();
+    let mut prg_rom = vec![0x00; 0x4000];
+    prg_rom[0x0000] = 0x11;
+    prg_rom[0x3FFC] = 0x22;
+    bus.insert_cartridge(Cartridge {
+      prg_rom,
+      chr_rom: vec![],
+      mapper: 0,
+      screen_mirroring: Mirroring::FourScreen,
+    });
+    assert_eq!(bus.ppu.mirroring, Mirroring::FourScreen);
+
+    assert_eq!(bus.mem_read(0x8000), 0x11);
+    assert_eq!(bus.mem_read(0xC000), 0x11);
+    assert_eq!(bus.mem_read(0xFFFC), 0x22);
+
+    bus.mem_write(0x8000, 0x33);
+    assert_eq!(bus.mem_read(0x8000), 0x11);
+  }
+
+  #[test]
+  fn test_apu_registers_are_routed() {
+    let mut bus = Bus::new();
+    bus.mem_write(0x4002, 0xFD);
+    bus.mem_write(0x4015, 0b0000_0011);
+    bus.mem_write(0x4003, 0x08);
+    bus.mem_write(0x4007, 0x08);
+    bus.mem_write(0x4017, 0x80);
+
+    assert_eq!(bus.apu.pulse_1.timer_period, 0xFD);
+    assert_eq!(bus.mem_read(0x4015), 0b0000_0011);
+    assert_eq!(bus.apu.frame_counter_mode, crate::hardware::apu::FrameCounterMode::FiveStep);
+
+    bus.tick(10);
+    assert_eq!(bus.apu.cycles, 10);
+  }
+
+  #[test]
+  fn test_write_only_registers_read_open_bus() {
+    let mut bus = Bus::new();
+    bus.mem_write(0x0010, 0x5A);
+    bus.mem_read(0x0010);
+
+    assert_eq!(bus.mem_read(0x4000), 0x5A);
+    assert_eq!(bus.mem_read(0x4014), 0x5A);
+    assert_eq!(bus.mem_read(0x401F), 0x5A);
+
+    // The last write is on the bus too
+    bus.mem_write(0x4002, 0xA7);
+    assert_eq!(bus.mem_read(0x4013), 0xA7);
+  }
+
+  #[test]
+  fn test_apu_status_unused_bit_is_open_bus() {
+    let mut bus = Bus::new();
+    bus.mem_write(0x0010, 0xFF);
+    bus.mem_read(0x0010);
+
+    // Only bit 5 comes from the bus, and reading $4015 leaves the latch alone
+    assert_eq!(bus.mem_read(0x4015), APU_STATUS_OPEN_BUS);
+    assert_eq!(bus.open_bus(), 0xFF);
+    assert_eq!(bus.mem_read(0x4000), 0xFF);
+  }
+
+  #[test]
+  fn test_two_controllers() {
+    use crate::hardware::joypad::Button;
+    let mut bus = Bus::new();
+    bus.joypad1.set_button(Button::A, true);
+    bus.joypad2.set_button(Button::B, true);
+
+    // One strobe reaches both pads, then each port shifts its own pad
+    bus.mem_write(0x4016, 1);
+    bus.mem_write(0x4016, 0);
+    let pad1: Vec<u8> = (0..2).map(|_| bus.mem_read(0x4016) & 1).collect();
+    let pad2: Vec<u8> = (0..3).map(|_| bus.mem_read(0x4017) & 1).collect();
+    assert_eq!(pad1, vec![1, 0]);
+    assert_eq!(pad2, vec![0, 1, 0]);
+
+    // Writing $4017 is still the APU frame counter, and doesn't touch the pads
+    bus.mem_write(0x4017, 0x80);
+    assert_eq!(bus.apu.frame_counter_mode, crate::hardware::apu::FrameCounterMode::FiveStep);
+    assert_eq!(bus.mem_read(0x4017) & 1, 0);
+    assert_eq!(bus.mem_read(0x4016) & 1, 0);
+  }
+
+  #[test]
+  fn test_ppu_address_space_mirroring() {
+    let mut bus = Bus::new();
+    bus.ppu.mirroring = Mirroring::Vertical;
+
+    bus.ppu_write(0x0010, 0xAA);
+    assert_eq!(bus.ppu_read(0x0010), 0xAA);
+
+    // Vertical mirroring: $2800 mirrors $2000, and $3000-$3EFF mirrors $2000-$2EFF
+    bus.ppu_write(0x2005, 0x11);
+    assert_eq!(bus.ppu_read(0x2805), 0x11);
+    assert_eq!(bus.ppu_read(0x3005), 0x11);
+    assert_ne!(bus.ppu_read(0x2405), 0x11);
+
+    // Palette RAM repeats every 32 bytes and $3F10 mirrors $3F00
+    bus.ppu_write(0x3F10, 0x0F);
+    assert_eq!(bus.ppu_read(0x3F00), 0x0F);
+    assert_eq!(bus.ppu_read(0x3F20), 0x0F);
+
+    // Addresses above $3FFF wrap into the 14 bit space
+    assert_eq!(bus.ppu_read(0x4010), 0xAA);
+
+    // CPU access through $2006/$2007 sees the same memory
+    bus.mem_write(0x2006, 0x20);
+    bus.mem_write(0x2006, 0x05);
+    bus.mem_read(0x2007);
+    assert_eq!(bus.mem_read(0x2007), 0x11);
+  }
+
+  // Start a DMC sample at $C000 holding the given bytes, with $4010 flags set to control
+  fn play_dmc_sample(bus: &mut Bus, control: u8, level: u8, sample: &[u8]) {
+    for (i, byte) in sample.iter().enumerate() {
+      bus.mem_write(0xC000 + i as u16, *byte);
+    }
+    bus.mem_write(0x4010, control);
+    bus.mem_write(0x4011, level);
+    bus.mem_write(0x4012, 0x00);
+    bus.mem_write(0x4013, ((sample.len() - 1) / 16) as u8);
+    bus.mem_write(0x4015, 0b0001_0000);
+  }
+
+  // Tick one cycle at a time, keeping each distinct DMC output level in order
+  fn dmc_levels(bus: &mut Bus, cycles: usize) -> Vec<u8> {
+    let mut levels = vec![bus.apu.dmc.output()];
+    for _ in 0..cycles {
+      bus.tick(1);
+      if *levels.last().unwrap() != bus.apu.dmc.output() {
+        levels.push(bus.apu.dmc.output());
+      }
+    }
+    levels
+  }
+
+  #[test]
+  fn test_dmc_plays_sample_and_raises_irq() {
+    let mut bus = Bus::new();
+    play_dmc_sample(&mut bus, 0b1000_1111, 64, &[0b1111_0101]); // IRQ on, rate 54
+    assert_eq!(bus.apu.dmc.bytes_remaining, 1);
+    assert_eq!(bus.mem_read(0x4015) & 0b1001_0000, 0b0001_0000);
+
+    // The reader fetches straight away and steals 4 CPU cycles for it
+    bus.tick(1);
+    assert_eq!(bus.apu.dmc.bytes_remaining, 0);
+    assert_eq!(bus.poll_dmc_stall(), 4);
+    assert_eq!(bus.poll_dmc_stall(), 0);
+
+    // Finishing the last fetch raises the interrupt
+    assert!(bus.irq_line());
+    assert_eq!(bus.mem_read(0x4015) & 0b1001_0000, 0b1000_0000);
+
+    // Bits play out least significant first, +2 for a 1 and -2 for a 0
+    assert_eq!(dmc_levels(&mut bus, 54 * 8 * 3), vec![64, 66, 64, 66, 64, 66, 68, 70, 72]);
+
+    // Writing $4015 acknowledges the interrupt
+    bus.mem_write(0x4015, 0x00);
+    assert!(!bus.irq_line());
+  }
+
+  #[test]
+  fn test_dmc_loops_without_irq() {
+    let mut bus = Bus::new();
+    play_dmc_sample(&mut bus, 0b1100_1111, 100, &[0x00]); // IRQ enabled, but looping
+
+    let levels = dmc_levels(&mut bus, 54 * 8 * 4);
+    assert!(*levels.last().unwrap() < 100 - 16 * 2); // Played past the end of the 1 byte sample
+    assert!(levels.windows(2).all(|pair| pair[1] == pair[0] - 2));
+    assert_eq!(bus.apu.dmc.current_address, 0xC000);
+    assert_eq!(bus.apu.dmc.bytes_remaining, 1); // Reloaded every time the byte is fetched
+    assert!(!bus.irq_line());
+    assert_eq!(bus.mem_read(0x4015) & 0b1001_0000, 0b0001_0000);
+  }
+
+  #[test]
+  fn test_peek_has_no_side_effects() {
+    let mut bus = Bus::new();
+    bus.mem_write(0x0012, 0x34);
+    bus.mem_write(0x6000, 0x56);
+    bus.ppu.status = 0x80;
+
+    assert_eq!(bus.peek(0x0812), 0x34);
+    assert_eq!(bus.peek(0x6000), 0x56);
+    assert_eq!(bus.peek(0x2002), 0);
+    assert_eq!(bus.ppu.status, 0x80); // A real $2002 read would have cleared vblank
+  }
+}
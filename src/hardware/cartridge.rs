@@ -0,0 +1,204 @@
+#![allow(unused)]
+/* The following code parses iNES game cartridges */
+
+use std::fmt;
+use std::path::Path;
+
+const NES_TAG: [u8; 4] = [0x4E, 0x45, 0x53, 0x1A]; // "NES\x1A"
+const HEADER_SIZE: usize = 16;
+const TRAINER_SIZE: usize = 512;
+const PRG_ROM_PAGE_SIZE: usize = 0x4000; // 16KB
+const CHR_ROM_PAGE_SIZE: usize = 0x2000; // 8KB
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mirroring {
+  Vertical,
+  Horizontal,
+  FourScreen,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CartridgeError {
+  InvalidFormat, // Missing the "NES\x1A" magic
+  UnsupportedVersion, // NES 2.0 headers aren't supported yet
+  Truncated, // File is shorter than the header says
+}
+
+impl fmt::Display for CartridgeError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      CartridgeError::InvalidFormat => write!(f, "file is not in iNES file format"),
+      CartridgeError::UnsupportedVersion => write!(f, "NES2.0 format is not supported"),
+      CartridgeError::Truncated => write!(f, "file is smaller than its header declares"),
+    }
+  }
+}
+
+impl std::error::Error for CartridgeError {}
+
+pub struct Cartridge {
+  pub prg_rom: Vec<u8>,
+  pub chr_rom: Vec<u8>,
+  pub mapper: u8,
+  pub screen_mirroring: Mirroring,
+}
+
+impl Cartridge {
+  // Cartridge constructor (parses a raw iNES image)
+  pub fn new(raw: &[u8]) -> Result<Cartridge, CartridgeError> {
+    if raw.len() < HEADER_SIZE || raw[0..4] != NES_TAG {
+      return Err(CartridgeError::InvalidFormat);
+    }
+
+    let mapper = (raw[7] & 0b1111_0000) | (raw[6] >> 4);
+
+    let ines_ver = (raw[7] >> 2) & 0b11;
+    if ines_ver != 0 {
+      return Err(CartridgeError::UnsupportedVersion);
+    }
+
+    let four_screen = raw[6] & 0b1000 != 0;
+    let vertical_mirroring = raw[6] & 0b1 != 0;
+    let screen_mirroring = match (four_screen, vertical_mirroring) {
+      (true, _) => Mirroring::FourScreen,
+      (false, true) => Mirroring::Vertical,
+      (false, false) => Mirroring::Horizontal,
+    };
+
+    let prg_rom_size = raw[4] as usize * PRG_ROM_PAGE_SIZE;
+    let chr_rom_size = raw[5] as usize * CHR_ROM_PAGE_SIZE;
+
+    let skip_trainer = raw[6] & 0b100 != 0;
+
+    let prg_rom_start = HEADER_SIZE + if skip_trainer { TRAINER_SIZE } else { 0 };
+    let chr_rom_start = prg_rom_start + prg_rom_size;
+
+    if raw.len() < chr_rom_start + chr_rom_size {
+      return Err(CartridgeError::Truncated);
+    }
+
+    Ok(Cartridge {
+      prg_rom: raw[prg_rom_start .. (prg_rom_start + prg_rom_size)].to_vec(),
+      chr_rom: raw[chr_rom_start .. (chr_rom_start + chr_rom_size)].to_vec(),
+      mapper,
+      screen_mirroring,
+    })
+  }
+}
+
+#[derive(Debug)]
+pub enum LoadError {
+  Io(std::io::Error),
+  Parse(CartridgeError),
+}
+
+impl fmt::Display for LoadError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      LoadError::Io(err) => write!(f, "could not read cartridge: {}", err),
+      LoadError::Parse(err) => write!(f, "could not parse cartridge: {}", err),
+    }
+  }
+}
+
+impl std::error::Error for LoadError {
+  fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+    match self {
+      LoadError::Io(err) => Some(err),
+      LoadError::Parse(err) => Some(err),
+    }
+  }
+}
+
+impl From<std::io::Error> for LoadError {
+  fn from(err: std::io::Error) -> Self {
+    LoadError::Io(err)
+  }
+}
+
+impl From<CartridgeError> for LoadError {
+  fn from(err: CartridgeError) -> Self {
+    LoadError::Parse(err)
+  }
+}
+
+// Read an iNES file from disk and parse it into a Cartridge
+pub fn load_from_file(path: &Path) -> Result<Cartridge, LoadError> {
+  let raw = std::fs::read(path)?;
+
+  if raw.len() < NES_TAG.len() || raw[0..4] != NES_TAG {
+    return Err(LoadError::Parse(CartridgeError::InvalidFormat));
+  }
+
+  Ok(Cartridge::new(&raw)?)
+}
+
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use std::io::Write;
+
+  // Build a raw iNES image with the given header flags and PRG/CHR page counts
+  fn create_ines(prg_pages: u8, chr_pages: u8, flags_6: u8, flags_7: u8) -> Vec<u8> {
+    let mut raw = vec![0x4E, 0x45, 0x53, 0x1A, prg_pages, chr_pages, flags_6, flags_7];
+    raw.resize(HEADER_SIZE, 0x00);
+    raw.extend(vec![0xEA; prg_pages as usize * PRG_ROM_PAGE_SIZE]);
+    raw.extend(vec![0x55; chr_pages as usize * CHR_ROM_PAGE_SIZE]);
+    raw
+  }
+
+  #[test]
+  fn test_new_parses_header() {
+    let cart = Cartridge::new(&create_ines(2, 1, 0b0001_0001, 0b0000_0000)).unwrap();
+
+    assert_eq!(cart.prg_rom.len(), 2 * PRG_ROM_PAGE_SIZE);
+    assert_eq!(cart.chr_rom.len(), CHR_ROM_PAGE_SIZE);
+    assert_eq!(cart.mapper, 1);
+    assert_eq!(cart.screen_mirroring, Mirroring::Vertical);
+  }
+
+  #[test]
+  fn test_new_rejects_bad_images() {
+    let mut raw = create_ines(1, 1, 0, 0);
+    raw[0] = 0x00;
+    assert_eq!(Cartridge::new(&raw).err(), Some(CartridgeError::InvalidFormat));
+
+    assert_eq!(Cartridge::new(&create_ines(1, 1, 0, 0b0000_1000)).err(), Some(CartridgeError::UnsupportedVersion));
+
+    let mut raw = create_ines(1, 1, 0, 0);
+    raw.truncate(HEADER_SIZE + 10);
+    assert_eq!(Cartridge::new(&raw).err(), Some(CartridgeError::Truncated));
+  }
+
+  #[test]
+  fn test_load_from_file() {
+    let mut file = tempfile::NamedTempFile::new().unwrap();
+    file.write_all(&create_ines(1, 1, 0b0000_1000, 0)).unwrap();
+
+    let cart = load_from_file(file.path()).unwrap();
+
+    assert_eq!(cart.prg_rom.len(), PRG_ROM_PAGE_SIZE);
+    assert_eq!(cart.chr_rom.len(), CHR_ROM_PAGE_SIZE);
+    assert_eq!(cart.mapper, 0);
+    assert_eq!(cart.screen_mirroring, Mirroring::FourScreen);
+  }
+
+  #[test]
+  fn test_load_from_file_missing_path() {
+    let dir = tempfile::tempdir().unwrap();
+    let result = load_from_file(&dir.path().join("missing.nes"));
+
+    assert!(matches!(result, Err(LoadError::Io(_))));
+  }
+
+  #[test]
+  fn test_load_from_file_not_ines() {
+    let mut file = tempfile::NamedTempFile::new().unwrap();
+    file.write_all(b"definitely not a rom").unwrap();
+
+    let result = load_from_file(file.path());
+
+    assert!(matches!(result, Err(LoadError::Parse(CartridgeError::InvalidFormat))));
+  }
+}
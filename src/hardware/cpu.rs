@@ -1,11 +1,22 @@
 #![allow(unused)]
 
 pub use super::opcodes::{
+  implemented_opcodes,
+  is_implemented,
+  is_unofficial,
   AddressingMode,
   OpCode,
   OPCODES_MAP,
-  STATUS_FLAGS
+  STATUS_FLAGS,
+  StatusFlags
 };
+use super::bus::{Bus, FillPattern};
+use super::cartridge;
+use super::error::{CpuError, TimingError};
+use crate::assembler;
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::path::Path;
 
 // // CPU Core Registers (Global)
 // static mut A: u8 = 0x00; // Accumulator Reg
@@ -17,17 +28,151 @@ pub use super::opcodes::{
 
 const STACK: u16 = 0x0100;
 const STACK_RESET: u8 = 0xFD;
+const NMI_VECTOR: u16 = 0xFFFA;
+const IRQ_VECTOR: u16 = 0xFFFE;
 
+// Instructions load_and_run allows before assuming the program is stuck in a loop
+pub const DEFAULT_INSTRUCTION_LIMIT: u64 = 1_000_000;
 
+// Automated test ROM (blargg) protocol: status at $6000, signature at $6001-$6003, text from $6004
+const TEST_ROM_STATUS: u16 = 0x6000;
+const TEST_ROM_SIGNATURE: u16 = 0x6001;
+const TEST_ROM_MESSAGE: u16 = 0x6004;
+const TEST_ROM_SIGNATURE_BYTES: [u8; 3] = [0xDE, 0xB0, 0x61];
+const TEST_ROM_NOT_STARTED: u8 = 0xFF;
+const TEST_ROM_RUNNING: u8 = 0x80;
+const TEST_ROM_NEEDS_RESET: u8 = 0x81;
 
+#[derive(Debug, PartialEq, Eq)]
+pub enum TestRomResult {
+  Passed,
+  Failed { code: u8, message: String },
+  Timeout,
+}
+
+
+
+// What a single CPU::tick did
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StepResult {
+  pub cycles: u64, // CPU cycles used, including interrupt entry and DMA stalls
+  pub nmi: bool, // An NMI is pending and will be taken before the next instruction
+  pub running: bool, // False once BRK is hit
+}
+
+// Snapshot of the registers and cycle count (memory not included)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CpuState {
+  pub a: u8,
+  pub x: u8,
+  pub y: u8,
+  pub status: u8,
+  pub sp: u8,
+  pub pc: u16,
+  pub cycles: u64,
+}
+
+// The instruction at the PC, decoded by CPU::peek_instruction without executing it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PeekedInstruction {
+  pub address: u16,
+  pub opcode: u8,
+  pub operands: Vec<u8>, // Bytes after the opcode (byte count - 1 of them)
+  pub mnemonic: &'static str,
+  pub mode: AddressingMode,
+  pub operand_address: Option<u16>, // Where the operand lives, for modes that address memory
+}
+
+// CPU state just before an instruction executes, handed to the attached CpuLogger
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TraceEntry {
+  pub program_counter: u16,
+  pub opcode: u8,
+  pub operands: Vec<u8>, // Bytes after the opcode (byte count - 1 of them)
+  pub mnemonic: &'static str,
+  pub mode: AddressingMode,
+  pub register_a: u8,
+  pub register_x: u8,
+  pub register_y: u8,
+  pub status: StatusFlags,
+  pub stack_pointer: u8,
+  pub cycles: u64,
+}
+
+// One trace line: address, instruction bytes, mnemonic, then registers with the flags spelled out
+impl fmt::Display for TraceEntry {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    let mut bytes = format!("{:02X}", self.opcode);
+    for operand in &self.operands {
+      bytes.push_str(&format!(" {:02X}", operand));
+    }
+    write!(
+      f,
+      "{:04X}  {:<8}  {}  A:{:02X} X:{:02X} Y:{:02X} P:{} SP:{:02X} CYC:{}",
+      self.program_counter,
+      bytes,
+      self.mnemonic,
+      self.register_a,
+      self.register_x,
+      self.register_y,
+      format_status(self.status.bits()),
+      self.stack_pointer,
+      self.cycles
+    )
+  }
+}
+
+// Returns true if a and b are in different 256 byte pages, which costs indexed reads
+// (and taken branches) an extra cycle
+fn page_crossed(a: u16, b: u16) -> bool {
+  a & 0xFF00 != b & 0xFF00
+}
+
+// Render a status byte as NV-BDIZC, uppercase for set flags and lowercase for clear ones
+// (bit 5 has no flag and is always shown as -)
+pub fn format_status(status: u8) -> String {
+  "NV-BDIZC".chars()
+    .enumerate()
+    .map(|(i, letter)| {
+      if letter == '-' || status & (0x80 >> i) != 0 {
+        letter
+      }
+      else {
+        letter.to_ascii_lowercase()
+      }
+    })
+    .collect()
+}
+
+// Receives a TraceEntry for every instruction while attached with CPU::set_logger
+pub trait CpuLogger {
+  fn log(&mut self, entry: &TraceEntry);
+}
+
+// How many times each opcode byte has executed while profiling is enabled
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct InstructionStats {
+  pub counts: HashMap<u8, u64>,
+  pub total: u64,
+}
+
+#[allow(clippy::upper_case_acronyms)]
 pub struct CPU {
   pub register_a: u8,
   pub register_x: u8,
   pub register_y: u8,
-  pub status: u8,
+  pub status: StatusFlags,
   pub stack_pointer: u8,
   pub program_counter: u16,
-  memory: [u8; 0xFFFF]
+  cycles: u64,
+  nmi_pending: bool,
+  irq_requested: bool, // Software IRQ from request_irq, latched until serviced (the bus IRQ line is level triggered)
+  pub bus: Bus,
+  logger: Option<Box<dyn CpuLogger>>,
+  pub profiling: Option<InstructionStats>,
+  execution_history: VecDeque<u16>, // Addresses of the most recent instructions, oldest first
+  history_enabled: bool,
+  history_depth: usize,
 }
 
 impl CPU {
@@ -37,28 +182,50 @@ impl CPU {
       register_a: 0,
       register_x: 0,
       register_y: 0,
-      status: 0b0010_0100,
+      status: StatusFlags::from_bits_truncate(0b0010_0100),
       stack_pointer: STACK_RESET,
       program_counter: 0,
-      memory: [0x00; 0xFFFF]
+      cycles: 0,
+      nmi_pending: false,
+      irq_requested: false,
+      bus: Bus::new(),
+      logger: None,
+      profiling: None,
+      execution_history: VecDeque::new(),
+      history_enabled: false,
+      history_depth: 0,
     }
   }
 
+  // CPU constructor with internal RAM filled in a power on pattern (CPU::new leaves it all zero)
+  pub fn with_fill_pattern(pattern: FillPattern) -> Self {
+    let mut cpu = CPU::new();
+    cpu.bus.fill_ram(pattern);
+    cpu
+  }
+
+  // A CPU that starts executing at entry, for programs loaded with load_image that have no reset vector
+  pub fn new_running_at(entry: u16) -> Self {
+    let mut cpu = CPU::new();
+    cpu.program_counter = entry;
+    cpu
+  }
+
   // Read from Memory
-  fn mem_read(&self, addr: u16) -> u8 {
-    self.memory[addr as usize]
+  fn mem_read(&mut self, addr: u16) -> u8 {
+    self.bus.mem_read(addr)
   }
   // Read from Memory in little endian format
-  fn mem_read_u16(&self, memory_pos: u16) -> u16 {
+  fn mem_read_u16(&mut self, memory_pos: u16) -> u16 {
     let lo = self.mem_read(memory_pos) as u16;
-    let hi = self.mem_read(memory_pos + 1) as u16;
+    let hi = self.mem_read(memory_pos.wrapping_add(1)) as u16;
 
-    (hi << 8) | (lo as u16)
+    (hi << 8) | lo
   }
 
   // Write to Memory
   fn mem_write(&mut self, addr: u16, data: u8) {
-    self.memory[addr as usize] = data;
+    self.bus.mem_write(addr, data);
   }
   // Write to Memory in little endian format
   fn mem_write_u16(&mut self, memory_pos: u16, data: u16) {
@@ -66,122 +233,231 @@ impl CPU {
     let lo = (data & 0xff) as u8;
 
     self.mem_write(memory_pos, lo);
-    self.mem_write(memory_pos + 1, hi);
+    self.mem_write(memory_pos.wrapping_add(1), hi);
+  }
+
+  // Push a byte onto the stack
+  fn stack_push(&mut self, data: u8) {
+    self.mem_write(STACK + self.stack_pointer as u16, data);
+    self.stack_pointer = self.stack_pointer.wrapping_sub(1);
+  }
+  // Pull a byte off of the stack
+  fn stack_pop(&mut self) -> u8 {
+    self.stack_pointer = self.stack_pointer.wrapping_add(1);
+    self.mem_read(STACK + self.stack_pointer as u16)
+  }
+  // Push a 16 bit value onto the stack (high byte first)
+  fn stack_push_u16(&mut self, data: u16) {
+    self.stack_push((data >> 8) as u8);
+    self.stack_push((data & 0xff) as u8);
+  }
+  // Pull a 16 bit value off of the stack (low byte first)
+  fn stack_pop_u16(&mut self) -> u16 {
+    let lo = self.stack_pop() as u16;
+    let hi = self.stack_pop() as u16;
+
+    (hi << 8) | lo
+  }
+
+  // Look up a status flag by name
+  fn status_flag(flag: &'static str) -> Result<StatusFlags, CpuError> {
+    STATUS_FLAGS.get(flag)
+      .map(|bits| StatusFlags::from_bits_truncate(*bits))
+      .ok_or(CpuError::UnknownFlag(flag))
   }
 
   // Returns true if the given status flag is set
-  fn check_status_flag_set(&self, flag: &'static str) -> bool {
-    let status_flag = (*STATUS_FLAGS.get(flag)
-      .expect(&format!(
-        "Bruh this flag doesn't exist: {flag}"
-      )));
-    status_flag & self.status != 0
+  fn check_status_flag_set(&self, flag: &'static str) -> Result<bool, CpuError> {
+    Ok(self.status.intersects(Self::status_flag(flag)?))
   }
 
   // Helper function that sets status flags
-  fn set_status_flag(&mut self, flag: &'static str) {
-    let status_flag = (*STATUS_FLAGS.get(flag)
-      .expect(&format!(
-        "Bruh this flag doesn't exist: {flag}"
-      )));
-    self.status |= status_flag;
+  fn set_status_flag(&mut self, flag: &'static str) -> Result<(), CpuError> {
+    self.status.insert(Self::status_flag(flag)?);
+    Ok(())
   }
 
   // Helper function that unsets status flags
-  fn unset_status_flag(&mut self, flag: &'static str) {
-    let status_flag = (*STATUS_FLAGS.get(flag)
-      .expect(&format!(
-        "Bruh this flag doesn't exist: {flag}"
-      )));
-    
-    self.status &= !status_flag;
+  fn unset_status_flag(&mut self, flag: &'static str) -> Result<(), CpuError> {
+    self.status.remove(Self::status_flag(flag)?);
+    Ok(())
   }
 
-  // Helper function that adds value to register A
+  // Helper function that adds value plus carry to register A, setting carry, overflow, zero and negative
+  // (the 2A03 has no decimal mode, so this is binary only)
   fn add_to_register_a(&mut self, value: u8) {
-    let sum = self.register_a as u16
-      + value as u16
-      + (if self.check_status_flag_set("CARRY") { 1 } else { 0 });
+    let current_accumulator_value = self.register_a;
+    let carry_bit = self.status.contains(StatusFlags::CARRY) as u16;
 
-    let should_carry = sum > 0xFF;
-    if should_carry {
-      self.set_status_flag("CARRY");
-    }
-    else {
-      self.unset_status_flag("CARRY");
-    }
+    let sum = current_accumulator_value as u16 + value as u16 + carry_bit;
+    let result = sum as u8;
+    self.register_a = result;
+
+    self.status.set(StatusFlags::CARRY, sum > 0xFF);
+    // Overflow when both inputs share a sign and the result's sign differs from them
+    self.status.set(StatusFlags::OVERFLOW, (current_accumulator_value ^ result) & (value ^ result) & 0x80 != 0);
+
+    self.update_zero_and_negative_flags(result);
   }
 
   // Determine what register to return based on Addressing Mode
-  fn get_operand_address(&self, mode: &AddressingMode) -> u16 {
-    match mode {
-      AddressingMode::Absolute => {
-        self.mem_read_u16(self.program_counter)
-      },
+  fn get_operand_address(&mut self, mode: &AddressingMode) -> Result<u16, CpuError> {
+    self.get_operand_address_page_cross(mode).map(|(addr, _)| addr)
+  }
+  // Like get_operand_address, also reporting whether indexing crossed into another page
+  fn get_operand_address_page_cross(&mut self, mode: &AddressingMode) -> Result<(u16, bool), CpuError> {
+    let (operand_pc, x, y) = (self.program_counter, self.register_x, self.register_y);
+    let bus = &mut self.bus;
+    Self::resolve_operand_address(mode, operand_pc, x, y, |addr| bus.mem_read(addr))
+  }
+  // Work out the operand address for the operand bytes at operand_pc, reading memory through read
+  // so executing and peeking at an instruction share the same decode
+  fn resolve_operand_address(
+    mode: &AddressingMode, operand_pc: u16, x: u8, y: u8, mut read: impl FnMut(u16) -> u8
+  ) -> Result<(u16, bool), CpuError> {
+    let mut read_u16 = |addr: u16| (read(addr) as u16) | ((read(addr.wrapping_add(1)) as u16) << 8);
+    Ok(match mode {
       AddressingMode::Absolute_X => {
-        let base = self.mem_read_u16(self.program_counter);
-        let addr = base.wrapping_add(self.register_x as u16);
-        addr
+        let base = read_u16(operand_pc);
+        let addr = base.wrapping_add(x as u16);
+        (addr, page_crossed(base, addr))
       },
       AddressingMode::Absolute_Y => {
-        let base = self.mem_read_u16(self.program_counter);
-        let addr = base.wrapping_add(self.register_y as u16);
-        addr
+        let base = read_u16(operand_pc);
+        let addr = base.wrapping_add(y as u16);
+        (addr, page_crossed(base, addr))
+      },
+      AddressingMode::Indirect_Y => {
+        let base = read(operand_pc);
+
+        let lo = read(base as u16) as u16;
+        let hi = read(base.wrapping_add(1) as u16) as u16;
+
+        let deref_base = (hi << 8) | lo;
+        let addr = deref_base.wrapping_add(y as u16);
+        (addr, page_crossed(deref_base, addr))
+      },
+      _ => (Self::resolve_unindexed_operand_address(mode, operand_pc, x, y, read)?, false)
+    })
+  }
+  // Addressing modes that can never cross a page
+  fn resolve_unindexed_operand_address(
+    mode: &AddressingMode, operand_pc: u16, x: u8, y: u8, mut read: impl FnMut(u16) -> u8
+  ) -> Result<u16, CpuError> {
+    Ok(match mode {
+      AddressingMode::Absolute => {
+        (read(operand_pc) as u16) | ((read(operand_pc.wrapping_add(1)) as u16) << 8)
       },
       AddressingMode::Immediate => {
-        self.program_counter
+        operand_pc
       },
       AddressingMode::Indirect_X => {
-        let base = self.mem_read(self.program_counter);
-        let ptr: u8 = (base as u8).wrapping_add(self.register_x);
+        let base = read(operand_pc);
+        let ptr: u8 = base.wrapping_add(x);
 
-        let lo = self.mem_read(ptr as u16) as u16;
-        let hi = self.mem_read(ptr.wrapping_add(1) as u16) as u16;
+        let lo = read(ptr as u16) as u16;
+        let hi = read(ptr.wrapping_add(1) as u16) as u16;
 
         (hi << 8) | lo
       },
-      AddressingMode::Indirect_Y => {
-        let base = self.mem_read(self.program_counter);
-        
-        let lo = self.mem_read(base as u16) as u16;
-        let hi = self.mem_read((base as u8).wrapping_add(1) as u16) as u16;
-
-        let deref_base = (hi << 8) | lo;
-        let derefed = deref_base.wrapping_add(self.register_y as u16);
-        derefed
-      },
       AddressingMode::ZeroPage => {
-        self.mem_read(self.program_counter) as u16
+        read(operand_pc) as u16
       },
       AddressingMode::ZeroPage_X => {
-        let pos = self.mem_read(self.program_counter);
-        let addr = pos.wrapping_add(self.register_x) as u16;
-        addr
+        let pos = read(operand_pc);
+        pos.wrapping_add(x) as u16
       },
       AddressingMode::ZeroPage_Y => {
-        let pos = self.mem_read(self.program_counter);
-        let addr = pos.wrapping_add(self.register_y) as u16;
-        addr
+        let pos = read(operand_pc);
+        pos.wrapping_add(y) as u16
       },
-      AddressingMode::NoneAddressing => {
-        panic!("mode {:?} is not supported", mode);
+      _ => {
+        return Err(CpuError::UnsupportedMode(*mode));
       }
+    })
+  }
+  // Fetch an instruction's operand value. Reads that index across a page take an extra cycle
+  // (stores and read-modify-write instructions always pay it, so it's already in their cycle count)
+  fn read_operand(&mut self, mode: &AddressingMode) -> Result<u8, CpuError> {
+    let (addr, page_crossed) = self.get_operand_address_page_cross(mode)?;
+    if page_crossed {
+      self.add_cycles(1);
     }
+    Ok(self.mem_read(addr))
   }
 
   // Update zero and negative flags based on results of an operation
   fn update_zero_and_negative_flags(&mut self, result: u8) {
-    if result == 0 { self.status |= 0b0000_0010; }
-    else { self.status &= 0b1111_1101; }
+    self.status.set(StatusFlags::ZERO, result == 0);
+    self.status.set(StatusFlags::NEGATIVE, result & 0b1000_0000 != 0);
+  }
+
+  // Count elapsed CPU cycles and advance the rest of the hardware alongside
+  fn add_cycles(&mut self, cycles: u16) {
+    self.cycles += cycles as u64;
+    self.bus.tick(cycles);
+  }
+
+  // Push program counter and status, then jump through the given interrupt vector
+  fn interrupt(&mut self, vector: u16) {
+    self.stack_push_u16(self.program_counter);
+
+    // Hardware interrupts push status with BREAK clear and BREAK2 set
+    let mut pushed_status = self.status;
+    pushed_status.remove(StatusFlags::BREAK);
+    pushed_status.insert(StatusFlags::BREAK2);
+    self.stack_push(pushed_status.bits());
+
+    self.status.insert(StatusFlags::INTERRUPT_DISABLE);
+    self.program_counter = self.mem_read_u16(vector);
+    self.add_cycles(7);
+  }
+
+  // Non-maskable interrupt (vector at 0xFFFA)
+  fn interrupt_nmi(&mut self) {
+    self.nmi_pending = false;
+    self.interrupt(NMI_VECTOR);
+  }
+
+  // Maskable interrupt request (vector at 0xFFFE)
+  fn interrupt_irq(&mut self) {
+    self.irq_requested = false;
+    self.interrupt(IRQ_VECTOR);
+  }
+
+  // Signal an NMI to be serviced at the next instruction boundary
+  pub fn request_nmi(&mut self) {
+    self.nmi_pending = true;
+  }
+
+  // Signal an IRQ to be serviced at the next instruction boundary (if interrupts are enabled)
+  pub fn request_irq(&mut self) {
+    self.irq_requested = true;
+  }
+
+  // Returns true while an NMI is waiting to be serviced
+  pub fn nmi_pending(&self) -> bool {
+    self.nmi_pending
+  }
 
-    if result & 0b1000_0000 != 0 { self.status |= 0b1000_0000; }
-    else { self.status &= 0b0111_1111; }
+  // Returns true while an IRQ is waiting to be serviced, either requested through request_irq
+  // (which stays pending while interrupts are disabled) or held on the bus IRQ line by a device
+  pub fn irq_pending(&self) -> bool {
+    self.irq_requested || self.bus.irq_line()
   }
 
   /* Opcode Functions */
   // Force Interrupt
   fn brk(&mut self) {
-    self.status |= 0b0001_0100;
+    self.status.insert(StatusFlags::BREAK | StatusFlags::INTERRUPT_DISABLE);
+  }
+  // No operation (unofficial forms still read their operand)
+  fn nop(&mut self, mode: &AddressingMode) -> Result<(), CpuError> {
+    // The read happens for real, page-cross cycle included
+    if *mode != AddressingMode::NoneAddressing {
+      self.read_operand(mode)?;
+    }
+    Ok(())
   }
   // Increment register X by 1
   fn inx(&mut self) {
@@ -190,19 +466,42 @@ impl CPU {
   }
 
   // Load accumulator
-  fn lda(&mut self, mode: &AddressingMode) {
-    let addr = self.get_operand_address(mode);
-    let value = self.mem_read(addr);
-    println!("addr: {}", addr);
-    println!("value: {}", value);
+  fn lda(&mut self, mode: &AddressingMode) -> Result<(), CpuError> {
+    let value = self.read_operand(mode)?;
     self.register_a = value;
     self.update_zero_and_negative_flags(self.register_a);
+    Ok(())
+  }
+
+  // Increment value in memory location
+  fn inc(&mut self, mode: &AddressingMode) -> Result<(), CpuError> {
+    let addr = self.get_operand_address(mode)?;
+    let value = self.mem_read(addr).wrapping_add(1);
+    self.mem_write(addr, value);
+    self.update_zero_and_negative_flags(value);
+    Ok(())
+  }
+
+  // Jump to address
+  fn jmp(&mut self, mode: &AddressingMode) -> Result<(), CpuError> {
+    self.program_counter = self.get_operand_address(mode)?;
+    Ok(())
+  }
+
+  // Return from interrupt
+  fn rti(&mut self) {
+    self.status = StatusFlags::from_bits_truncate(self.stack_pop());
+    self.status.remove(StatusFlags::BREAK);
+    self.status.insert(StatusFlags::BREAK2);
+
+    self.program_counter = self.stack_pop_u16();
   }
 
   // Store accumulator
-  fn sta(&mut self, mode: &AddressingMode) {
-    let addr = self.get_operand_address(mode);
+  fn sta(&mut self, mode: &AddressingMode) -> Result<(), CpuError> {
+    let addr = self.get_operand_address(mode)?;
     self.mem_write(addr, self.register_a);
+    Ok(())
   }
 
   // Transfer accumulator to register X
@@ -212,41 +511,32 @@ impl CPU {
   }
 
   // Add memory contents to accumulator with carry bit (set carry if overflow)
-  fn adc(&mut self, mode: &AddressingMode) {
-    let addr = self.get_operand_address(mode);
-    let value = self.mem_read(addr);
-
-    let current_accumulator_value = self.register_a;
-    let carry_bit = if self.status & 0b0000_0001 != 0 { 1 } else { 0 } as u8;
-
-    let result = self.register_a.wrapping_add(value).wrapping_add(carry_bit);
-    self.register_a = result;
-
-    if result <= current_accumulator_value { self.status |= 0b0100_0001; }
-    else { self.status &= 0b1011_1110; }
-
-    self.update_zero_and_negative_flags(result);
+  fn adc(&mut self, mode: &AddressingMode) -> Result<(), CpuError> {
+    let value = self.read_operand(mode)?;
+    self.add_to_register_a(value);
+    Ok(())
   }
 
-  // Subtract memory contents to accumulator with negated carry bit (clear carry if overflow)
-  fn sbc(&mut self, mode: &AddressingMode) {
-    let addr = self.get_operand_address(mode);
-    let value = self.mem_read(addr);
-
-    let current_accumulator_value = self.register_a;
-    let carry_bit = if self.status & 0b0000_0001 != 0 { 1 } else { 0 } as u8;
-
-    let result = self.register_a.wrapping_sub(value).wrapping_sub(1 - carry_bit);
-    self.register_a = result;
-
-    // if result >= current_accumulator_value { self.status |= }
+  // Subtract memory contents from accumulator with borrow (carry clear means borrow),
+  // A - M - (1 - C) is the same as A + !M + C
+  fn sbc(&mut self, mode: &AddressingMode) -> Result<(), CpuError> {
+    let value = self.read_operand(mode)?;
+    self.add_to_register_a(!value);
+    Ok(())
   }
   /* End of Opcode Functions */
 
-  pub fn load_and_run(&mut self, program: Vec<u8>) {
+  pub fn load_and_run(&mut self, program: Vec<u8>) -> Result<(), CpuError> {
     self.load(program);
     self.reset();
-    self.run();
+    self.run_with_limit(DEFAULT_INSTRUCTION_LIMIT)
+  }
+
+  // Assemble 6502 source, then load and run it like load_and_run
+  pub fn load_and_run_asm(&mut self, source: &str) -> Result<(), CpuError> {
+    let program = assembler::assemble(source, 0x8000)
+      .unwrap_or_else(|err| panic!("Couldn't assemble program: {}", err));
+    self.load_and_run(program)
   }
 
   pub fn reset(&mut self) {
@@ -255,104 +545,502 @@ impl CPU {
     self.register_y = 0;
 
     self.stack_pointer = STACK_RESET;
-    self.status = 0b0010_0100;
+    self.status = StatusFlags::from_bits_truncate(0b0010_0100);
 
     self.program_counter = self.mem_read_u16(0xFFFC);
+    // The reset sequence takes 7 cycles (like an interrupt, but with the stack writes suppressed)
+    self.add_cycles(7);
   }
 
   pub fn load(&mut self, program: Vec<u8>) {
-    self.memory[0x8000 .. (0x8000 + program.len())].copy_from_slice(&program[..]);
+    for (i, byte) in program.iter().enumerate() {
+      self.mem_write(0x8000 + i as u16, *byte);
+    }
     self.mem_write_u16(0xFFFC, 0x8000);
   }
 
-  pub fn run(&mut self) {
+  // Copy a raw memory image to origin, leaving the reset vector to whatever the image contains
+  pub fn load_image(&mut self, origin: u16, image: &[u8]) {
+    for (i, byte) in image.iter().enumerate() {
+      self.mem_write(origin.wrapping_add(i as u16), *byte);
+    }
+  }
+
+  // Run until BRK, or until the CPU hits something it can't execute
+  pub fn run(&mut self) -> Result<(), CpuError> {
+    while self.step()? {}
+    Ok(())
+  }
+
+  // Like run, but gives up once max_instructions have executed without reaching BRK
+  pub fn run_with_limit(&mut self, max_instructions: u64) -> Result<(), CpuError> {
+    let mut executed = 0;
     loop {
-      let register = self.mem_read(self.program_counter);
-      self.program_counter += 1;
-      let current_prog_state = self.program_counter;
-
-      let OpCode(
-        name,
-        byte_count,
-        cycle_count,
-        mode
-      ) = *OPCODES_MAP
-        .get(&register)
-        .expect(&format!(
-          "Ah shit this opcode {:x} don't exist...",
-          register
-        ));
-      // println!("name {}", name);
-      // println!("mode {:?}", mode);
-
-      match name {
-        "ADC" => { self.adc(&mode); }, // Add with carry
-        "AND" => { todo!(); }, // Logical AND
-        "ASL" => { todo!(); }, // Arithmetic shift left
-        "BCC" => { todo!(); }, // Branch if carry flag is clear
-        "BCS" => { todo!(); }, // Branch if carry flag is set
-        "BEQ" => { todo!(); }, // Branch if equal
-        "BIT" => { todo!(); }, // Bit test
-        "BMI" => { todo!(); }, // Branch if negative flag is set
-        "BNE" => { todo!(); }, // Branch if not equal
-        "BRK" => { self.brk(); }, // Force interrupt
-        "BVC" => { todo!(); }, // Branch if overflow flag is clear
-        "BVS" => { todo!(); }, // Branch if overflow flag is set
-        "CLC" => { todo!(); }, // Clear carry flag
-        "CLD" => { todo!(); }, // Clear decimal mode flag
-        "CLI" => { todo!(); }, // Clear interrupt disable flag
-        "CLV" => { todo!(); }, // Clear overflow flag
-        "CMP" => { todo!(); }, // Compare value in register A with value in memory location
-        "CPX" => { todo!(); }, // Compare value in register X with value in memory location
-        "CPY" => { todo!(); }, // Compare value in register Y with value in memory location
-        "DEC" => { todo!(); }, // Decrement value in memory location
-        "DEX" => { todo!(); }, // Decrement value in register X
-        "DEY" => { todo!(); }, // Decrement value in register Y
-        "EOR" => { todo!(); }, // Logical XOR
-        "INC" => { todo!(); }, // Increment value in memory location
-        "INX" => { self.inx(); }, // Increment value in register X
-        "INY" => { todo!(); }, // Increment value in register Y
-        "JMP" => { todo!(); }, // Sets program counter to address specified by operand
-        "JSR" => { todo!(); }, // Jump to subroutine
-        "LDA" => { self.lda(&mode); }, // Load value into register A
-        "LDX" => { todo!(); }, // Load value into register X
-        "LDY" => { todo!(); }, // Load value into register Y
-        "LSR" => { todo!(); }, // Logicial shift right
-        "NOP" => { todo!(); }, // No operation to be made
-        "ORA" => { todo!(); }, // Logical OR
-        "PHA" => { todo!(); }, // Push copy of value in register A onto stack
-        "PHP" => { todo!(); }, // Push copy of processor status onto stack
-        "PLA" => { todo!(); }, // Pull 8 bit value from stack and loads it into register A
-        "PLP" => { todo!(); }, // Pull 8 bit value from stack and sets processor status to be said value
-        "ROL" => { todo!(); }, // Shift register A or memory location's value's bits to the left such that the 0th bit is set to be the carry flag's value and then the carry flag's value is set to be the old 7th bit value
-        "ROR" => { todo!(); }, // Same as ROR instruction except shift right (7th bit gets set to carry flag value and carry flag value gets set to old 0tth bit value)
-        "RTI" => { todo!(); }, // Return from processing routine interrupt, and pull and set processor status flags and program counter from stack
-        "RTS" => { todo!(); }, // Return from end of subroutine to routine that called it and pull and set program counter (minus 1) from stack
-        "SBC" => { self.sbc(&mode); }, // Subtract contents of memory location from register A with the NOT of the carry flag (if overflow, clear carry bit)
-        "SEC" => { todo!(); }, // Set carry flag to 1
-        "SED" => { todo!(); }, // Set decimal flag to 1
-        "SEI" => { todo!(); }, // Set interrupt disable flag to 1
-        "STA" => { self.sta(&mode); }, // Store register A value in memory location
-        "STX" => { todo!(); }, // Store register X value in memory location
-        "STY" => { todo!(); }, // Store registter Y value in memory location
-        "TAX" => { self.tax(); }, // Copy value in register A and store it in register X
-        "TAY" => { todo!(); }, // Copy value in register A and store it in register Y
-        "TSX" => { todo!(); }, // Copy value in stack register and store it in register X
-        "TXA" => { todo!(); }, // Copy value in register X and store it in register A
-        "TXS" => { todo!(); }, // Copy value in register X and store it in stack register
-        "TYA" => { todo!(); }, // Copy value in register Y and store it in register A
-        _ => {
-          !todo!()
+      if executed >= max_instructions {
+        return Err(CpuError::InstructionLimitExceeded);
+      }
+      executed += 1;
+      if !self.step()? {
+        return Ok(());
+      }
+    }
+  }
+
+  // Step until the program counter reaches target, giving up after max_instructions
+  pub fn run_until_pc(&mut self, target: u16, max_instructions: usize) -> Result<(), &'static str> {
+    for _ in 0..max_instructions {
+      if self.program_counter == target {
+        return Ok(());
+      }
+      match self.step() {
+        Ok(true) => {},
+        Ok(false) => { return Err("hit BRK before reaching target"); },
+        Err(_) => { return Err("CPU error before reaching target"); }
+      }
+    }
+
+    if self.program_counter == target {
+      Ok(())
+    }
+    else {
+      Err("instruction limit reached before target")
+    }
+  }
+
+  // Run a blargg style test ROM until it reports a result in $6000 or timeout_frames frames pass
+  pub fn run_automated_test_rom(&mut self, path: &Path, timeout_frames: u64) -> TestRomResult {
+    let cartridge = match cartridge::load_from_file(path) {
+      Ok(cartridge) => cartridge,
+      Err(err) => {
+        return TestRomResult::Failed {
+          code: TEST_ROM_NOT_STARTED,
+          message: format!("could not load test ROM: {}", err)
+        };
+      }
+    };
+    self.bus.insert_cartridge(cartridge);
+    self.mem_write(TEST_ROM_STATUS, TEST_ROM_NOT_STARTED);
+    self.reset();
+
+    let start_frame = self.bus.ppu.frame_count;
+    while self.bus.ppu.frame_count - start_frame < timeout_frames {
+      let running = match self.step() {
+        Ok(running) => running,
+        Err(err) => {
+          return TestRomResult::Failed {
+            code: self.mem_read(TEST_ROM_STATUS),
+            message: format!("CPU error: {}", err)
+          };
         }
+      };
+
+      let status = self.mem_read(TEST_ROM_STATUS);
+      let done = match status {
+        TEST_ROM_NOT_STARTED | TEST_ROM_RUNNING | TEST_ROM_NEEDS_RESET => false,
+        _ => self.test_rom_signature_valid()
+      };
+      if done {
+        return match status {
+          0x00 => TestRomResult::Passed,
+          code => TestRomResult::Failed { code, message: self.test_rom_message() }
+        };
+      }
+      if !running {
+        break;
+      }
+    }
+
+    TestRomResult::Timeout
+  }
+
+  // Returns true if the test ROM has written its signature (so $6000 holds a real status)
+  fn test_rom_signature_valid(&mut self) -> bool {
+    (0..TEST_ROM_SIGNATURE_BYTES.len()).all(|i| {
+      self.mem_read(TEST_ROM_SIGNATURE + i as u16) == TEST_ROM_SIGNATURE_BYTES[i]
+    })
+  }
+
+  // Read the zero terminated text the test ROM wrote from $6004 onwards
+  fn test_rom_message(&mut self) -> String {
+    let mut message = String::new();
+    let mut addr = TEST_ROM_MESSAGE;
+    while addr < 0x8000 {
+      let byte = self.mem_read(addr);
+      if byte == 0 {
+        break;
       }
+      message.push(byte as char);
+      addr += 1;
+    }
+    message
+  }
+
+  // Attach a logger that sees every instruction before it executes (replacing any previous one)
+  pub fn set_logger(&mut self, logger: Box<dyn CpuLogger>) {
+    self.logger = Some(logger);
+  }
+
+  // Detach the logger, returning it
+  pub fn take_logger(&mut self) -> Option<Box<dyn CpuLogger>> {
+    self.logger.take()
+  }
+
+  fn log_instruction(&mut self, opcode_addr: u16, opcode: u8, byte_count: u8, mnemonic: &'static str, mode: AddressingMode) {
+    let operands = (1..byte_count as u16)
+      .map(|i| self.bus.peek(opcode_addr.wrapping_add(i)))
+      .collect();
+    let entry = TraceEntry {
+      program_counter: opcode_addr,
+      opcode,
+      operands,
+      mnemonic,
+      mode,
+      register_a: self.register_a,
+      register_x: self.register_x,
+      register_y: self.register_y,
+      status: self.status,
+      stack_pointer: self.stack_pointer,
+      cycles: self.cycles,
+    };
+    if let Some(logger) = self.logger.as_mut() {
+      logger.log(&entry);
+    }
+  }
+
+  // CPU cycles since power on, or since the last reset_cycles
+  pub fn total_cycles(&self) -> u64 {
+    self.cycles
+  }
+
+  // Restart the cycle count from zero, to measure the cost of the code that runs next.
+  // Only the counter restarts, the rest of the hardware keeps its own timing
+  pub fn reset_cycles(&mut self) {
+    self.cycles = 0;
+  }
+
+  // Check the cycle count against a reference (nestest.log, another emulator) so timing
+  // regressions show up as an exact difference
+  pub fn validate_timing(&self, expected: u64) -> Result<(), TimingError> {
+    let actual = self.total_cycles();
+    if actual == expected {
+      Ok(())
+    }
+    else {
+      Err(TimingError { expected, actual, diff: actual as i64 - expected as i64 })
+    }
+  }
+
+  pub fn get_state(&self) -> CpuState {
+    CpuState {
+      a: self.register_a,
+      x: self.register_x,
+      y: self.register_y,
+      status: self.status.bits(),
+      sp: self.stack_pointer,
+      pc: self.program_counter,
+      cycles: self.cycles,
+    }
+  }
+
+  // Overwrite the registers and cycle count, leaving memory and the rest of the hardware alone
+  pub fn set_state(&mut self, state: CpuState) {
+    self.register_a = state.a;
+    self.register_x = state.x;
+    self.register_y = state.y;
+    self.status = StatusFlags::from_bits_truncate(state.status);
+    self.stack_pointer = state.sp;
+    self.program_counter = state.pc;
+    self.cycles = state.cycles;
+  }
+
+  // The used part of the stack, from the most recently pushed byte up to $01FF
+  pub fn stack_dump(&self) -> Vec<u8> {
+    (self.stack_pointer as u16 + 1 ..= 0xFF)
+      .map(|offset| self.bus.peek(STACK | offset))
+      .collect()
+  }
+
+  // The 256 bytes of one memory page, page 0 is the zero page and page 1 the stack
+  pub fn copy_page(&self, page: u8) -> [u8; 256] {
+    let base = (page as u16) << 8;
+    let mut bytes = [0x00; 256];
+    for (offset, byte) in bytes.iter_mut().enumerate() {
+      *byte = self.bus.peek(base | offset as u16);
+    }
+    bytes
+  }
+
+  // XOR of the len bytes starting at start (wrapping past $FFFF), for spotting memory changes.
+  // Reads go through Bus::peek so hardware registers aren't disturbed
+  pub fn mem_checksum(&self, start: u16, len: u16) -> u8 {
+    (0..len).fold(0, |checksum, offset| checksum ^ self.bus.peek(start.wrapping_add(offset)))
+  }
+
+  // Check the given flags (None means don't care), panicking with every mismatch and the full status
+  #[cfg(test)]
+  pub fn assert_flags(&self, carry: Option<bool>, zero: Option<bool>, neg: Option<bool>, overflow: Option<bool>) {
+    let mismatches: Vec<String> = [
+      ("CARRY", StatusFlags::CARRY, carry),
+      ("ZERO", StatusFlags::ZERO, zero),
+      ("NEGATIVE", StatusFlags::NEGATIVE, neg),
+      ("OVERFLOW", StatusFlags::OVERFLOW, overflow),
+    ]
+      .iter()
+      .filter_map(|(name, flag, expected)| {
+        let actual = self.status.contains(*flag);
+        match expected {
+          Some(expected) if *expected != actual => Some(format!("{} should be {}", name, if *expected { "set" } else { "clear" })),
+          _ => None
+        }
+      })
+      .collect();
+    assert!(
+      mismatches.is_empty(),
+      "{} (P: ${:02X} {})", mismatches.join(", "), self.status.bits(), format_status(self.status.bits())
+    );
+  }
+
+  // Decode the instruction at the PC and resolve its operand address without executing it.
+  // Memory is read through Bus::peek, so nothing (registers, PC, hardware registers) changes
+  pub fn peek_instruction(&self) -> Result<PeekedInstruction, CpuError> {
+    self.peek_instruction_at(self.program_counter)
+  }
+
+  // Decode the instruction at address the same way, for listing code away from the PC
+  pub fn peek_instruction_at(&self, address: u16) -> Result<PeekedInstruction, CpuError> {
+    let opcode = self.bus.peek(address);
+    let OpCode(mnemonic, byte_count, _, mode) = *OPCODES_MAP
+      .get(&opcode)
+      .ok_or(CpuError::UnknownOpcode(opcode))?;
+    let operands = (1..byte_count as u16)
+      .map(|i| self.bus.peek(address.wrapping_add(i)))
+      .collect();
+    let operand_address = Self::resolve_operand_address(
+      &mode, address.wrapping_add(1), self.register_x, self.register_y, |addr| self.bus.peek(addr)
+    ).ok().map(|(addr, _)| addr);
+
+    Ok(PeekedInstruction { address, opcode, operands, mnemonic, mode, operand_address })
+  }
+
+  // Start counting executed instructions, discarding any earlier stats
+  pub fn enable_profiling(&mut self) {
+    self.profiling = Some(InstructionStats::default());
+  }
+
+  // Stop counting, returning what was collected
+  pub fn disable_profiling(&mut self) -> Option<InstructionStats> {
+    self.profiling.take()
+  }
+
+  // The n most executed opcodes as (mnemonic, count), busiest first
+  pub fn top_instructions(&self, n: usize) -> Vec<(&'static str, u64)> {
+    let stats = match &self.profiling {
+      Some(stats) => stats,
+      None => return vec![]
+    };
+    let mut counts: Vec<(u8, u64)> = stats.counts.iter().map(|(opcode, count)| (*opcode, *count)).collect();
+    counts.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+    counts.iter()
+      .take(n)
+      .map(|(opcode, count)| (OPCODES_MAP[opcode].0, *count))
+      .collect()
+  }
+
+  // Start remembering the addresses of the last depth instructions executed
+  pub fn enable_history(&mut self, depth: usize) {
+    self.history_enabled = true;
+    self.history_depth = depth;
+    self.execution_history.clear();
+  }
+
+  pub fn execution_history(&self) -> &VecDeque<u16> {
+    &self.execution_history
+  }
 
-      if (current_prog_state == self.program_counter) {
-        self.program_counter += (byte_count - 1) as u16;
+  // Returns the most repeated address in the history if it shows up more than depth / 4 times
+  pub fn detect_infinite_loop(&self) -> Option<u16> {
+    let mut counts: HashMap<u16, usize> = HashMap::new();
+    for addr in &self.execution_history {
+      *counts.entry(*addr).or_insert(0) += 1;
+    }
+    counts.into_iter()
+      .filter(|(_, count)| *count > self.history_depth / 4)
+      .max_by(|a, b| a.1.cmp(&b.1).then(b.0.cmp(&a.0)))
+      .map(|(addr, _)| addr)
+  }
+
+  // Execute one instruction like step and report how many cycles it took. The bus has already
+  // advanced the PPU by 3 dots per cycle, so hosts only need the count for their own timing
+  pub fn tick(&mut self) -> Result<StepResult, CpuError> {
+    let start = self.cycles;
+    let running = self.step()?;
+    if self.bus.poll_nmi() {
+      self.request_nmi();
+    }
+
+    Ok(StepResult { cycles: self.cycles - start, nmi: self.nmi_pending, running })
+  }
+
+  // Execute a single instruction (servicing pending interrupts first), returns false once BRK is hit
+  pub fn step(&mut self) -> Result<bool, CpuError> {
+    if self.bus.poll_nmi() {
+      self.request_nmi();
+    }
+
+    // Check for interrupts at the instruction boundary (NMI takes priority over IRQ). The bus IRQ
+    // line is sampled here rather than latched, so a device acknowledged while I was set is forgotten
+    if self.nmi_pending {
+      self.interrupt_nmi();
+    }
+    else if self.irq_pending() && !self.check_status_flag_set("INTERRUPT_DISABLE")? {
+      self.interrupt_irq();
+    }
+
+    let opcode_addr = self.program_counter;
+    if self.history_enabled {
+      self.execution_history.push_back(opcode_addr);
+      while self.execution_history.len() > self.history_depth {
+        self.execution_history.pop_front();
       }
-      if self.status & 0b0000_0100 != 0 {
-        return;
+    }
+    let register = self.mem_read(opcode_addr);
+    self.program_counter = opcode_addr.checked_add(1)
+      .ok_or(CpuError::OutOfBounds(opcode_addr))?;
+    let current_prog_state = self.program_counter;
+
+    let OpCode(
+      name,
+      byte_count,
+      cycle_count,
+      mode
+    ) = *OPCODES_MAP
+      .get(&register)
+      .ok_or(CpuError::UnknownOpcode(register))?;
+    if self.logger.is_some() {
+      self.log_instruction(opcode_addr, register, byte_count, name, mode);
+    }
+
+    // Catch the rest of the hardware up to the instruction's last cycle, which is where its
+    // memory access happens, so PPU register reads/writes see the PPU at the right time
+    self.add_cycles(cycle_count as u16 - 1);
+
+    match name {
+      "ADC" => { self.adc(&mode)?; }, // Add with carry
+      "AND" => { todo!(); }, // Logical AND
+      "ASL" => { todo!(); }, // Arithmetic shift left
+      "BCC" => { todo!(); }, // Branch if carry flag is clear
+      "BCS" => { todo!(); }, // Branch if carry flag is set
+      "BEQ" => { todo!(); }, // Branch if equal
+      "BIT" => { todo!(); }, // Bit test
+      "BMI" => { todo!(); }, // Branch if negative flag is set
+      "BNE" => { todo!(); }, // Branch if not equal
+      "BRK" => { self.brk(); }, // Force interrupt
+      "BVC" => { todo!(); }, // Branch if overflow flag is clear
+      "BVS" => { todo!(); }, // Branch if overflow flag is set
+      "CLC" => { todo!(); }, // Clear carry flag
+      "CLD" => { todo!(); }, // Clear decimal mode flag
+      "CLI" => { todo!(); }, // Clear interrupt disable flag
+      "CLV" => { todo!(); }, // Clear overflow flag
+      "CMP" => { todo!(); }, // Compare value in register A with value in memory location
+      "CPX" => { todo!(); }, // Compare value in register X with value in memory location
+      "CPY" => { todo!(); }, // Compare value in register Y with value in memory location
+      "DEC" => { todo!(); }, // Decrement value in memory location
+      "DEX" => { todo!(); }, // Decrement value in register X
+      "DEY" => { todo!(); }, // Decrement value in register Y
+      "EOR" => { todo!(); }, // Logical XOR
+      "INC" => { self.inc(&mode)?; }, // Increment value in memory location
+      "INX" => { self.inx(); }, // Increment value in register X
+      "INY" => { todo!(); }, // Increment value in register Y
+      "JMP" => { self.jmp(&mode)?; }, // Sets program counter to address specified by operand
+      "JSR" => { todo!(); }, // Jump to subroutine
+      "LDA" => { self.lda(&mode)?; }, // Load value into register A
+      "LDX" => { todo!(); }, // Load value into register X
+      "LDY" => { todo!(); }, // Load value into register Y
+      "LSR" => { todo!(); }, // Logicial shift right
+      "NOP" => { self.nop(&mode)?; }, // No operation to be made
+      "ORA" => { todo!(); }, // Logical OR
+      "PHA" => { todo!(); }, // Push copy of value in register A onto stack
+      "PHP" => { todo!(); }, // Push copy of processor status onto stack
+      "PLA" => { todo!(); }, // Pull 8 bit value from stack and loads it into register A
+      "PLP" => { todo!(); }, // Pull 8 bit value from stack and sets processor status to be said value
+      "ROL" => { todo!(); }, // Shift register A or memory location's value's bits to the left such that the 0th bit is set to be the carry flag's value and then the carry flag's value is set to be the old 7th bit value
+      "ROR" => { todo!(); }, // Same as ROR instruction except shift right (7th bit gets set to carry flag value and carry flag value gets set to old 0tth bit value)
+      "RTI" => { self.rti(); }, // Return from processing routine interrupt, and pull and set processor status flags and program counter from stack
+      "RTS" => { todo!(); }, // Return from end of subroutine to routine that called it and pull and set program counter (minus 1) from stack
+      "SBC" => { self.sbc(&mode)?; }, // Subtract contents of memory location from register A with the NOT of the carry flag (if overflow, clear carry bit)
+      "SEC" => { todo!(); }, // Set carry flag to 1
+      "SED" => { todo!(); }, // Set decimal flag to 1
+      "SEI" => { todo!(); }, // Set interrupt disable flag to 1
+      "STA" => { self.sta(&mode)?; }, // Store register A value in memory location
+      "STX" => { todo!(); }, // Store register X value in memory location
+      "STY" => { todo!(); }, // Store registter Y value in memory location
+      "TAX" => { self.tax(); }, // Copy value in register A and store it in register X
+      "TAY" => { todo!(); }, // Copy value in register A and store it in register Y
+      "TSX" => { todo!(); }, // Copy value in stack register and store it in register X
+      "TXA" => { todo!(); }, // Copy value in register X and store it in register A
+      "TXS" => { todo!(); }, // Copy value in register X and store it in stack register
+      "TYA" => { todo!(); }, // Copy value in register Y and store it in register A
+      "JAM" => {
+        self.program_counter = opcode_addr; // Stuck on the same opcode until reset
+        return Err(CpuError::Jammed);
+      },
+      _ => {
+        return Err(CpuError::UnknownOpcode(register));
       }
     }
+
+    if current_prog_state == self.program_counter {
+      self.program_counter = self.program_counter.checked_add((byte_count - 1) as u16)
+        .ok_or(CpuError::OutOfBounds(opcode_addr))?;
+    }
+    self.add_cycles(1);
+    if self.bus.poll_oam_dma() {
+      // OAM DMA needs an extra alignment cycle when it starts on an odd CPU cycle. The parity
+      // comes from the APU's count, which reset_cycles leaves running
+      let stall = if self.bus.apu.cycles % 2 == 1 { 514 } else { 513 };
+      self.add_cycles(stall);
+    }
+    let dmc_stall = self.bus.poll_dmc_stall();
+    if dmc_stall > 0 {
+      self.add_cycles(dmc_stall);
+    }
+    if let Some(stats) = self.profiling.as_mut() {
+      *stats.counts.entry(register).or_insert(0) += 1;
+      stats.total += 1;
+    }
+    Ok(!self.check_status_flag_set("BREAK")?)
+  }
+}
+
+impl Default for CPU {
+  fn default() -> Self {
+    CPU::new()
+  }
+}
+
+// Register state in the format of a nestest log line's register columns
+impl fmt::Display for CPU {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(
+      f,
+      "A:{:02X} X:{:02X} Y:{:02X} P:{:02X} SP:{:02X} PC:{:04X} CYC:{}",
+      self.register_a,
+      self.register_x,
+      self.register_y,
+      self.status.bits(),
+      self.stack_pointer,
+      self.program_counter,
+      self.cycles
+    )
+  }
+}
+
+// Register state plus the opcode waiting at PC
+impl fmt::Debug for CPU {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    let opcode = self.bus.peek(self.program_counter);
+    let name = OPCODES_MAP.get(&opcode).map_or("???", |op| op.0);
+    write!(f, "CPU {{ {} NEXT:{:02X} {} }}", self, opcode, name)
   }
 }
 
@@ -360,14 +1048,15 @@ impl CPU {
 #[cfg(test)]
 mod test {
   use super::*;
+  use std::convert::TryFrom;
+  use crate::hardware::joypad::Button;
 
   #[test]
   fn test_0xa9_lda_immediate_load_data() {
     let mut cpu = CPU::new();
-    cpu.load_and_run(vec![0xa9, 0x05, 0x00]);
+    cpu.load_and_run(vec![0xa9, 0x05, 0x00]).unwrap();
     assert_eq!(cpu.register_a, 5);
-    assert!(cpu.status & 0b0000_0010 == 0b00);
-    assert!(cpu.status & 0b1000_0000 == 0);
+    cpu.assert_flags(None, Some(false), Some(false), None);
   }
 
   #[test]
@@ -376,7 +1065,7 @@ mod test {
     cpu.load(vec![0xaa, 0x00]);
     cpu.reset();
     cpu.register_a = 10;
-    cpu.run();
+    cpu.run_with_limit(DEFAULT_INSTRUCTION_LIMIT).unwrap();
 
     assert_eq!(cpu.register_x, 10);
   }
@@ -384,7 +1073,19 @@ mod test {
   #[test]
   fn test_5_ops_working_together() {
     let mut cpu = CPU::new();
-    cpu.load_and_run(vec![0xa9, 0xc0, 0xaa, 0xe8, 0x00]);
+    cpu.load_and_run(vec![0xa9, 0xc0, 0xaa, 0xe8, 0x00]).unwrap();
+    assert_eq!(cpu.register_x, 0xc1);
+  }
+
+  #[test]
+  fn test_5_ops_working_together_asm() {
+    let mut cpu = CPU::new();
+    cpu.load_and_run_asm("
+      LDA #$C0
+      TAX
+      INX
+      BRK
+    ").unwrap();
     assert_eq!(cpu.register_x, 0xc1);
   }
 
@@ -394,7 +1095,7 @@ mod test {
     cpu.load(vec![0xe8, 0xe8, 0x00]);
     cpu.reset();
     cpu.register_x = 0xff;
-    cpu.run();
+    cpu.run_with_limit(DEFAULT_INSTRUCTION_LIMIT).unwrap();
 
     assert_eq!(cpu.register_x, 1);
   }
@@ -403,8 +1104,1224 @@ mod test {
   fn test_lda_from_mem() {
     let mut cpu = CPU::new();
     cpu.mem_write(0x10, 0x55);
-    cpu.load_and_run(vec![0xa5, 0x10, 0x00]);
+    cpu.load_and_run(vec![0xa5, 0x10, 0x00]).unwrap();
 
     assert_eq!(cpu.register_a, 0x55);
   }
-}
\ No newline at end of file
+  #[test]
+  fn test_nmi_serviced_before_irq() {
+    let mut cpu = CPU::new();
+    cpu.load(vec![0x00]);
+    // NMI handler: LDA #$01; STA $10; RTI
+    for (i, byte) in [0xa9, 0x01, 0x85, 0x10, 0x40].iter().enumerate() {
+      cpu.mem_write(0x9000 + i as u16, *byte);
+    }
+    // IRQ handler: LDA $10; TAX; BRK
+    for (i, byte) in [0xa5, 0x10, 0xaa, 0x00].iter().enumerate() {
+      cpu.mem_write(0xA000 + i as u16, *byte);
+    }
+    cpu.mem_write_u16(0xFFFA, 0x9000);
+    cpu.mem_write_u16(0xFFFE, 0xA000);
+    cpu.reset();
+    cpu.unset_status_flag("INTERRUPT_DISABLE").unwrap();
+
+    cpu.request_nmi();
+    cpu.request_irq();
+    cpu.run_with_limit(DEFAULT_INSTRUCTION_LIMIT).unwrap();
+
+    // IRQ handler only runs after the NMI handler returned and saw its write
+    assert_eq!(cpu.register_x, 0x01);
+    assert_eq!(cpu.program_counter, 0xA004);
+    assert!(!cpu.nmi_pending);
+    assert!(!cpu.irq_pending());
+  }
+
+  #[test]
+  fn test_nmi_pending_until_serviced() {
+    let mut cpu = CPU::new();
+    cpu.load(vec![0xea, 0x00]);
+    // NMI handler: BRK
+    cpu.mem_write_u16(0xFFFA, 0x9000);
+    cpu.reset();
+    assert!(!cpu.nmi_pending() && !cpu.irq_pending());
+
+    cpu.request_nmi();
+    cpu.request_irq();
+    assert!(cpu.nmi_pending() && cpu.irq_pending());
+
+    // Taking the NMI clears it, the IRQ is masked by the interrupt disable flag
+    cpu.step().unwrap();
+    assert!(!cpu.nmi_pending());
+    assert!(cpu.irq_pending());
+    assert_eq!(cpu.program_counter, 0x9001);
+  }
+
+  #[test]
+  fn test_irq_masked_by_interrupt_disable() {
+    let mut cpu = CPU::new();
+    cpu.load(vec![0xe8, 0x00]);
+    cpu.mem_write_u16(0xFFFE, 0xA000);
+    cpu.reset();
+
+    cpu.request_irq();
+    cpu.run_with_limit(DEFAULT_INSTRUCTION_LIMIT).unwrap();
+
+    assert_eq!(cpu.register_x, 1);
+    assert!(cpu.irq_pending());
+  }
+
+  #[test]
+  fn test_acknowledged_apu_irq_is_not_taken_later() {
+    let mut cpu = CPU::new();
+    cpu.load(assembler::assemble("
+      LDA $4015
+      NOP
+      BRK
+    ", 0x8000).unwrap());
+    cpu.mem_write_u16(0xFFFE, 0xA000);
+    cpu.reset();
+
+    // Run the APU until the frame counter raises its IRQ, with the I flag still set from reset
+    while !cpu.bus.irq_line() {
+      cpu.bus.tick(1000);
+    }
+    assert!(cpu.irq_pending());
+
+    // Reading $4015 acknowledges it while masked
+    cpu.step().unwrap();
+    assert!(!cpu.bus.irq_line());
+    assert!(!cpu.irq_pending());
+
+    // Clearing I (CLI isn't implemented yet) doesn't let a stale IRQ through
+    cpu.unset_status_flag("INTERRUPT_DISABLE").unwrap();
+    cpu.step().unwrap();
+    assert_eq!(cpu.program_counter, 0x8004);
+  }
+  #[test]
+  fn test_vblank_nmi_handler_increments_counter() {
+    let mut cpu = CPU::new();
+    // LDA #$80; STA $2000 (enable vblank NMI); loop: JMP loop
+    cpu.load(vec![0xa9, 0x80, 0x8d, 0x00, 0x20, 0x4c, 0x05, 0x80]);
+    // NMI handler: INC $10; BRK
+    for (i, byte) in [0xe6, 0x10, 0x00].iter().enumerate() {
+      cpu.mem_write(0x9000 + i as u16, *byte);
+    }
+    cpu.mem_write_u16(0xFFFA, 0x9000);
+    cpu.reset();
+    cpu.run_with_limit(DEFAULT_INSTRUCTION_LIMIT).unwrap();
+
+    assert_eq!(cpu.mem_read(0x10), 1);
+    assert_eq!(cpu.bus.ppu.scanline, 241);
+    // Vblank starts 241 * 341 + 1 dots in, the NMI is taken at the next instruction boundary
+    // (at most one JMP later) and the handler costs NMI (7) + INC (5) + BRK (7) cycles
+    let vblank_cycle = (241 * 341 + 1) / 3;
+    assert!(cpu.total_cycles() > vblank_cycle && cpu.total_cycles() <= vblank_cycle + 3 + 7 + 5 + 7);
+  }
+
+  #[test]
+  fn test_oam_dma_stall_depends_on_cycle_parity() {
+    // Reset (7 cycles); LDA #$02 (2 cycles); STA $4014 (4 cycles); BRK -> DMA starts on odd cycle 13
+    let mut cpu = CPU::new();
+    cpu.load_and_run(vec![0xa9, 0x02, 0x8d, 0x14, 0x40, 0x00]).unwrap();
+    assert_eq!(cpu.total_cycles(), 13 + 514 + 7);
+
+    // Reset (7 cycles); LDA $10 (3 cycles); STA $4014 (4 cycles); BRK -> DMA starts on even cycle 14
+    let mut cpu = CPU::new();
+    cpu.mem_write(0x10, 0x02);
+    cpu.load_and_run(vec![0xa5, 0x10, 0x8d, 0x14, 0x40, 0x00]).unwrap();
+    assert_eq!(cpu.total_cycles(), 14 + 513 + 7);
+  }
+
+  #[test]
+  fn test_reset_cycles_keeps_oam_dma_parity() {
+    // Reset (7 cycles); LDA #$02 (2 cycles), then the counter restarts; STA $4014 (4 cycles); BRK.
+    // The DMA still starts on odd cycle 13, so it takes 514 cycles
+    let mut cpu = CPU::new();
+    cpu.load(vec![0xa9, 0x02, 0x8d, 0x14, 0x40, 0x00]);
+    cpu.reset();
+    cpu.step().unwrap();
+    cpu.reset_cycles();
+    cpu.run().unwrap();
+    assert_eq!(cpu.total_cycles(), 4 + 514 + 7);
+  }
+
+  // Build a 16KB NROM test ROM that reports the given status code and message the way blargg's ROMs do
+  fn create_test_rom(result: Option<(u8, &str)>) -> tempfile::NamedTempFile {
+    let mut program = vec![];
+    // LDA #$80; STA $6000 (test running)
+    program.extend([0xa9, 0x80, 0x8d, 0x00, 0x60]);
+    for (i, byte) in TEST_ROM_SIGNATURE_BYTES.iter().enumerate() {
+      program.extend([0xa9, *byte, 0x8d, 0x01 + i as u8, 0x60]);
+    }
+    if let Some((code, message)) = result {
+      for (i, byte) in message.bytes().chain(std::iter::once(0)).enumerate() {
+        program.extend([0xa9, byte, 0x8d, 0x04 + i as u8, 0x60]);
+      }
+      program.extend([0xa9, code, 0x8d, 0x00, 0x60]);
+    }
+    // loop: JMP loop
+    let loop_addr = 0x8000 + program.len() as u16;
+    program.extend([0x4c, (loop_addr & 0xff) as u8, (loop_addr >> 8) as u8]);
+
+    let mut prg_rom = vec![0x00; 0x4000];
+    prg_rom[.. program.len()].copy_from_slice(&program);
+    prg_rom[0x3FFC] = 0x00;
+    prg_rom[0x3FFD] = 0x80;
+
+    let mut raw = vec![0x4E, 0x45, 0x53, 0x1A, 0x01, 0x00];
+    raw.resize(16, 0x00);
+    raw.extend(prg_rom);
+
+    let mut file = tempfile::NamedTempFile::new().unwrap();
+    std::io::Write::write_all(&mut file, &raw).unwrap();
+    file
+  }
+
+  #[test]
+  fn test_automated_test_rom_passed() {
+    let rom = create_test_rom(Some((0x00, "Passed")));
+    let mut cpu = CPU::new();
+
+    assert_eq!(cpu.run_automated_test_rom(rom.path(), 10), TestRomResult::Passed);
+  }
+
+  #[test]
+  fn test_automated_test_rom_failed() {
+    let rom = create_test_rom(Some((0x03, "ADC #3")));
+    let mut cpu = CPU::new();
+
+    assert_eq!(
+      cpu.run_automated_test_rom(rom.path(), 10),
+      TestRomResult::Failed { code: 0x03, message: String::from("ADC #3") }
+    );
+  }
+
+  #[test]
+  fn test_automated_test_rom_timeout() {
+    let rom = create_test_rom(None);
+    let mut cpu = CPU::new();
+
+    assert_eq!(cpu.run_automated_test_rom(rom.path(), 2), TestRomResult::Timeout);
+    assert_eq!(cpu.bus.ppu.frame_count, 2);
+  }
+
+  #[test]
+  fn test_one_vblank_per_frame_of_cycles() {
+    let mut cpu = CPU::new();
+    // LDA #$80; STA $2000 (enable vblank NMI); loop: JMP loop
+    cpu.load(vec![0xa9, 0x80, 0x8d, 0x00, 0x20, 0x4c, 0x05, 0x80]);
+    // NMI handler: INC $10; RTI
+    for (i, byte) in [0xe6, 0x10, 0x40].iter().enumerate() {
+      cpu.mem_write(0x9000 + i as u16, *byte);
+    }
+    cpu.mem_write_u16(0xFFFA, 0x9000);
+    cpu.reset();
+
+    // One NTSC frame is 341 * 262 / 3 ~= 29780.67 CPU cycles
+    while cpu.total_cycles() < 29781 {
+      cpu.step().unwrap();
+    }
+
+    assert_eq!(cpu.mem_read(0x10), 1);
+    assert_eq!(cpu.bus.ppu.frame_count, 1);
+  }
+
+  #[test]
+  fn test_ppustatus_read_sees_current_ppu_time() {
+    // LDA $2002; BRK
+    let program = vec![0xad, 0x02, 0x20, 0x00];
+
+    // Mid-frame the vblank flag reads clear
+    let mut cpu = CPU::new();
+    cpu.load(program.clone());
+    cpu.reset();
+    cpu.bus.ppu.tick(100 * 341);
+    cpu.run_with_limit(DEFAULT_INSTRUCTION_LIMIT).unwrap();
+    assert_eq!(cpu.register_a & 0b1000_0000, 0);
+
+    // Vblank starts 6 dots into the LDA (its read happens on the 4th cycle, 9 dots in)
+    let mut cpu = CPU::new();
+    cpu.load(program);
+    cpu.reset();
+    cpu.bus.ppu.tick(241 * 341 - 6);
+    cpu.run_with_limit(DEFAULT_INSTRUCTION_LIMIT).unwrap();
+    assert_eq!(cpu.register_a & 0b1000_0000, 0b1000_0000);
+  }
+
+  #[test]
+  fn test_typed_status_matches_raw_bits() {
+    let mut cpu = CPU::new();
+    assert_eq!(cpu.status.bits(), 0b0010_0100);
+    assert!(cpu.status.contains(StatusFlags::INTERRUPT_DISABLE | StatusFlags::BREAK2));
+
+    cpu.load_and_run(vec![0xa9, 0x00, 0x00]).unwrap(); // LDA #$00; BRK
+    assert!(cpu.status.contains(StatusFlags::ZERO));
+    assert_eq!(cpu.status.bits() & 0b0000_0010, 0b0000_0010);
+    assert!(!cpu.status.contains(StatusFlags::NEGATIVE));
+    assert_eq!(cpu.status.bits() & 0b1000_0000, 0);
+
+    cpu.status = StatusFlags::from(0b1100_0011);
+    assert_eq!(cpu.status, StatusFlags::NEGATIVE | StatusFlags::OVERFLOW | StatusFlags::ZERO | StatusFlags::CARRY);
+    assert_eq!(u8::from(cpu.status), 0b1100_0011);
+  }
+
+  #[test]
+  fn test_status_pushed_as_single_byte() {
+    let mut cpu = CPU::new();
+    cpu.load(vec![0x00]);
+    cpu.mem_write_u16(0xFFFA, 0x8000);
+    cpu.reset();
+    cpu.status.insert(StatusFlags::CARRY | StatusFlags::NEGATIVE);
+
+    cpu.interrupt_nmi();
+
+    // NMI pushes status with B clear and bit 5 set
+    assert_eq!(cpu.mem_read(0x01FB), 0b1010_0101);
+  }
+
+  #[test]
+  fn test_joypad_strobe_and_read_loop() {
+    let mut cpu = CPU::new();
+    cpu.bus.joypad1.set_button(Button::A, true);
+    cpu.bus.joypad1.set_button(Button::Select, true);
+    cpu.bus.joypad1.set_button(Button::Left, true);
+
+    // Strobe, then read all eight buttons (and one more) into $00-$08
+    let mut source = String::from("LDA #$01\n STA $4016\n LDA #$00\n STA $4016\n");
+    for i in 0..9 {
+      source += &format!("LDA $4016\n STA ${:02X}\n", i);
+    }
+    cpu.load_and_run_asm(&source).unwrap();
+
+    let bits: Vec<u8> = (0..9).map(|addr| cpu.mem_read(addr) & 1).collect();
+    assert_eq!(bits, vec![1, 0, 1, 0, 0, 0, 1, 0, 1]);
+    // The upper bits are open bus, left over from the $40 high byte of the operand
+    assert_eq!(cpu.mem_read(0x00), 0x41);
+  }
+
+  #[test]
+  fn test_run_until_pc() {
+    let mut cpu = CPU::new();
+    let program = assembler::assemble("
+      LDA #$05
+      STA $10
+    loop:
+      INC $10
+      INC $11
+      JMP loop
+    ", 0x8000).unwrap();
+    cpu.load(program);
+    cpu.reset();
+
+    // Stops on reaching the JMP at $8008 the first time around
+    assert_eq!(cpu.run_until_pc(0x8008, 100), Ok(()));
+    assert_eq!(cpu.mem_read(0x10), 0x06);
+    assert_eq!(cpu.mem_read(0x11), 0x01);
+
+    // Already at the target, so nothing runs
+    assert_eq!(cpu.run_until_pc(0x8008, 100), Ok(()));
+    assert_eq!(cpu.mem_read(0x11), 0x01);
+  }
+
+  #[test]
+  fn test_run_until_pc_errors() {
+    let mut cpu = CPU::new();
+    cpu.load(assembler::assemble("loop: JMP loop", 0x8000).unwrap());
+    cpu.reset();
+    assert_eq!(cpu.run_until_pc(0x9000, 50), Err("instruction limit reached before target"));
+
+    let mut cpu = CPU::new();
+    cpu.load(vec![0xe8, 0x00]);
+    cpu.reset();
+    assert_eq!(cpu.run_until_pc(0x9000, 50), Err("hit BRK before reaching target"));
+  }
+
+  // Run a single ADC #value with the given accumulator and carry
+  fn run_adc(a: u8, value: u8, carry: bool) -> CPU {
+    let mut cpu = CPU::new();
+    cpu.load(vec![0x69, value, 0x00]);
+    cpu.reset();
+    cpu.register_a = a;
+    cpu.status.set(StatusFlags::CARRY, carry);
+    cpu.run_with_limit(DEFAULT_INSTRUCTION_LIMIT).unwrap();
+    cpu
+  }
+
+  #[test]
+  fn test_adc_positive_plus_positive_overflows() {
+    let cpu = run_adc(0x50, 0x50, false);
+    assert_eq!(cpu.register_a, 0xA0);
+    cpu.assert_flags(Some(false), Some(false), Some(true), Some(true));
+  }
+
+  #[test]
+  fn test_adc_negative_plus_negative_overflows() {
+    let cpu = run_adc(0xD0, 0x90, false);
+    assert_eq!(cpu.register_a, 0x60);
+    cpu.assert_flags(Some(true), Some(false), Some(false), Some(true));
+  }
+
+  #[test]
+  fn test_adc_mixed_signs_never_overflow() {
+    let cpu = run_adc(0x50, 0xD0, false);
+    assert_eq!(cpu.register_a, 0x20);
+    cpu.assert_flags(Some(true), Some(false), Some(false), Some(false));
+
+    let cpu = run_adc(0x50, 0x90, false);
+    assert_eq!(cpu.register_a, 0xE0);
+    cpu.assert_flags(Some(false), Some(false), Some(true), Some(false));
+  }
+
+  #[test]
+  fn test_adc_carry_in_and_zero() {
+    let cpu = run_adc(0x01, 0x01, true);
+    assert_eq!(cpu.register_a, 0x03);
+    cpu.assert_flags(Some(false), Some(false), Some(false), Some(false));
+
+    let cpu = run_adc(0xFF, 0x00, true);
+    assert_eq!(cpu.register_a, 0x00);
+    cpu.assert_flags(Some(true), Some(true), Some(false), Some(false));
+
+    // Carry in tips $7F over into overflow
+    let cpu = run_adc(0x7F, 0x00, true);
+    assert_eq!(cpu.register_a, 0x80);
+    cpu.assert_flags(Some(false), Some(false), Some(true), Some(true));
+  }
+
+  #[test]
+  fn test_adc_multi_byte_carry_propagation() {
+    // $12FF + $01F0 = $14EF, carry from the low byte feeds the high byte
+    let mut cpu = CPU::new();
+    cpu.load_and_run_asm("
+      LDA #$FF
+      ADC #$F0
+      STA $10
+      LDA #$12
+      ADC #$01
+      STA $11
+      BRK
+    ").unwrap();
+
+    assert_eq!(cpu.mem_read(0x10), 0xEF);
+    assert_eq!(cpu.mem_read(0x11), 0x14);
+    cpu.assert_flags(Some(false), None, None, None);
+  }
+
+  #[test]
+  fn test_load_image_keeps_its_reset_vector() {
+    let mut image = vec![0x00; 0x4000];
+    image[0x0000] = 0xe8; // INX at $C000
+    image[0x3FFC] = 0x00; // Reset vector -> $C000
+    image[0x3FFD] = 0xC0;
+
+    let mut cpu = CPU::new();
+    cpu.load_image(0xC000, &image);
+    cpu.reset();
+    assert_eq!(cpu.program_counter, 0xC000);
+
+    cpu.run_with_limit(DEFAULT_INSTRUCTION_LIMIT).unwrap();
+    assert_eq!(cpu.register_x, 1);
+  }
+
+  #[test]
+  fn test_unknown_opcode_error() {
+    let mut cpu = CPU::new();
+    assert_eq!(cpu.load_and_run(vec![0xe8, 0xff]), Err(CpuError::UnknownOpcode(0xff)));
+    assert_eq!(cpu.register_x, 1);
+
+    assert!(matches!(OpCode::try_from(0xff), Err(CpuError::UnknownOpcode(0xff))));
+  }
+
+  #[test]
+  fn test_unknown_flag_error() {
+    let mut cpu = CPU::new();
+    assert_eq!(cpu.set_status_flag("SPARKLE"), Err(CpuError::UnknownFlag("SPARKLE")));
+    assert_eq!(cpu.check_status_flag_set("CARRY"), Ok(false));
+  }
+
+  #[test]
+  fn test_unsupported_mode_error() {
+    let mut cpu = CPU::new();
+    assert_eq!(
+      cpu.get_operand_address(&AddressingMode::NoneAddressing),
+      Err(CpuError::UnsupportedMode(AddressingMode::NoneAddressing))
+    );
+  }
+
+  #[test]
+  fn test_out_of_bounds_error() {
+    let mut cpu = CPU::new();
+    cpu.mem_write(0xFFFF, 0xe8);
+    cpu.program_counter = 0xFFFF;
+
+    assert_eq!(cpu.step(), Err(CpuError::OutOfBounds(0xFFFF)));
+  }
+
+  #[test]
+  fn test_out_of_bounds_operand_error() {
+    let mut cpu = CPU::new();
+    cpu.mem_write(0xFFFE, 0xa9);
+    cpu.mem_write(0xFFFF, 0x42);
+    cpu.program_counter = 0xFFFE;
+
+    assert_eq!(cpu.step(), Err(CpuError::OutOfBounds(0xFFFE)));
+  }
+
+  #[test]
+  fn test_jammed_error() {
+    let mut cpu = CPU::new();
+    assert_eq!(cpu.load_and_run(vec![0xe8, 0x02, 0xe8]), Err(CpuError::Jammed));
+    assert_eq!(cpu.register_x, 1);
+    assert_eq!(cpu.program_counter, 0x8001);
+  }
+
+  #[test]
+  fn test_mapped_io_handler_sees_writes() {
+    use super::super::bus::IoHandler;
+    use std::cell::Cell;
+    use std::rc::Rc;
+
+    struct WriteCounter(Rc<Cell<u32>>);
+    impl IoHandler for WriteCounter {
+      fn read(&mut self, _addr: u16) -> u8 { 0x42 }
+      fn write(&mut self, _addr: u16, _data: u8) { self.0.set(self.0.get() + 1); }
+    }
+
+    let writes = Rc::new(Cell::new(0));
+    let mut cpu = CPU::new();
+    cpu.bus.map_io(0x5000..0x5010, Box::new(WriteCounter(writes.clone())));
+    cpu.load_and_run_asm("
+      LDA #$07
+      STA $5000
+      STA $500F
+      STA $5010
+      LDA $5008
+      BRK
+    ").unwrap();
+
+    assert_eq!(writes.get(), 2);
+    assert_eq!(cpu.register_a, 0x42);
+    assert_eq!(cpu.mem_read(0x5010), 0x07); // Outside the range falls back to memory
+  }
+
+  #[test]
+  fn test_fill_pattern_before_program_runs() {
+    let mut cpu = CPU::with_fill_pattern(FillPattern::All(0xFF));
+    cpu.load(assembler::assemble("LDA $0123\nBRK", 0x8000).unwrap());
+    cpu.reset();
+
+    assert!((0..0x0800).all(|addr| cpu.mem_read(addr) == 0xFF));
+    cpu.run_with_limit(DEFAULT_INSTRUCTION_LIMIT).unwrap();
+    assert_eq!(cpu.register_a, 0xFF);
+
+    // The default stays all zero
+    let mut cpu = CPU::new();
+    assert!((0..0x0800).all(|addr| cpu.mem_read(addr) == 0x00));
+  }
+
+  #[test]
+  fn test_tick_reports_cycles() {
+    let mut cpu = CPU::new();
+    cpu.load(assembler::assemble("
+      LDA #$01
+      LDA $1234
+      INC $1234,X
+      BRK
+    ", 0x8000).unwrap());
+    cpu.reset();
+
+    assert_eq!(cpu.tick().unwrap(), StepResult { cycles: 2, nmi: false, running: true });
+    assert_eq!(cpu.tick().unwrap().cycles, 4);
+    assert_eq!(cpu.tick().unwrap().cycles, 7);
+    assert_eq!(cpu.bus.ppu.cycles, (7 + 13) * 3); // Reset, then the three instructions
+    assert!(!cpu.tick().unwrap().running);
+  }
+
+  #[test]
+  fn test_tick_reports_pending_nmi() {
+    let mut cpu = CPU::new();
+    cpu.load(vec![0x4c, 0x00, 0x80]); // JMP $8000
+    cpu.mem_write_u16(0xFFFA, 0x9000);
+    cpu.mem_write(0x9000, 0xe8); // INX
+    cpu.reset();
+    cpu.bus.mem_write(0x2000, 0x80);
+
+    let mut result = cpu.tick().unwrap();
+    while !result.nmi {
+      result = cpu.tick().unwrap();
+    }
+    assert_eq!(cpu.bus.ppu.scanline, 241);
+
+    // The NMI is taken on the next tick, which costs 7 cycles on top of the handler's INX
+    assert_eq!(cpu.tick().unwrap().cycles, 7 + 2);
+    assert_eq!(cpu.program_counter, 0x9000 + 1);
+  }
+
+  // Run a single SBC #value with the given accumulator and carry
+  fn run_sbc(a: u8, value: u8, carry: bool) -> CPU {
+    let mut cpu = CPU::new();
+    cpu.load(vec![0xe9, value, 0x00]);
+    cpu.reset();
+    cpu.register_a = a;
+    cpu.status.set(StatusFlags::CARRY, carry);
+    cpu.run_with_limit(DEFAULT_INSTRUCTION_LIMIT).unwrap();
+    cpu
+  }
+
+  #[test]
+  fn test_sbc_borrow_across_zero() {
+    let cpu = run_sbc(0x00, 0x01, true);
+    assert_eq!(cpu.register_a, 0xFF);
+    cpu.assert_flags(Some(false), Some(false), Some(true), Some(false));
+  }
+
+  #[test]
+  fn test_sbc_without_borrow() {
+    let cpu = run_sbc(0x05, 0x05, true);
+    assert_eq!(cpu.register_a, 0x00);
+    cpu.assert_flags(Some(true), Some(true), Some(false), Some(false));
+
+    // Clear carry borrows one more
+    let cpu = run_sbc(0x05, 0x03, false);
+    assert_eq!(cpu.register_a, 0x01);
+    cpu.assert_flags(Some(true), Some(false), Some(false), Some(false));
+  }
+
+  #[test]
+  fn test_sbc_overflow() {
+    // 80 - (-16) = 96, fine
+    let cpu = run_sbc(0x50, 0xF0, true);
+    assert_eq!(cpu.register_a, 0x60);
+    cpu.assert_flags(Some(false), Some(false), Some(false), Some(false));
+
+    // 80 - (-80) = 160 doesn't fit in a signed byte
+    let cpu = run_sbc(0x50, 0xB0, true);
+    assert_eq!(cpu.register_a, 0xA0);
+    cpu.assert_flags(Some(false), Some(false), Some(true), Some(true));
+
+    // -128 - 1 wraps to +127
+    let cpu = run_sbc(0x80, 0x01, true);
+    assert_eq!(cpu.register_a, 0x7F);
+    cpu.assert_flags(Some(true), Some(false), Some(false), Some(true));
+  }
+
+  #[test]
+  fn test_sbc_ignores_decimal_mode() {
+    let mut cpu = CPU::new();
+    cpu.load(vec![0xe9, 0x01, 0x00]);
+    cpu.reset();
+    cpu.register_a = 0x10;
+    cpu.status.insert(StatusFlags::CARRY | StatusFlags::DECIMAL_MODE);
+    cpu.run_with_limit(DEFAULT_INSTRUCTION_LIMIT).unwrap();
+
+    assert_eq!(cpu.register_a, 0x0F); // Binary result, 0x09 in BCD
+  }
+
+  #[test]
+  fn test_logger_sees_each_instruction() {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    struct VecLogger(Rc<RefCell<Vec<TraceEntry>>>);
+    impl CpuLogger for VecLogger {
+      fn log(&mut self, entry: &TraceEntry) { self.0.borrow_mut().push(entry.clone()); }
+    }
+
+    let entries = Rc::new(RefCell::new(vec![]));
+    let mut cpu = CPU::new();
+    cpu.set_logger(Box::new(VecLogger(entries.clone())));
+    cpu.load_and_run_asm("
+      LDA #$C0
+      STA $1234
+      TAX
+      BRK
+    ").unwrap();
+
+    let entries = entries.borrow();
+    let mnemonics: Vec<_> = entries.iter().map(|entry| entry.mnemonic).collect();
+    assert_eq!(mnemonics, vec!["LDA", "STA", "TAX", "BRK"]);
+
+    assert_eq!(entries[1].program_counter, 0x8002);
+    assert_eq!(entries[1].opcode, 0x8d);
+    assert_eq!(entries[1].operands, vec![0x34, 0x12]);
+    assert_eq!(entries[1].mode, AddressingMode::Absolute);
+    assert_eq!(entries[1].register_a, 0xC0); // State before the instruction runs
+    assert_eq!(entries[2].register_x, 0x00);
+    assert_eq!(entries[1].cycles, 7 + 2);
+
+    assert!(cpu.take_logger().is_some());
+    assert!(cpu.take_logger().is_none());
+  }
+
+  #[test]
+  fn test_profiling_counts_instructions() {
+    let mut cpu = CPU::new();
+    // No conditional branches yet, so the loop bounces between two JMPs
+    cpu.load(assembler::assemble("
+      start:
+        INX
+        JMP middle
+      middle:
+        INC $10
+        JMP start
+    ", 0x8000).unwrap());
+    cpu.reset();
+    assert!(cpu.top_instructions(3).is_empty());
+
+    cpu.enable_profiling();
+    for _ in 0..400 {
+      cpu.step().unwrap();
+    }
+
+    assert_eq!(cpu.top_instructions(2), vec![("JMP", 200), ("INC", 100)]);
+    assert_eq!(cpu.top_instructions(10).len(), 3);
+
+    let stats = cpu.disable_profiling().unwrap();
+    assert_eq!(stats.total, 400);
+    assert_eq!(stats.counts[&0xE8], 100); // INX
+    assert_eq!(stats.counts[&0x4C], 200);
+    assert!(cpu.profiling.is_none());
+
+    cpu.step().unwrap();
+    assert!(cpu.disable_profiling().is_none());
+  }
+
+  #[test]
+  fn test_indexed_store_cycles_ignore_page_cross() {
+    let mut cpu = CPU::new();
+    cpu.load(assembler::assemble("
+      LDA $1210,X
+      LDA $12F0,X
+      STA $1210,X
+      STA $12F0,X
+      LDA $1210,Y
+      STA $12F0,Y
+      LDA ($20),Y
+      LDA ($22),Y
+      STA ($20),Y
+      STA ($22),Y
+      BRK
+    ", 0x8000).unwrap());
+    cpu.reset();
+    cpu.mem_write_u16(0x0020, 0x0300);
+    cpu.mem_write_u16(0x0022, 0x03F0);
+    cpu.mem_write(0x0410, 0x5A);
+    cpu.register_x = 0x20;
+    cpu.register_y = 0x20;
+
+    // Loads only pay for the page cross when it happens
+    assert_eq!(cpu.tick().unwrap().cycles, 4);
+    assert_eq!(cpu.tick().unwrap().cycles, 5);
+    // Stores always take the longer path
+    assert_eq!(cpu.tick().unwrap().cycles, 5);
+    assert_eq!(cpu.tick().unwrap().cycles, 5);
+    assert_eq!(cpu.tick().unwrap().cycles, 4);
+    assert_eq!(cpu.tick().unwrap().cycles, 5);
+    assert_eq!(cpu.tick().unwrap().cycles, 5);
+    assert_eq!(cpu.tick().unwrap().cycles, 6);
+    assert_eq!(cpu.tick().unwrap().cycles, 6);
+    assert_eq!(cpu.tick().unwrap().cycles, 6);
+    assert_eq!(cpu.mem_read(0x0320), 0x5A); // Loaded through ($22),Y, stored through ($20),Y
+  }
+
+  #[test]
+  fn test_execution_history_detects_loop() {
+    let mut cpu = CPU::new();
+    cpu.load(assembler::assemble("
+      INX
+      INX
+      spin:
+        JMP spin
+    ", 0x8000).unwrap());
+    cpu.reset();
+    cpu.enable_history(16);
+
+    for _ in 0..2 + 20 {
+      cpu.step().unwrap();
+    }
+
+    assert_eq!(cpu.execution_history().len(), 16);
+    assert!(cpu.execution_history().iter().all(|addr| *addr == 0x8002));
+    assert_eq!(cpu.detect_infinite_loop(), Some(0x8002));
+  }
+
+  #[test]
+  fn test_execution_history_without_loop() {
+    let mut cpu = CPU::new();
+    cpu.load(assembler::assemble("
+      INX
+      INX
+      LDA #$01
+      STA $10
+      INC $10
+      BRK
+    ", 0x8000).unwrap());
+    cpu.reset();
+    assert_eq!(cpu.detect_infinite_loop(), None);
+
+    cpu.enable_history(8);
+    cpu.run_with_limit(DEFAULT_INSTRUCTION_LIMIT).unwrap();
+
+    assert_eq!(cpu.execution_history(), &VecDeque::from(vec![0x8000, 0x8001, 0x8002, 0x8004, 0x8006, 0x8008]));
+    assert_eq!(cpu.detect_infinite_loop(), None);
+  }
+
+  #[test]
+  fn test_new_running_at() {
+    let mut cpu = CPU::new_running_at(0x0600);
+    cpu.load_image(0x0600, &assembler::assemble("
+      LDA #$07
+      STA $10
+      BRK
+    ", 0x0600).unwrap());
+
+    assert_eq!(cpu.step(), Ok(true));
+    assert_eq!(cpu.register_a, 0x07);
+    assert_eq!(cpu.program_counter, 0x0602);
+
+    cpu.run_with_limit(DEFAULT_INSTRUCTION_LIMIT).unwrap();
+    assert_eq!(cpu.mem_read(0x10), 0x07);
+    assert_eq!(cpu.mem_read_u16(0xFFFC), 0x0000); // The reset vector was never written
+  }
+
+  #[test]
+  fn test_absolute_indexed_wraps_around_top_of_memory() {
+    let mut cpu = CPU::new();
+    cpu.load(assembler::assemble("
+      LDA $FFF0,X
+      LDA $FFFF,Y
+      BRK
+    ", 0x8000).unwrap());
+    cpu.reset();
+    cpu.mem_write(0x0010, 0x11);
+    cpu.mem_write(0x0000, 0x22);
+    cpu.register_x = 0x20;
+    cpu.register_y = 0x01;
+
+    // Operand bytes follow the opcode, which is where get_operand_address expects the PC
+    cpu.program_counter = 0x8001;
+    assert_eq!(cpu.get_operand_address_page_cross(&AddressingMode::Absolute_X), Ok((0x0010, true)));
+    cpu.program_counter = 0x8004;
+    assert_eq!(cpu.get_operand_address_page_cross(&AddressingMode::Absolute_Y), Ok((0x0000, true)));
+
+    // Wrapping from $FFxx into $00xx is a page cross, so the loads take the extra cycle
+    cpu.program_counter = 0x8000;
+    assert_eq!(cpu.tick().unwrap().cycles, 5);
+    assert_eq!(cpu.register_a, 0x11);
+    assert_eq!(cpu.tick().unwrap().cycles, 5);
+    assert_eq!(cpu.register_a, 0x22);
+  }
+
+  #[test]
+  fn test_page_crossed() {
+    assert!(!page_crossed(0x0200, 0x02FF));
+    assert!(!page_crossed(0x80F0, 0x80F0));
+    assert!(page_crossed(0x02FF, 0x0300));
+    assert!(page_crossed(0x0300, 0x02FF));
+    assert!(page_crossed(0xFFFF, 0x0000));
+  }
+
+  #[test]
+  fn test_zero_page_x_wraps_within_zero_page() {
+    let mut cpu = CPU::new();
+    cpu.load(assembler::assemble("
+      LDA $FE,X
+      STA $FF,X
+      BRK
+    ", 0x8000).unwrap());
+    cpu.reset();
+    cpu.mem_write(0x0001, 0x42);
+    cpu.mem_write(0x0101, 0x99); // Where $FE + 3 would land without the wrap
+    cpu.register_x = 0x03;
+
+    cpu.step().unwrap();
+    assert_eq!(cpu.register_a, 0x42);
+    cpu.step().unwrap();
+    assert_eq!(cpu.mem_read(0x0002), 0x42);
+    assert_eq!(cpu.mem_read(0x0102), 0x00);
+  }
+
+  #[test]
+  fn test_zero_page_y_wraps_within_zero_page() {
+    // No implemented instruction uses zero page,Y yet (LDX/STX), so resolve the address directly
+    let mut cpu = CPU::new();
+    cpu.load(vec![0xFE]);
+    cpu.reset();
+    cpu.register_y = 0x03;
+
+    assert_eq!(cpu.get_operand_address_page_cross(&AddressingMode::ZeroPage_Y), Ok((0x0001, false)));
+  }
+
+  #[test]
+  fn test_indirect_x_wraps_within_zero_page() {
+    let mut cpu = CPU::new();
+    cpu.load(assembler::assemble("
+      LDA ($FF,X)
+      STA $10
+      LDA ($FF,X)
+      BRK
+    ", 0x8000).unwrap());
+    cpu.reset();
+    // $FF + 1 wraps to pointer $00, so lo comes from $0000 and hi from $0001
+    cpu.mem_write_u16(0x0000, 0x0300);
+    cpu.mem_write_u16(0x0100, 0x0400); // Where the pointer would be without the wrap
+    cpu.mem_write(0x0300, 0x77);
+    cpu.register_x = 0x01;
+
+    cpu.step().unwrap();
+    assert_eq!(cpu.register_a, 0x77);
+
+    // With X = 0 the pointer itself sits at $FF, and its high byte wraps around to $0000
+    cpu.step().unwrap();
+    cpu.mem_write(0x00FF, 0x00);
+    cpu.mem_write(0x0000, 0x05);
+    cpu.mem_write(0x0500, 0x55);
+    cpu.register_x = 0x00;
+    cpu.step().unwrap();
+    assert_eq!(cpu.register_a, 0x55);
+  }
+
+  #[test]
+  fn test_stack_dump() {
+    let mut cpu = CPU::new();
+    cpu.stack_pointer = 0xFF;
+    assert!(cpu.stack_dump().is_empty());
+
+    cpu.stack_push(0xAA);
+    cpu.stack_push(0xBB);
+    assert_eq!(cpu.stack_dump(), vec![0xBB, 0xAA]);
+
+    cpu.stack_pop();
+    assert_eq!(cpu.stack_dump(), vec![0xAA]);
+  }
+
+  #[test]
+  fn test_peek_instruction() {
+    let mut cpu = CPU::new();
+    cpu.load(assembler::assemble("
+      LDA #$01
+      LDA $0200,X
+      BRK
+    ", 0x8000).unwrap());
+    cpu.reset();
+    cpu.step().unwrap();
+    cpu.register_x = 0x05;
+
+    let before = cpu.get_state();
+    let peeked = cpu.peek_instruction().unwrap();
+    assert_eq!(cpu.get_state(), before);
+    assert_eq!(peeked, PeekedInstruction {
+      address: 0x8002,
+      opcode: 0xBD,
+      operands: vec![0x00, 0x02],
+      mnemonic: "LDA",
+      mode: AddressingMode::Absolute_X,
+      operand_address: Some(0x0205),
+    });
+
+    // Peeking again gives the same answer, and stepping executes what was peeked
+    assert_eq!(cpu.peek_instruction().unwrap(), peeked);
+    cpu.step().unwrap();
+    assert_eq!(cpu.program_counter, 0x8005);
+
+    let peeked = cpu.peek_instruction().unwrap();
+    assert_eq!(peeked.mnemonic, "BRK");
+    assert_eq!(peeked.operand_address, None);
+  }
+
+  #[test]
+  fn test_default_matches_new() {
+    let cpu = CPU::default();
+    assert_eq!(cpu.register_a, 0);
+    assert_eq!(cpu.status.bits(), 0b0010_0100);
+    assert_eq!(cpu.stack_pointer, STACK_RESET);
+
+    let mut cpu = CPU::new();
+    cpu.register_a = 0x42;
+    let old = std::mem::take(&mut cpu);
+    assert_eq!(old.register_a, 0x42);
+    assert_eq!(cpu.register_a, 0);
+  }
+
+  #[test]
+  fn test_display_and_debug() {
+    let mut cpu = CPU::new();
+    cpu.load(vec![0xa9, 0x05, 0x00]);
+    cpu.reset();
+    cpu.register_a = 0x0A;
+    cpu.register_x = 0xFF;
+    cpu.register_y = 0x01;
+    cpu.stack_pointer = 0xF0;
+    cpu.cycles = 7;
+
+    assert_eq!(cpu.to_string(), "A:0A X:FF Y:01 P:24 SP:F0 PC:8000 CYC:7");
+    assert_eq!(format!("{:?}", cpu), "CPU { A:0A X:FF Y:01 P:24 SP:F0 PC:8000 CYC:7 NEXT:A9 LDA }");
+  }
+
+  #[test]
+  fn test_format_status() {
+    assert_eq!(format_status(0b0010_0100), "nv-bdIzc");
+    assert_eq!(format_status(0xFF), "NV-BDIZC");
+    assert_eq!(format_status(0x00), "nv-bdizc");
+    assert_eq!(format_status(0b1000_0011), "Nv-bdiZC");
+  }
+
+  #[test]
+  fn test_trace_entry_display() {
+    let entry = TraceEntry {
+      program_counter: 0xC000,
+      opcode: 0x8D,
+      operands: vec![0x00, 0x02],
+      mnemonic: "STA",
+      mode: AddressingMode::Absolute,
+      register_a: 0x01,
+      register_x: 0x02,
+      register_y: 0x03,
+      status: StatusFlags::from_bits_truncate(0b0010_0101),
+      stack_pointer: 0xFD,
+      cycles: 7,
+    };
+
+    assert_eq!(entry.to_string(), "C000  8D 00 02  STA  A:01 X:02 Y:03 P:nv-bdIzC SP:FD CYC:7");
+  }
+
+  #[test]
+  fn test_implemented_opcodes() {
+    for opcode in [0xA9, 0x8D, 0x69, 0xAA, 0xE8, 0x00] { // LDA, STA, ADC, TAX, INX, BRK
+      assert!(is_implemented(opcode), "{:02X}", opcode);
+    }
+    assert!(!is_implemented(0xA2)); // LDX
+
+    let opcodes = implemented_opcodes();
+    assert_eq!(opcodes.len(), OPCODES_MAP.len());
+    assert!(opcodes.windows(2).all(|pair| pair[0] < pair[1]));
+    assert!(opcodes.iter().all(|opcode| OpCode::try_from(*opcode).is_ok()));
+  }
+
+  #[test]
+  fn test_unofficial_opcodes() {
+    assert!(is_unofficial(0x1A)); // NOP
+    assert!(is_unofficial(0x02)); // JAM
+    assert!(!is_unofficial(0xEA)); // Official NOP
+    assert!(!is_unofficial(0xA9));
+    // Every unofficial opcode made it into OPCODES_MAP
+    let unofficial = implemented_opcodes().into_iter().filter(|opcode| is_unofficial(*opcode)).count();
+    assert_eq!(unofficial, 39);
+  }
+
+  #[test]
+  fn test_addressing_mode_display() {
+    let expected = [
+      (AddressingMode::Immediate, "#"),
+      (AddressingMode::ZeroPage, "zp"),
+      (AddressingMode::ZeroPage_X, "zp,X"),
+      (AddressingMode::ZeroPage_Y, "zp,Y"),
+      (AddressingMode::Absolute, "abs"),
+      (AddressingMode::Absolute_X, "abs,X"),
+      (AddressingMode::Absolute_Y, "abs,Y"),
+      (AddressingMode::Indirect_X, "(ind,X)"),
+      (AddressingMode::Indirect_Y, "(ind),Y"),
+      (AddressingMode::Indirect, "(ind)"),
+      (AddressingMode::Relative, "rel"),
+      (AddressingMode::Accumulator, "A"),
+      (AddressingMode::NoneAddressing, ""),
+    ];
+    for (mode, notation) in expected {
+      assert_eq!(mode.to_string(), notation);
+    }
+  }
+
+  #[test]
+  fn test_operand_size_matches_opcode_table() {
+    assert_eq!(AddressingMode::Accumulator.operand_size(), 0);
+    assert_eq!(AddressingMode::Relative.operand_size(), 1);
+    assert_eq!(AddressingMode::Indirect.operand_size(), 2);
+
+    for (code, OpCode(name, byte_count, _, mode)) in OPCODES_MAP.iter() {
+      assert_eq!(1 + mode.operand_size(), *byte_count, "{} ({:02X})", name, code);
+    }
+  }
+
+  #[test]
+  fn test_assert_flags() {
+    let mut cpu = CPU::new();
+    cpu.status = StatusFlags::CARRY | StatusFlags::NEGATIVE;
+    cpu.assert_flags(Some(true), Some(false), Some(true), Some(false));
+    cpu.assert_flags(None, None, None, None);
+  }
+
+  #[test]
+  #[should_panic(expected = "CARRY should be clear, OVERFLOW should be set (P: $81 Nv-bdizC)")]
+  fn test_assert_flags_reports_mismatches() {
+    let mut cpu = CPU::new();
+    cpu.status = StatusFlags::CARRY | StatusFlags::NEGATIVE;
+    cpu.assert_flags(Some(false), None, Some(true), Some(true));
+  }
+
+  #[test]
+  fn test_copy_page() {
+    let mut cpu = CPU::new();
+    for offset in 0..=0xFF {
+      cpu.mem_write(offset, offset as u8 ^ 0x5A);
+    }
+    cpu.stack_push(0xAB);
+    cpu.stack_push(0xCD);
+
+    let zero_page = cpu.copy_page(0x00);
+    assert!(zero_page.iter().enumerate().all(|(offset, byte)| *byte == offset as u8 ^ 0x5A));
+
+    let stack = cpu.copy_page(0x01);
+    assert_eq!(&stack[0xFC..=0xFD], &[0xCD, 0xAB]);
+    assert_eq!(&stack[0xFC..], &cpu.stack_dump()[..]);
+
+    cpu.mem_write(0x0700, 0x11);
+    cpu.mem_write(0x07FF, 0x22);
+    let page = cpu.copy_page(0x07);
+    assert_eq!((page[0x00], page[0xFF]), (0x11, 0x22));
+  }
+
+  #[test]
+  fn test_mem_checksum() {
+    let mut cpu = CPU::new();
+    let pattern = [0x12, 0x34, 0x56, 0x78, 0x9A];
+    for (i, byte) in pattern.iter().enumerate() {
+      cpu.mem_write(0x0300 + i as u16, *byte);
+    }
+
+    assert_eq!(cpu.mem_checksum(0x0300, 5), 0x12 ^ 0x34 ^ 0x56 ^ 0x78 ^ 0x9A);
+    assert_eq!(cpu.mem_checksum(0x0300, 2), 0x12 ^ 0x34);
+    assert_eq!(cpu.mem_checksum(0x0300, 0), 0);
+
+    cpu.mem_write(0x0302, 0x57);
+    assert_eq!(cpu.mem_checksum(0x0300, 5), 0x12 ^ 0x34 ^ 0x57 ^ 0x78 ^ 0x9A);
+
+    // The range wraps around the end of the address space
+    cpu.mem_write(0xFFFF, 0x0F);
+    cpu.mem_write(0x0000, 0xF0);
+    assert_eq!(cpu.mem_checksum(0xFFFF, 2), 0xFF);
+  }
+
+  #[test]
+  fn test_reset_cycles() {
+    let mut cpu = CPU::new();
+    cpu.load(assembler::assemble("
+      LDA #$01
+      STA $0200
+      INC $0200
+      BRK
+    ", 0x8000).unwrap());
+    cpu.reset();
+    cpu.run_with_limit(DEFAULT_INSTRUCTION_LIMIT).unwrap();
+    assert_eq!(cpu.total_cycles(), 7 + 2 + 4 + 6 + 7);
+
+    // The second run only counts its own cycles
+    cpu.reset();
+    cpu.reset_cycles();
+    assert_eq!(cpu.total_cycles(), 0);
+    cpu.run_with_limit(DEFAULT_INSTRUCTION_LIMIT).unwrap();
+    assert_eq!(cpu.total_cycles(), 2 + 4 + 6 + 7);
+  }
+
+  #[test]
+  fn test_validate_timing() {
+    let mut cpu = CPU::new();
+    // Reset, then LDA #imm (2), STA abs,X (5, page cross or not), ADC zp (3), INC zp,X (6), BRK (7)
+    cpu.load_and_run_asm("
+      LDA #$01
+      STA $02FF,X
+      ADC $10
+      INC $10,X
+      BRK
+    ").unwrap();
+
+    assert_eq!(cpu.validate_timing(7 + 2 + 5 + 3 + 6 + 7), Ok(()));
+    let err = cpu.validate_timing(28).unwrap_err();
+    assert_eq!(err, TimingError { expected: 28, actual: 30, diff: 2 });
+    assert_eq!(err.to_string(), "expected 28 cycles, took 30 (+2)");
+  }
+
+  #[test]
+  fn test_reset_takes_seven_cycles() {
+    let mut cpu = CPU::new();
+    cpu.load(vec![0xe8, 0x00]);
+    cpu.reset();
+    assert_eq!(cpu.total_cycles(), 7);
+    assert_eq!(cpu.bus.ppu.cycles, 7 * 3);
+
+    cpu.step().unwrap(); // INX
+    assert_eq!(cpu.total_cycles(), 7 + 2);
+  }
+
+  #[test]
+  fn test_run_with_limit() {
+    let mut cpu = CPU::new();
+    cpu.load(assembler::assemble("loop: JMP loop", 0x8000).unwrap());
+    cpu.reset();
+    assert_eq!(cpu.run_with_limit(1000), Err(CpuError::InstructionLimitExceeded));
+    assert_eq!(cpu.total_cycles(), 7 + 3000);
+
+    // BRK counts as an instruction, so three instructions need a limit of three
+    cpu.load(assembler::assemble("INX\nINX\nBRK", 0x8000).unwrap());
+    cpu.reset();
+    assert_eq!(cpu.run_with_limit(2), Err(CpuError::InstructionLimitExceeded));
+    cpu.reset();
+    assert_eq!(cpu.run_with_limit(3), Ok(()));
+
+    let mut cpu = CPU::new();
+    assert_eq!(cpu.load_and_run_asm("loop: JMP loop"), Err(CpuError::InstructionLimitExceeded));
+  }
+
+  #[test]
+  fn test_opcode_byte_counts_in_range() {
+    for (code, OpCode(name, byte_count, _, _)) in OPCODES_MAP.iter() {
+      assert!((1..=3).contains(byte_count), "{} ({:02X}) has byte count {}", name, code, byte_count);
+    }
+  }
+
+  #[test]
+  fn test_unofficial_nops() {
+    let mut cpu = CPU::new();
+    cpu.load(vec![
+      0xea, // NOP
+      0x0c, 0x34, 0x12, // NOP $1234
+      0x80, 0xff, // NOP #$FF
+      0x1c, 0xf0, 0x12, // NOP $12F0,X (crosses a page)
+      0x14, 0x10, // NOP $10,X
+      0x00
+    ]);
+    cpu.reset();
+    cpu.register_x = 0x20;
+
+    assert_eq!(cpu.tick().unwrap().cycles, 2);
+    assert_eq!(cpu.program_counter, 0x8001);
+    assert_eq!(cpu.tick().unwrap().cycles, 4);
+    assert_eq!(cpu.program_counter, 0x8004);
+    assert_eq!(cpu.tick().unwrap().cycles, 2);
+    assert_eq!(cpu.tick().unwrap().cycles, 5);
+    assert_eq!(cpu.program_counter, 0x8009);
+    assert_eq!(cpu.tick().unwrap().cycles, 4);
+    assert_eq!(cpu.program_counter, 0x800B);
+    assert_eq!(cpu.register_a, 0);
+    assert_eq!(cpu.status.bits(), 0b0010_0100);
+
+    // The assembler sticks to the official NOP
+    assert_eq!(assembler::assemble("NOP", 0x8000), Ok(vec![0xea]));
+  }
+
+  #[test]
+  fn test_get_and_set_state() {
+    let mut cpu = CPU::new();
+    cpu.load(vec![0xa9, 0x05, 0xaa, 0x00]);
+    cpu.reset();
+    cpu.step().unwrap();
+
+    assert_eq!(cpu.get_state(), CpuState { a: 5, x: 0, y: 0, status: 0b0010_0100, sp: 0xFD, pc: 0x8002, cycles: 7 + 2 });
+
+    let state = CpuState { a: 0x80, x: 0x01, y: 0x02, status: 0b1010_0101, sp: 0xF0, pc: 0x8002, cycles: 100 };
+    cpu.set_state(state);
+    assert_eq!(cpu.get_state(), state);
+
+    cpu.step().unwrap(); // TAX
+    assert_eq!(cpu.get_state(), CpuState { x: 0x80, pc: 0x8003, cycles: 102, ..state });
+  }
+}
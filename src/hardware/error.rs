@@ -0,0 +1,46 @@
+#![allow(unused)]
+/* The following code defines the errors the emulated hardware can run into */
+
+use std::fmt;
+use super::opcodes::AddressingMode;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CpuError {
+  UnknownOpcode(u8), // Byte isn't in the opcode table
+  UnknownFlag(&'static str), // Status flag name isn't in STATUS_FLAGS
+  UnsupportedMode(AddressingMode), // Instruction asked for an operand its mode can't provide
+  OutOfBounds(u16), // Program counter ran off the end of the address space
+  Jammed, // Hit a JAM/KIL opcode, the real CPU locks up until reset
+  InstructionLimitExceeded, // run_with_limit executed its whole budget without reaching BRK
+}
+
+impl fmt::Display for CpuError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      CpuError::UnknownOpcode(code) => write!(f, "unknown opcode ${:02X}", code),
+      CpuError::UnknownFlag(flag) => write!(f, "unknown status flag {}", flag),
+      CpuError::UnsupportedMode(mode) => write!(f, "addressing mode {:?} has no operand address", mode),
+      CpuError::OutOfBounds(addr) => write!(f, "program counter ran past ${:04X}", addr),
+      CpuError::Jammed => write!(f, "CPU jammed"),
+      CpuError::InstructionLimitExceeded => write!(f, "instruction limit exceeded before BRK"),
+    }
+  }
+}
+
+impl std::error::Error for CpuError {}
+
+// Cycle count that didn't match a reference, from CPU::validate_timing
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimingError {
+  pub expected: u64,
+  pub actual: u64,
+  pub diff: i64, // actual - expected, positive when the CPU ran slow
+}
+
+impl fmt::Display for TimingError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "expected {} cycles, took {} ({:+})", self.expected, self.actual, self.diff)
+  }
+}
+
+impl std::error::Error for TimingError {}
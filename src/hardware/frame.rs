@@ -0,0 +1,113 @@
+#![allow(unused)]
+/* The following code holds a rendered 256x240 RGB picture */
+
+pub const WIDTH: usize = 256;
+pub const HEIGHT: usize = 240;
+
+// Rows/columns hidden at each edge, like a TV's overscan
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Overscan {
+  pub top: usize,
+  pub bottom: usize,
+  pub left: usize,
+  pub right: usize,
+}
+
+impl Overscan {
+  // Show the whole frame (the default)
+  pub const NONE: Overscan = Overscan { top: 0, bottom: 0, left: 0, right: 0 };
+  // Hide the top and bottom 8 scanlines, where most TVs cut off the picture
+  pub const STANDARD: Overscan = Overscan { top: 8, bottom: 8, left: 0, right: 0 };
+
+  // Width and height of the picture left after cropping
+  pub fn size(&self) -> (usize, usize) {
+    (
+      WIDTH.saturating_sub(self.left + self.right),
+      HEIGHT.saturating_sub(self.top + self.bottom),
+    )
+  }
+}
+
+pub struct Frame {
+  pub data: Vec<u8>, // RGB triples, row by row
+  pub indices: Vec<u16>, // Color index + emphasis bits behind each pixel (see palette::emphasized_index)
+}
+
+impl Frame {
+  // Frame constructor (all black)
+  pub fn new() -> Self {
+    Frame {
+      data: vec![0x00; WIDTH * HEIGHT * 3],
+      indices: vec![0x00; WIDTH * HEIGHT],
+    }
+  }
+
+  pub fn set_pixel(&mut self, x: usize, y: usize, rgb: (u8, u8, u8)) {
+    if x < WIDTH && y < HEIGHT {
+      let base = (y * WIDTH + x) * 3;
+      self.data[base] = rgb.0;
+      self.data[base + 1] = rgb.1;
+      self.data[base + 2] = rgb.2;
+    }
+  }
+
+  pub fn set_index(&mut self, x: usize, y: usize, index: u16) {
+    if x < WIDTH && y < HEIGHT {
+      self.indices[y * WIDTH + x] = index;
+    }
+  }
+
+  pub fn get_pixel(&self, x: usize, y: usize) -> (u8, u8, u8) {
+    let base = (y * WIDTH + x) * 3;
+    (self.data[base], self.data[base + 1], self.data[base + 2])
+  }
+
+  // Copy the visible part of the frame into a tightly packed RGB buffer (see Overscan::size)
+  pub fn cropped(&self, overscan: Overscan) -> Vec<u8> {
+    let (width, height) = overscan.size();
+    let mut data = Vec::with_capacity(width * height * 3);
+    for y in overscan.top .. overscan.top + height {
+      let row_start = (y * WIDTH + overscan.left) * 3;
+      data.extend_from_slice(&self.data[row_start .. row_start + width * 3]);
+    }
+    data
+  }
+}
+
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn test_set_and_get_pixel() {
+    let mut frame = Frame::new();
+    frame.set_pixel(255, 239, (1, 2, 3));
+    frame.set_pixel(256, 0, (4, 5, 6)); // Off screen is ignored
+
+    assert_eq!(frame.get_pixel(255, 239), (1, 2, 3));
+    assert_eq!(&frame.data[frame.data.len() - 3 ..], &[1, 2, 3]);
+    assert_eq!(frame.get_pixel(0, 1), (0, 0, 0));
+  }
+
+  #[test]
+  fn test_cropped_overscan() {
+    let mut frame = Frame::new();
+    frame.set_pixel(0, 8, (1, 2, 3));
+    frame.set_pixel(255, 8, (4, 5, 6));
+    frame.set_pixel(8, 8, (7, 8, 9));
+
+    assert_eq!(frame.cropped(Overscan::NONE), frame.data);
+
+    let cropped = frame.cropped(Overscan::STANDARD);
+    assert_eq!(Overscan::STANDARD.size(), (256, 224));
+    assert_eq!(cropped.len(), 256 * 224 * 3);
+    assert_eq!(&cropped[.. WIDTH * 3], &frame.data[8 * WIDTH * 3 .. 9 * WIDTH * 3]);
+
+    let sides = Overscan { left: 8, right: 8, ..Overscan::STANDARD };
+    let cropped = frame.cropped(sides);
+    assert_eq!(cropped.len(), 240 * 224 * 3);
+    assert_eq!(&cropped[.. 3], &[7, 8, 9]);
+    assert_eq!(&cropped[240 * 3 .. 241 * 3], &[0, 0, 0]); // Row 9 starts after 240 pixels
+  }
+}
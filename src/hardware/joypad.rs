@@ -0,0 +1,194 @@
+#![allow(unused)]
+/* The following code emulates the standard NES controller read through $4016 */
+
+// Buttons in the order the controller shifts them out, which is also their bit in the state byte
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Button {
+  A,
+  B,
+  Select,
+  Start,
+  Up,
+  Down,
+  Left,
+  Right,
+}
+
+impl Button {
+  pub const ALL: [Button; 8] = [
+    Button::A, Button::B, Button::Select, Button::Start,
+    Button::Up, Button::Down, Button::Left, Button::Right,
+  ];
+
+  // Bit for this button in the state byte
+  pub fn bit(self) -> u8 {
+    1 << self as u8
+  }
+}
+
+const UP_DOWN: u8 = 0b0011_0000;
+const LEFT_RIGHT: u8 = 0b1100_0000;
+
+pub struct Joypad {
+  strobe: bool, // While set the shift register keeps reloading, so reads always return A
+  button_index: u8, // Next button to shift out, past 7 once all eight have been read
+  buttons: u8, // Held buttons, one bit each (see Button::bit)
+  block_opposite_directions: bool, // Hide Left+Right and Up+Down, which a real d-pad can't press
+}
+
+impl Joypad {
+  // Joypad constructor (nothing pressed)
+  pub fn new() -> Self {
+    Joypad {
+      strobe: false,
+      button_index: 0,
+      buttons: 0,
+      block_opposite_directions: false,
+    }
+  }
+
+  // Press or release a button, frontends call this as input arrives
+  pub fn set_button(&mut self, button: Button, pressed: bool) {
+    if pressed {
+      self.buttons |= button.bit();
+    }
+    else {
+      self.buttons &= !button.bit();
+    }
+  }
+
+  pub fn press(&mut self, button: Button) {
+    self.set_button(button, true);
+  }
+
+  pub fn release(&mut self, button: Button) {
+    self.set_button(button, false);
+  }
+
+  // Replace the whole state with the buttons held this frame
+  pub fn set(&mut self, held: &[Button]) {
+    self.buttons = held.iter().fold(0, |buttons, button| buttons | button.bit());
+  }
+
+  pub fn is_pressed(&self, button: Button) -> bool {
+    self.buttons() & button.bit() != 0
+  }
+
+  // Keyboards can hold both directions of an axis, which some games glitch on. When blocking
+  // is on, an axis with both directions held reads as neither
+  pub fn set_block_opposite_directions(&mut self, enabled: bool) {
+    self.block_opposite_directions = enabled;
+  }
+
+  // Buttons the game sees as a state byte
+  pub fn buttons(&self) -> u8 {
+    let mut buttons = self.buttons;
+    if self.block_opposite_directions {
+      for axis in [UP_DOWN, LEFT_RIGHT] {
+        if buttons & axis == axis {
+          buttons &= !axis;
+        }
+      }
+    }
+    buttons
+  }
+
+  // $4016 write, bit 0 is the strobe and setting it restarts the read from A
+  pub fn write(&mut self, data: u8) {
+    self.strobe = data & 1 != 0;
+    if self.strobe {
+      self.button_index = 0;
+    }
+  }
+
+  // Serial read, one button per read in Button order, then 1s once all eight are out
+  pub fn read(&mut self) -> u8 {
+    if self.button_index > 7 {
+      return 1;
+    }
+    let bit = (self.buttons() >> self.button_index) & 1;
+    if !self.strobe {
+      self.button_index += 1;
+    }
+    bit
+  }
+}
+
+impl Default for Joypad {
+  fn default() -> Self {
+    Joypad::new()
+  }
+}
+
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn test_reads_shift_out_buttons_in_order() {
+    let mut joypad = Joypad::new();
+    joypad.set_button(Button::A, true);
+    joypad.set_button(Button::Start, true);
+    joypad.set_button(Button::Right, true);
+    assert_eq!(joypad.buttons(), 0b1000_1001);
+
+    joypad.write(1);
+    joypad.write(0);
+    let bits: Vec<u8> = (0..10).map(|_| joypad.read()).collect();
+    assert_eq!(bits, vec![1, 0, 0, 1, 0, 0, 0, 1, 1, 1]);
+  }
+
+  #[test]
+  fn test_button_bits() {
+    let bits: Vec<u8> = Button::ALL.iter().map(|button| button.bit()).collect();
+    assert_eq!(bits, vec![0x01, 0x02, 0x04, 0x08, 0x10, 0x20, 0x40, 0x80]);
+
+    let mut joypad = Joypad::new();
+    joypad.set(&[Button::Select, Button::Down]);
+    assert_eq!(joypad.buttons(), 0b0010_0100);
+    joypad.press(Button::B);
+    joypad.release(Button::Select);
+    assert_eq!(joypad.buttons(), 0b0010_0010);
+    joypad.set(&[]);
+    assert_eq!(joypad.buttons(), 0);
+  }
+
+  #[test]
+  fn test_block_opposite_directions() {
+    let mut joypad = Joypad::new();
+    joypad.set(&[Button::A, Button::Up, Button::Down, Button::Left, Button::Right]);
+    assert_eq!(joypad.buttons(), 0b1111_0001);
+
+    joypad.set_block_opposite_directions(true);
+    assert_eq!(joypad.buttons(), 0b0000_0001);
+    assert!(!joypad.is_pressed(Button::Left));
+    joypad.write(1);
+    joypad.write(0);
+    let bits: Vec<u8> = (0..8).map(|_| joypad.read()).collect();
+    assert_eq!(bits, vec![1, 0, 0, 0, 0, 0, 0, 0]);
+
+    // One direction on its own still gets through, and turning blocking off restores both
+    joypad.release(Button::Left);
+    assert_eq!(joypad.buttons(), 0b1000_0001);
+    joypad.set_block_opposite_directions(false);
+    assert_eq!(joypad.buttons(), 0b1011_0001);
+  }
+
+  #[test]
+  fn test_strobe_held_keeps_returning_a() {
+    let mut joypad = Joypad::new();
+    joypad.set_button(Button::B, true);
+    joypad.write(1);
+    assert_eq!((joypad.read(), joypad.read()), (0, 0));
+
+    joypad.set_button(Button::A, true);
+    assert_eq!(joypad.read(), 1);
+    joypad.set_button(Button::A, false);
+    assert!(!joypad.is_pressed(Button::A));
+
+    // Dropping the strobe starts the shift from A again
+    joypad.write(0);
+    assert_eq!((joypad.read(), joypad.read()), (0, 1));
+  }
+}
@@ -2,4 +2,13 @@
 /* This file contains module declarations for various hardware implementations */
 
 pub mod cpu; // Declaration for CPU
-pub mod opcodes; // Declaration for Bus (connects everything together)
\ No newline at end of file
+pub mod opcodes; // Declaration for Bus (connects everything together)
+pub mod cartridge; // Declaration for Cartridge (iNES game ROMs)
+pub mod ppu; // Declaration for PPU (Picture Processing Unit)
+pub mod apu; // Declaration for APU (Audio Processing Unit)
+pub mod joypad; // Declaration for Joypad (standard controller)
+pub mod bus; // Declaration for Bus (connects everything together)
+pub mod palette; // Declaration for Palette (NES colors to RGB)
+pub mod frame; // Declaration for Frame (rendered picture)
+pub mod error; // Declaration for CpuError (hardware error type)
+pub mod nes; // Declaration for Nes (the whole console wired together)
\ No newline at end of file
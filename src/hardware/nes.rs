@@ -0,0 +1,130 @@
+#![allow(unused)]
+/* The following code wires the CPU, bus, PPU and cartridge together into a whole console */
+
+use super::cartridge::Cartridge;
+use super::cpu::CPU;
+use super::error::CpuError;
+use super::frame::Frame;
+use super::joypad::Joypad;
+
+// The CPU owns the bus, which owns the PPU, APU and the inserted cartridge's memory
+pub struct Nes {
+  pub cpu: CPU,
+}
+
+impl Nes {
+  // Nes constructor (no cartridge inserted)
+  pub fn new() -> Self {
+    Nes {
+      cpu: CPU::new(),
+    }
+  }
+
+  // Plug in a cartridge, then reset so the CPU starts from its reset vector
+  pub fn insert_cartridge(&mut self, cartridge: Cartridge) {
+    self.cpu.bus.insert_cartridge(cartridge);
+    self.reset();
+  }
+
+  // Press the reset button
+  pub fn reset(&mut self) {
+    self.cpu.reset();
+  }
+
+  // Run the CPU (which keeps the PPU and APU in lockstep) until the PPU finishes a frame.
+  // Returns false if BRK stopped the CPU first
+  pub fn step_frame(&mut self) -> Result<bool, CpuError> {
+    let start_frame = self.cpu.bus.ppu.frame_count;
+    while self.cpu.bus.ppu.frame_count == start_frame {
+      if !self.cpu.step()? {
+        return Ok(false);
+      }
+    }
+    Ok(true)
+  }
+
+  // Frames the PPU has finished since power on
+  pub fn frame_count(&self) -> u64 {
+    self.cpu.bus.ppu.frame_count
+  }
+
+  // Controller plugged into port 1, for frontends to feed input into
+  pub fn joypad1_mut(&mut self) -> &mut Joypad {
+    &mut self.cpu.bus.joypad1
+  }
+
+  // Controller plugged into port 2
+  pub fn joypad2_mut(&mut self) -> &mut Joypad {
+    &mut self.cpu.bus.joypad2
+  }
+
+  // The last picture the PPU rendered
+  pub fn frame(&self) -> &Frame {
+    &self.cpu.bus.ppu.frame
+  }
+}
+
+impl Default for Nes {
+  fn default() -> Self {
+    Nes::new()
+  }
+}
+
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use crate::hardware::cartridge::Mirroring;
+
+  // 16KB NROM cartridge that spins on JMP $8000 from reset
+  fn spin_cartridge() -> Cartridge {
+    let mut prg_rom = vec![0x00; 0x4000];
+    prg_rom[..3].copy_from_slice(&[0x4C, 0x00, 0x80]);
+    prg_rom[0x3FFC..0x3FFE].copy_from_slice(&[0x00, 0x80]);
+    Cartridge {
+      prg_rom,
+      chr_rom: vec![0x00; 0x2000],
+      mapper: 0,
+      screen_mirroring: Mirroring::Horizontal,
+    }
+  }
+
+  #[test]
+  fn test_step_frame() {
+    let mut nes = Nes::new();
+    nes.insert_cartridge(spin_cartridge());
+    assert_eq!(nes.cpu.program_counter, 0x8000);
+
+    assert_eq!(nes.step_frame(), Ok(true));
+    assert_eq!(nes.frame_count(), 1);
+    assert_eq!(nes.step_frame(), Ok(true));
+    assert_eq!(nes.frame_count(), 2);
+
+    // A frame is 341 * 262 dots, about 29781 CPU cycles
+    let cycles = nes.cpu.total_cycles();
+    nes.step_frame().unwrap();
+    assert!((nes.cpu.total_cycles() - cycles).abs_diff(29781) <= 3);
+  }
+
+  #[test]
+  fn test_joypad_helpers() {
+    use crate::hardware::joypad::Button;
+    let mut nes = Nes::new();
+    nes.joypad1_mut().press(Button::Start);
+    nes.joypad2_mut().press(Button::A);
+
+    assert_eq!(nes.cpu.bus.joypad1.buttons(), Button::Start.bit());
+    assert_eq!(nes.cpu.bus.joypad2.buttons(), Button::A.bit());
+  }
+
+  #[test]
+  fn test_step_frame_stops_at_brk() {
+    let mut cartridge = spin_cartridge();
+    cartridge.prg_rom[0] = 0x00;
+    let mut nes = Nes::new();
+    nes.insert_cartridge(cartridge);
+
+    assert_eq!(nes.step_frame(), Ok(false));
+    assert_eq!(nes.frame_count(), 0);
+  }
+}
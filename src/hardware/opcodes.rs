@@ -1,7 +1,7 @@
 #![allow(unused)]
 /* The following code defines the opCodes for each instruction */
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[allow(non_camel_case_types)]
 pub enum AddressingMode {
    Immediate,
@@ -13,9 +13,54 @@ pub enum AddressingMode {
    Absolute_Y,
    Indirect_X,
    Indirect_Y,
+   Indirect, // JMP ($xxxx) only
+   Relative, // Signed branch offset from the next instruction
+   Accumulator, // Operates on register A (ASL A, ROR A, ...)
    NoneAddressing,
 }
 
+impl AddressingMode {
+  // Bytes of operand following the opcode
+  pub fn operand_size(&self) -> u8 {
+    match self {
+      AddressingMode::NoneAddressing | AddressingMode::Accumulator => 0,
+      AddressingMode::Immediate
+      | AddressingMode::ZeroPage
+      | AddressingMode::ZeroPage_X
+      | AddressingMode::ZeroPage_Y
+      | AddressingMode::Relative
+      | AddressingMode::Indirect_X
+      | AddressingMode::Indirect_Y => 1,
+      AddressingMode::Absolute
+      | AddressingMode::Absolute_X
+      | AddressingMode::Absolute_Y
+      | AddressingMode::Indirect => 2,
+    }
+  }
+}
+
+// Operand shape in assembler notation, as used in opcode reference tables
+impl fmt::Display for AddressingMode {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    let notation = match self {
+      AddressingMode::Immediate => "#",
+      AddressingMode::ZeroPage => "zp",
+      AddressingMode::ZeroPage_X => "zp,X",
+      AddressingMode::ZeroPage_Y => "zp,Y",
+      AddressingMode::Absolute => "abs",
+      AddressingMode::Absolute_X => "abs,X",
+      AddressingMode::Absolute_Y => "abs,Y",
+      AddressingMode::Indirect_X => "(ind,X)",
+      AddressingMode::Indirect_Y => "(ind),Y",
+      AddressingMode::Indirect => "(ind)",
+      AddressingMode::Relative => "rel",
+      AddressingMode::Accumulator => "A",
+      AddressingMode::NoneAddressing => "",
+    };
+    f.write_str(notation)
+  }
+}
+
 // type aliases for readability
 pub type ByteCount = u8;
 pub type CycleCount = u8;
@@ -27,9 +72,11 @@ pub struct OpCode (
   pub CycleCount,
   pub AddressingMode
 );
-impl From<u8> for OpCode {
-  fn from(value: u8) -> Self {
-    match value {
+impl TryFrom<u8> for OpCode {
+  type Error = CpuError;
+
+  fn try_from(value: u8) -> Result<Self, CpuError> {
+    Ok(match value {
       // Load Accumulator
       0xAD => OpCode("LDA", 3, 4, AddressingMode::Absolute),
       0xBD => OpCode("LDA", 3, 4, AddressingMode::Absolute_X),
@@ -56,45 +103,145 @@ impl From<u8> for OpCode {
       0x71 => OpCode("ADC", 2, 5, AddressingMode::Indirect_Y), // +1 cycle if page crossed
       0x65 => OpCode("ADC", 2, 3, AddressingMode::ZeroPage),
       0x75 => OpCode("ADC", 2, 4, AddressingMode::ZeroPage_X),
+      // Subtract with Carry
+      0xED => OpCode("SBC", 3, 4, AddressingMode::Absolute),
+      0xFD => OpCode("SBC", 3, 4, AddressingMode::Absolute_X), // +1 cycle if page crossed
+      0xF9 => OpCode("SBC", 3, 4, AddressingMode::Absolute_Y), // +1 cycle if page crossed
+      0xE9 => OpCode("SBC", 2, 2, AddressingMode::Immediate),
+      0xE1 => OpCode("SBC", 2, 6, AddressingMode::Indirect_X),
+      0xF1 => OpCode("SBC", 2, 5, AddressingMode::Indirect_Y), // +1 cycle if page crossed
+      0xE5 => OpCode("SBC", 2, 3, AddressingMode::ZeroPage),
+      0xF5 => OpCode("SBC", 2, 4, AddressingMode::ZeroPage_X),
+      // Increment Memory
+      0xE6 => OpCode("INC", 2, 5, AddressingMode::ZeroPage),
+      0xF6 => OpCode("INC", 2, 6, AddressingMode::ZeroPage_X),
+      0xEE => OpCode("INC", 3, 6, AddressingMode::Absolute),
+      0xFE => OpCode("INC", 3, 7, AddressingMode::Absolute_X),
+      // Jump
+      0x4C => OpCode("JMP", 3, 3, AddressingMode::Absolute),
       // Others
       0x00 => OpCode("BRK", 1, 7, AddressingMode::NoneAddressing),
       0xAA => OpCode("TAX", 1, 2, AddressingMode::NoneAddressing),
       0xE8 => OpCode("INX", 1, 2, AddressingMode::NoneAddressing),
-      // PANIC!!
-      _ => panic!("no operation exists for the given value {:?}", value)
-    }
+      0x40 => OpCode("RTI", 1, 6, AddressingMode::NoneAddressing),
+      // No Operation
+      0xEA => OpCode("NOP", 1, 2, AddressingMode::NoneAddressing),
+      // Unofficial NOPs, the operand forms read (and ignore) their operand
+      0x1A | 0x3A | 0x5A | 0x7A | 0xDA | 0xFA => OpCode("NOP", 1, 2, AddressingMode::NoneAddressing),
+      0x80 | 0x82 | 0x89 | 0xC2 | 0xE2 => OpCode("NOP", 2, 2, AddressingMode::Immediate),
+      0x04 | 0x44 | 0x64 => OpCode("NOP", 2, 3, AddressingMode::ZeroPage),
+      0x14 | 0x34 | 0x54 | 0x74 | 0xD4 | 0xF4 => OpCode("NOP", 2, 4, AddressingMode::ZeroPage_X),
+      0x0C => OpCode("NOP", 3, 4, AddressingMode::Absolute),
+      0x1C | 0x3C | 0x5C | 0x7C | 0xDC | 0xFC => {
+        OpCode("NOP", 3, 4, AddressingMode::Absolute_X) // +1 cycle if page crossed
+      },
+      // Jam (unofficial, locks up the CPU)
+      0x02 | 0x12 | 0x22 | 0x32 | 0x42 | 0x52 | 0x62 | 0x72 | 0x92 | 0xB2 | 0xD2 | 0xF2 => {
+        OpCode("JAM", 1, 2, AddressingMode::NoneAddressing)
+      },
+      _ => return Err(CpuError::UnknownOpcode(value))
+    })
   }
 }
 
 use std::collections::HashMap;
-use lazy_static::lazy_static;
-
-lazy_static! {
-  pub static ref OPCODES_MAP: HashMap<u8, OpCode> = {
-    let valid_codes: Vec<u8> = vec![
-      0xAD, 0xBD, 0xB9, 0xA9, 0xA1, 0xB1, 0xA5, 0xB5, // LDA
-      0x8D, 0x9D, 0x99, 0x81, 0x91, 0x85, 0x95, // STA
-      0x6D, 0x7D, 0x79, 0x69, 0x61, 0x71, 0x65, 0x75, // ADC
-      0x00, // BRK
-      0xAA, // TAX
-      0xE8, // INX
-    ];
-    let mut map = HashMap::new();
-    for code in valid_codes {
-      map.insert(code, OpCode::from(code));
-    }
+use std::convert::TryFrom;
+use std::fmt;
+use super::error::CpuError;
+use std::sync::LazyLock;
+use bitflags::bitflags;
+
+bitflags! {
+  // CPU status register
+  //  7 6 5 4 3 2 1 0
+  //  N V _ B D I Z C
+  #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+  pub struct StatusFlags: u8 {
+    const CARRY = 0b0000_0001;
+    const ZERO = 0b0000_0010;
+    const INTERRUPT_DISABLE = 0b0000_0100;
+    const DECIMAL_MODE = 0b0000_1000;
+    const BREAK = 0b0001_0000;
+    const BREAK2 = 0b0010_0000;
+    const OVERFLOW = 0b0100_0000;
+    const NEGATIVE = 0b1000_0000;
+  }
+}
+
+// Status is pushed/pulled (PHP, PLP, interrupts) and saved as a single byte
+impl From<u8> for StatusFlags {
+  fn from(value: u8) -> Self {
+    StatusFlags::from_bits_truncate(value)
+  }
+}
+
+impl From<StatusFlags> for u8 {
+  fn from(flags: StatusFlags) -> Self {
+    flags.bits()
+  }
+}
+
+// Opcodes the CPU can execute, split so the undocumented ones can be told apart (the assembler
+// prefers official encodings). Both lists feed OPCODES_MAP
+const OFFICIAL_OPCODES: [u8; 41] = [
+  0xAD, 0xBD, 0xB9, 0xA9, 0xA1, 0xB1, 0xA5, 0xB5, // LDA
+  0x8D, 0x9D, 0x99, 0x81, 0x91, 0x85, 0x95, // STA
+  0x6D, 0x7D, 0x79, 0x69, 0x61, 0x71, 0x65, 0x75, // ADC
+  0xED, 0xFD, 0xF9, 0xE9, 0xE1, 0xF1, 0xE5, 0xF5, // SBC
+  0xE6, 0xF6, 0xEE, 0xFE, // INC
+  0x4C, // JMP
+  0x00, // BRK
+  0xAA, // TAX
+  0xE8, // INX
+  0x40, // RTI
+  0xEA, // NOP
+];
+
+// Undocumented opcodes, kept so ROMs that rely on them don't desync
+const UNOFFICIAL_OPCODES: [u8; 39] = [
+  0x1A, 0x3A, 0x5A, 0x7A, 0xDA, 0xFA, // NOP
+  0x80, 0x82, 0x89, 0xC2, 0xE2,
+  0x04, 0x44, 0x64,
+  0x14, 0x34, 0x54, 0x74, 0xD4, 0xF4,
+  0x0C,
+  0x1C, 0x3C, 0x5C, 0x7C, 0xDC, 0xFC,
+  0x02, 0x12, 0x22, 0x32, 0x42, 0x52, 0x62, 0x72, 0x92, 0xB2, 0xD2, 0xF2, // JAM
+];
+
+pub static OPCODES_MAP: LazyLock<HashMap<u8, OpCode>> = LazyLock::new(|| {
+  let mut map = HashMap::new();
+  for code in OFFICIAL_OPCODES.iter().chain(UNOFFICIAL_OPCODES.iter()).copied() {
+    let opcode = OpCode::try_from(code).unwrap();
+    // The CPU advances the PC by byte_count - 1, so a zero here would wrap it around
+    debug_assert!((1..=3).contains(&opcode.1), "{} ({:02X}) has byte count {}", opcode.0, code, opcode.1);
+    map.insert(code, opcode);
+  }
+
+  map
+});
+
+pub static STATUS_FLAGS: LazyLock<HashMap<&'static str, u8>> = LazyLock::new(|| HashMap::from([
+  ("CARRY", 0b0000_0001),
+  ("ZERO", 0b0000_0010),
+  ("INTERRUPT_DISABLE", 0b0000_0100),
+  ("DECIMAL_MODE", 0b0000_1000),
+  ("BREAK", 0b0001_0000),
+  ("BREAK2", 0b0010_0000),
+  ("OVERFLOW", 0b0100_0000),
+  ("NEGATIVE", 0b1000_0000)
+]));
+
+pub fn is_unofficial(opcode: u8) -> bool {
+  UNOFFICIAL_OPCODES.contains(&opcode)
+}
+
+// Opcodes only enter OPCODES_MAP once the CPU can execute them, so the table doubles as the coverage list
+pub fn implemented_opcodes() -> Vec<u8> {
+  let mut opcodes: Vec<u8> = OPCODES_MAP.keys().copied().collect();
+  opcodes.sort_unstable();
+  opcodes
+}
 
-    map
-  };
-  
-  pub static ref STATUS_FLAGS: HashMap<&'static str, u8> = HashMap::from([
-    ("CARRY", 0b0000_0001),
-    ("ZERO", 0b0000_0010),
-    ("INTERRUPT_DISABLE", 0b0000_0100),
-    ("DECIMAL_MODE", 0b0000_1000),
-    ("BREAK", 0b0001_0000),
-    ("BREAK2", 0b0010_0000),
-    ("OVERFLOW", 0b0100_0000),
-    ("NEGATIVE", 0b1000_0000)
-  ]);
+pub fn is_implemented(opcode: u8) -> bool {
+  OPCODES_MAP.contains_key(&opcode)
 }
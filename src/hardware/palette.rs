@@ -0,0 +1,173 @@
+#![allow(unused)]
+/* The following code turns NES palette indices into RGB colors */
+
+// PPUMASK ($2001) bits that affect color output
+const MASK_GRAYSCALE: u8 = 0b0000_0001;
+const MASK_EMPHASIS: u8 = 0b1110_0000; // Red, green, blue (NTSC)
+
+// Non-emphasized channels are dimmed to roughly 81.6% when any emphasis bit is set
+const EMPHASIS_ATTENUATION: u16 = 209; // out of 256
+
+// Standard 2C02 palette
+pub const NES_PALETTE: [(u8, u8, u8); 64] = [
+  (84, 84, 84), (0, 30, 116), (8, 16, 144), (48, 0, 136), (68, 0, 100), (92, 0, 48), (84, 4, 0), (60, 24, 0),
+  (32, 42, 0), (8, 58, 0), (0, 64, 0), (0, 60, 0), (0, 50, 60), (0, 0, 0), (0, 0, 0), (0, 0, 0),
+  (152, 150, 152), (8, 76, 196), (48, 50, 236), (92, 30, 228), (136, 20, 176), (160, 20, 100), (152, 34, 32), (120, 60, 0),
+  (84, 90, 0), (40, 114, 0), (8, 124, 0), (0, 118, 40), (0, 102, 120), (0, 0, 0), (0, 0, 0), (0, 0, 0),
+  (236, 238, 236), (76, 154, 236), (120, 124, 236), (176, 98, 236), (228, 84, 236), (236, 88, 180), (236, 106, 100), (212, 136, 32),
+  (160, 170, 0), (116, 196, 0), (76, 208, 32), (56, 204, 108), (56, 180, 204), (60, 60, 60), (0, 0, 0), (0, 0, 0),
+  (236, 238, 236), (168, 204, 236), (188, 188, 236), (212, 178, 236), (236, 174, 236), (236, 174, 212), (236, 180, 176), (228, 196, 144),
+  (204, 210, 120), (180, 222, 120), (168, 226, 144), (152, 226, 180), (160, 214, 228), (160, 162, 160), (0, 0, 0), (0, 0, 0),
+];
+
+// Plain palette index to RGB with the standard palette, no grayscale or emphasis
+pub fn palette_to_rgb(index: u8) -> (u8, u8, u8) {
+  NES_PALETTE[(index & 0x3F) as usize]
+}
+
+// Color index with PPUMASK grayscale applied and the emphasis bits in bits 6-8, which is what
+// the PPU actually puts on the video signal (NTSC filters work from this rather than RGB)
+pub fn emphasized_index(index: u8, mask: u8) -> u16 {
+  let mut index = index & 0x3F;
+  if mask & MASK_GRAYSCALE != 0 {
+    index &= 0x30;
+  }
+  index as u16 | ((mask & MASK_EMPHASIS) as u16) << 1
+}
+
+// How PPUMASK color emphasis gets applied
+pub enum Emphasis {
+  Attenuate, // Dim the channels that aren't emphasized
+  Variants(Box<[[(u8, u8, u8); 64]; 8]>), // One full palette per emphasis combination (512 color .pal files)
+}
+
+pub struct Palette {
+  pub colors: [(u8, u8, u8); 64],
+  pub emphasis: Emphasis,
+}
+
+impl Palette {
+  // Palette constructor (standard 2C02 colors with attenuated emphasis)
+  pub fn new() -> Self {
+    Palette {
+      colors: NES_PALETTE,
+      emphasis: Emphasis::Attenuate,
+    }
+  }
+
+  // Build a palette from a .pal file, either 64 colors or 512 colors (8 emphasis variants)
+  pub fn from_pal(data: &[u8]) -> Result<Palette, String> {
+    let read_colors = |chunk: &[u8]| {
+      let mut colors = [(0u8, 0u8, 0u8); 64];
+      for (i, color) in colors.iter_mut().enumerate() {
+        *color = (chunk[i * 3], chunk[i * 3 + 1], chunk[i * 3 + 2]);
+      }
+      colors
+    };
+
+    match data.len() {
+      192 => Ok(Palette { colors: read_colors(data), emphasis: Emphasis::Attenuate }),
+      1536 => {
+        let mut variants = Box::new([[(0u8, 0u8, 0u8); 64]; 8]);
+        for (i, variant) in variants.iter_mut().enumerate() {
+          *variant = read_colors(&data[i * 192 .. (i + 1) * 192]);
+        }
+        Ok(Palette { colors: variants[0], emphasis: Emphasis::Variants(variants) })
+      },
+      len => Err(format!(".pal file should be 192 or 1536 bytes, got {}", len))
+    }
+  }
+
+  // Which emphasis approach this palette uses
+  pub fn emphasis(&self) -> &Emphasis {
+    &self.emphasis
+  }
+
+  // Resolve a palette index to RGB, applying PPUMASK grayscale and emphasis bits
+  pub fn resolve(&self, index: u8, mask: u8) -> (u8, u8, u8) {
+    let mut index = (index & 0x3F) as usize;
+    if mask & MASK_GRAYSCALE != 0 {
+      index &= 0x30;
+    }
+
+    let emphasis = ((mask & MASK_EMPHASIS) >> 5) as usize;
+    match &self.emphasis {
+      Emphasis::Variants(variants) => variants[emphasis][index],
+      Emphasis::Attenuate => {
+        let (r, g, b) = self.colors[index];
+        if emphasis == 0 {
+          return (r, g, b);
+        }
+        let dim = |channel: u8, emphasized: bool| {
+          if emphasized { channel } else { (channel as u16 * EMPHASIS_ATTENUATION / 256) as u8 }
+        };
+        (dim(r, emphasis & 0b001 != 0), dim(g, emphasis & 0b010 != 0), dim(b, emphasis & 0b100 != 0))
+      }
+    }
+  }
+}
+
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn test_palette_to_rgb() {
+    assert_eq!(palette_to_rgb(0x0F), (0, 0, 0));
+    assert_eq!(palette_to_rgb(0x00), (84, 84, 84));
+    assert_eq!(palette_to_rgb(0x21), (76, 154, 236));
+    assert_eq!(palette_to_rgb(0x30), (236, 238, 236));
+    // Only the low 6 bits select a color
+    assert_eq!(palette_to_rgb(0x41), palette_to_rgb(0x01));
+  }
+
+  #[test]
+  fn test_grayscale_masks_to_gray_column() {
+    let palette = Palette::new();
+
+    assert_eq!(palette.resolve(0x16, 0), (152, 34, 32));
+    assert_eq!(palette.resolve(0x16, MASK_GRAYSCALE), palette.resolve(0x10, 0));
+    assert_eq!(palette.resolve(0x2A, MASK_GRAYSCALE), palette.resolve(0x20, 0));
+  }
+
+  #[test]
+  fn test_emphasis_attenuates_other_channels() {
+    let palette = Palette::new();
+
+    // Red emphasis keeps red and dims green and blue
+    assert_eq!(palette.resolve(0x30, 0b0010_0000), (236, 194, 192));
+    // Green and blue emphasis dims only red
+    assert_eq!(palette.resolve(0x30, 0b1100_0000), (192, 238, 236));
+    // All three leaves the color alone
+    assert_eq!(palette.resolve(0x30, 0b1110_0000), (236, 238, 236));
+  }
+
+  #[test]
+  fn test_pal_file_variants_override_emphasis() {
+    let mut data = vec![];
+    for variant in 0..8u8 {
+      for _ in 0..64 {
+        data.extend([variant, variant, variant]);
+      }
+    }
+    let palette = Palette::from_pal(&data).unwrap();
+
+    assert!(matches!(palette.emphasis(), Emphasis::Variants(_)));
+    assert_eq!(palette.resolve(0x05, 0), (0, 0, 0));
+    assert_eq!(palette.resolve(0x05, 0b1010_0000), (5, 5, 5));
+  }
+
+  #[test]
+  fn test_pal_file_sizes() {
+    assert!(matches!(Palette::from_pal(&[0; 192]).unwrap().emphasis(), Emphasis::Attenuate));
+    assert!(Palette::from_pal(&[0; 100]).is_err());
+  }
+
+  #[test]
+  fn test_emphasized_index() {
+    assert_eq!(emphasized_index(0x16, 0), 0x016);
+    assert_eq!(emphasized_index(0x56, MASK_GRAYSCALE), 0x010);
+    assert_eq!(emphasized_index(0x16, 0b1010_0000), 0b101_010110);
+  }
+}
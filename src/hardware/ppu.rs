@@ -0,0 +1,1739 @@
+#![allow(unused)]
+/* The following code emulates the Picture Processing Unit (Ricoh 2C02) */
+
+use super::cartridge::Mirroring;
+use super::frame::{Frame, WIDTH};
+use super::palette::{self, Palette};
+
+pub const DOTS_PER_SCANLINE: u16 = 341;
+pub const SCANLINES_PER_FRAME: u16 = 262;
+const VISIBLE_SCANLINES: u16 = 240;
+const VBLANK_SCANLINE: u16 = 241;
+const PRE_RENDER_SCANLINE: u16 = 261;
+
+// What happened during a call to Ppu::tick
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PpuTickResult {
+  Nothing,
+  NmiTriggered, // Vblank started with NMI enabled in PPUCTRL
+  FrameComplete, // Wrapped from the pre-render line back to scanline 0
+}
+
+// One palette RAM entry, as stored and as it shows up on screen
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PaletteColor {
+  pub index: u8, // Raw NES color index held in palette RAM
+  pub rgb: (u8, u8, u8), // Color actually drawn, entry 0 of every palette draws the backdrop
+}
+
+// Whether a sprite draws over or under the background (OAM attribute bit 5)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SpritePriority {
+  Front,
+  Behind,
+}
+
+// One OAM entry decoded for inspection
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SpriteInfo {
+  pub x: u8,
+  pub y: u8, // As stored in OAM, the sprite shows up one scanline lower
+  pub tile: u8,
+  pub palette: u8, // Sprite palette 0-3 ($3F10 + palette * 4)
+  pub priority: SpritePriority,
+  pub flip_h: bool,
+  pub flip_v: bool,
+  pub on_screen: bool, // Y of $EF or more hides the sprite below the visible area
+}
+
+// A sprite picked by sprite evaluation for the next scanline
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SpriteData {
+  pub tile: u8,
+  pub x: u8,
+  pub attributes: u8,
+  pub row: u8, // Scanline minus the sprite's OAM Y, before any vertical flip
+}
+
+// Sprite layer output for one pixel
+#[derive(Clone, Copy)]
+struct SpritePixel {
+  pixel: u8, // 1-3, transparent pixels are never stored
+  palette: u8,
+  priority: SpritePriority,
+  sprite_zero: bool,
+}
+
+// PPUCTRL ($2000) bits
+const CTRL_VRAM_INCREMENT: u8 = 0b0000_0100;
+const CTRL_SPRITE_PATTERN_ADDR: u8 = 0b0000_1000;
+const CTRL_BACKGROUND_PATTERN_ADDR: u8 = 0b0001_0000;
+const CTRL_SPRITE_SIZE: u8 = 0b0010_0000;
+const CTRL_GENERATE_NMI: u8 = 0b1000_0000;
+
+// PPUMASK ($2001) bits
+const MASK_SHOW_BACKGROUND_LEFT: u8 = 0b0000_0010;
+const MASK_SHOW_SPRITES_LEFT: u8 = 0b0000_0100;
+const MASK_SHOW_BACKGROUND: u8 = 0b0000_1000;
+const MASK_SHOW_SPRITES: u8 = 0b0001_0000;
+
+// PPUSTATUS ($2002) bits
+const STATUS_SPRITE_OVERFLOW: u8 = 0b0010_0000;
+const STATUS_SPRITE_ZERO_HIT: u8 = 0b0100_0000;
+const STATUS_VBLANK: u8 = 0b1000_0000;
+
+pub struct Ppu {
+  pub chr_rom: Vec<u8>, // Pattern tables (CHR ROM from the cartridge, or 8KB of CHR RAM)
+  pub palette_table: [u8; 32],
+  pub vram: [u8; 0x1000], // 2KB internal VRAM (+2KB on the cartridge for four-screen games)
+  pub mirroring: Mirroring,
+  pub oam: [u8; 256],
+  pub oam_addr: u8,
+  pub ctrl: u8,
+  pub mask: u8,
+  pub status: u8,
+  v: u16, // Current VRAM address (loopy v): 0yyy NNYY YYYX XXXX
+  t: u16, // Temporary VRAM address (loopy t), same layout as v
+  fine_x: u8, // Fine X scroll (3 bits)
+  w: bool, // First/second write toggle shared by PPUSCROLL and PPUADDR, cleared by reading PPUSTATUS
+  read_buffer: u8, // PPUDATA reads return this, then refill it from v
+  pub scanline: u16,
+  pub dot: u16,
+  pub frame_count: u64,
+  pub frame: Frame,
+  pub palette: Palette,
+  pub cycles: u64, // Total dots since power on
+  sprite_zero_hit_cycle: Option<u64>,
+  nmi_interrupt: bool,
+  suppress_vblank: bool,
+  pending_sprite_zero_hit: Option<u16>, // Dot on the current scanline where sprite 0 will hit
+}
+
+impl Ppu {
+  // PPU constructor
+  pub fn new() -> Self {
+    let mut ppu = Ppu {
+      chr_rom: vec![0x00; 0x2000],
+      palette_table: [0x00; 32],
+      vram: [0x00; 0x1000],
+      mirroring: Mirroring::Horizontal,
+      oam: [0x00; 256],
+      oam_addr: 0,
+      ctrl: 0,
+      mask: 0,
+      status: 0,
+      v: 0,
+      t: 0,
+      fine_x: 0,
+      w: false,
+      read_buffer: 0,
+      scanline: 0,
+      dot: 0,
+      frame_count: 0,
+      frame: Frame::new(),
+      palette: Palette::new(),
+      cycles: 0,
+      sprite_zero_hit_cycle: None,
+      nmi_interrupt: false,
+      suppress_vblank: false,
+      pending_sprite_zero_hit: None,
+    };
+    ppu.oam_reset();
+    ppu
+  }
+
+  // Move every sprite off screen (Y = $FF never lands on a visible scanline)
+  pub fn oam_reset(&mut self) {
+    self.oam.fill(0xFF);
+  }
+
+  // Combine the two bitplane bytes of a tile row into 8 pixel values (0-3), leftmost pixel first
+  pub fn combine_bitplanes(lo: u8, hi: u8) -> [u8; 8] {
+    let mut pixels = [0u8; 8];
+    for (i, pixel) in pixels.iter_mut().enumerate() {
+      *pixel = ((lo >> (7 - i)) & 1) | (((hi >> (7 - i)) & 1) << 1);
+    }
+    pixels
+  }
+
+  // Palette RAM address for a pixel value (0-3) in a background or sprite palette (0-3),
+  // transparent pixels always use the universal background color at $3F00
+  pub fn apply_palette_index(pixel: u8, palette: u8, is_sprite: bool) -> u16 {
+    if pixel == 0 {
+      return 0x3F00;
+    }
+    let base = if is_sprite { 0x3F10 } else { 0x3F00 };
+    base + (palette & 0b11) as u16 * 4 + (pixel & 0b11) as u16
+  }
+
+  // Set PPUCTRL directly, without the NMI side effects of a $2000 write (for test setup)
+  pub fn set_ppuctrl(&mut self, v: u8) {
+    self.ctrl = v;
+  }
+
+  // Set PPUMASK directly (for test setup)
+  pub fn set_ppumask(&mut self, v: u8) {
+    self.mask = v;
+  }
+
+  // Set the scroll in t and fine X in one go instead of through the two-write protocol,
+  // keeping the nametable select bits already in t. Lands in v at the usual copy points
+  pub fn set_scroll(&mut self, coarse_x: u8, coarse_y: u8, fine_x: u8, fine_y: u8) {
+    self.t = (self.t & 0x0C00)
+      | ((fine_y & 0x07) as u16) << 12
+      | ((coarse_y & 0x1F) as u16) << 5
+      | (coarse_x & 0x1F) as u16;
+    self.fine_x = fine_x & 0x07;
+  }
+
+  // Handle CPU writes to the PPU registers ($2000-$2007)
+  pub fn write_register(&mut self, addr: u16, data: u8) {
+    match addr {
+      0x2000 => { self.write_to_ctrl(data); },
+      0x2001 => { self.mask = data; },
+      0x2003 => { self.oam_addr = data; },
+      0x2004 => { self.write_to_oam_data(data); },
+      0x2005 => { self.write_to_scroll(data); },
+      0x2006 => { self.write_to_ppu_addr(data); },
+      0x2007 => { self.write_to_data(data); },
+      _ => {} // PPUSTATUS is read-only
+    }
+  }
+
+  // Handle CPU reads from the PPU registers ($2000-$2007)
+  pub fn read_register(&mut self, addr: u16) -> u8 {
+    match addr {
+      0x2002 => self.read_status(),
+      0x2004 => self.oam[self.oam_addr as usize],
+      0x2007 => self.read_data(),
+      _ => 0 // Write-only or not implemented yet
+    }
+  }
+
+  // OAMDATA write, increments OAMADDR
+  fn write_to_oam_data(&mut self, data: u8) {
+    self.oam[self.oam_addr as usize] = data;
+    self.oam_addr = self.oam_addr.wrapping_add(1);
+  }
+
+  // Copy a full page into OAM starting at OAMADDR (used by OAM DMA at $4014)
+  pub fn write_oam_dma(&mut self, data: &[u8; 256]) {
+    for byte in data.iter() {
+      self.write_to_oam_data(*byte);
+    }
+  }
+
+  // PPUSCROLL write, X then Y. Coarse scroll goes into t (reaching v at the copy points during
+  // rendering), fine X takes effect straight away
+  fn write_to_scroll(&mut self, data: u8) {
+    if !self.w {
+      self.t = (self.t & !0x001F) | (data >> 3) as u16;
+      self.fine_x = data & 0x07;
+    }
+    else {
+      self.t = (self.t & !0x73E0) | ((data as u16 & 0x07) << 12) | ((data as u16 & 0xF8) << 2);
+    }
+    self.w = !self.w;
+  }
+
+  // PPUADDR write, high byte first, goes through t and lands in v on the second write
+  fn write_to_ppu_addr(&mut self, data: u8) {
+    if !self.w {
+      self.t = (self.t & 0x00FF) | (((data & 0x3F) as u16) << 8);
+    }
+    else {
+      self.t = (self.t & 0xFF00) | data as u16;
+      self.v = self.t;
+    }
+    self.w = !self.w;
+  }
+
+  // Step v after a PPUDATA access, across (32) or down (1) depending on PPUCTRL
+  fn increment_vram_addr(&mut self) {
+    let step = if self.ctrl & CTRL_VRAM_INCREMENT != 0 { 32 } else { 1 };
+    self.v = self.v.wrapping_add(step) & 0x7FFF;
+  }
+
+  // PPUDATA write
+  fn write_to_data(&mut self, data: u8) {
+    self.write_vram(self.v, data);
+    self.increment_vram_addr();
+  }
+
+  // PPUDATA read, delayed by one read through the internal buffer except for palette RAM,
+  // which answers right away (the buffer still picks up the nametable byte underneath)
+  fn read_data(&mut self) -> u8 {
+    let addr = self.v & 0x3FFF;
+    let data = if addr >= 0x3F00 {
+      self.read_buffer = self.read_vram(addr - 0x1000);
+      self.read_vram(addr)
+    }
+    else {
+      let buffered = self.read_buffer;
+      self.read_buffer = self.read_vram(addr);
+      buffered
+    };
+    self.increment_vram_addr();
+    data
+  }
+
+  // PPUCTRL write, the nametable select goes into t and enabling NMI while already in vblank
+  // fires one immediately
+  fn write_to_ctrl(&mut self, data: u8) {
+    let nmi_was_enabled = self.ctrl & CTRL_GENERATE_NMI != 0;
+    self.ctrl = data;
+    self.t = (self.t & !0x0C00) | ((data as u16 & 0x03) << 10);
+
+    let nmi_enabled = self.ctrl & CTRL_GENERATE_NMI != 0;
+    if !nmi_was_enabled && nmi_enabled && self.status & STATUS_VBLANK != 0 {
+      self.nmi_interrupt = true;
+    }
+  }
+
+  // PPUSTATUS read, clears vblank as a side effect
+  fn read_status(&mut self) -> u8 {
+    let data = self.status;
+    self.status &= !STATUS_VBLANK;
+    self.w = false;
+
+    // Reading right around the vblank set point races with it (approximation of the real timing)
+    if self.scanline == VBLANK_SCANLINE {
+      match self.dot {
+        0 => { self.suppress_vblank = true; }, // Flag never gets set and no NMI this frame
+        1 | 2 => { self.nmi_interrupt = false; }, // Flag reads as set but NMI is dropped
+        _ => {}
+      }
+    }
+
+    data
+  }
+
+  // Advance the PPU by the given number of dots and report NMI / end of frame
+  // (NMI wins if both happen in the same call)
+  pub fn tick(&mut self, ppu_cycles: u32) -> PpuTickResult {
+    let mut result = PpuTickResult::Nothing;
+    for _ in 0..ppu_cycles {
+      self.cycles += 1;
+      self.dot += 1;
+
+      // Odd frames skip the last dot of the pre-render line while rendering is enabled
+      if self.scanline == PRE_RENDER_SCANLINE && self.dot == DOTS_PER_SCANLINE - 1
+        && self.frame_count % 2 == 1 && self.rendering_enabled() {
+        self.dot = DOTS_PER_SCANLINE;
+      }
+      if self.dot == DOTS_PER_SCANLINE {
+        self.dot = 0;
+        self.scanline += 1;
+        if self.scanline == SCANLINES_PER_FRAME {
+          self.scanline = 0;
+          self.frame_count += 1;
+          if result == PpuTickResult::Nothing {
+            result = PpuTickResult::FrameComplete;
+          }
+        }
+      }
+
+      // The line is composed as its first pixel goes out, and a sprite 0 hit is flagged on the
+      // dot that outputs the overlapping pixel (pixel x is output on dot x + 1)
+      let rendering = self.rendering_enabled();
+      if self.scanline < VISIBLE_SCANLINES && self.dot == 1 {
+        let (colors, hit_x) = self.compose_scanline(self.scanline);
+        Ppu::draw_line(&mut self.frame, &self.palette, self.mask, self.scanline as usize, &colors);
+        self.pending_sprite_zero_hit = hit_x.map(|x| x as u16 + 1);
+      }
+      if self.scanline < VISIBLE_SCANLINES && self.pending_sprite_zero_hit == Some(self.dot) {
+        self.pending_sprite_zero_hit = None;
+        self.set_sprite_zero_hit();
+      }
+      if self.scanline < VISIBLE_SCANLINES && self.dot == 256 && rendering {
+        self.increment_y();
+      }
+      if self.scanline < VISIBLE_SCANLINES && (257 ..= 320).contains(&self.dot) && rendering {
+        self.sprite_fetch_dot();
+      }
+      if self.scanline == PRE_RENDER_SCANLINE {
+        self.pre_render_dot();
+      }
+
+      if self.scanline == VBLANK_SCANLINE && self.dot == 1 {
+        if !self.suppress_vblank {
+          self.status |= STATUS_VBLANK;
+          if self.ctrl & CTRL_GENERATE_NMI != 0 {
+            self.nmi_interrupt = true;
+            result = PpuTickResult::NmiTriggered;
+          }
+        }
+        self.suppress_vblank = false;
+      }
+    }
+
+    result
+  }
+
+  // Dots 257-320 fetch sprites for the next line, which resets OAMADDR and takes horizontal
+  // scroll back from t (the sprite layer itself is built when the line is composed)
+  fn sprite_fetch_dot(&mut self) {
+    if self.dot == 257 {
+      self.copy_horizontal_scroll();
+    }
+    self.oam_addr = 0;
+  }
+
+  // Per dot work on the pre-render line: clear the frame's flags at dot 1, then while rendering
+  // fetch sprites for scanline 0 and restore vertical scroll from t (dots 280-304)
+  fn pre_render_dot(&mut self) {
+    if self.dot == 1 {
+      self.status &= !(STATUS_VBLANK | STATUS_SPRITE_ZERO_HIT | STATUS_SPRITE_OVERFLOW);
+      self.sprite_zero_hit_cycle = None;
+    }
+    if !self.rendering_enabled() {
+      return;
+    }
+    if (257 ..= 320).contains(&self.dot) {
+      self.sprite_fetch_dot();
+    }
+    if (280 ..= 304).contains(&self.dot) {
+      self.copy_vertical_scroll();
+    }
+  }
+
+  // Run the rest of the pre-render line (scanline 261), ending on scanline 0 dot 0
+  pub fn pre_render_scanline(&mut self) {
+    while self.scanline == PRE_RENDER_SCANLINE {
+      self.tick(1);
+    }
+  }
+
+  // Move v down one pixel row, wrapping into the next nametable vertically after row 29
+  fn increment_y(&mut self) {
+    if self.v & 0x7000 != 0x7000 {
+      self.v += 0x1000; // fine Y
+      return;
+    }
+
+    self.v &= !0x7000;
+    let mut coarse_y = (self.v & 0x03E0) >> 5;
+    if coarse_y == 29 {
+      coarse_y = 0;
+      self.v ^= 0x0800;
+    }
+    else if coarse_y == 31 {
+      coarse_y = 0; // Out of bounds rows wrap without switching nametable
+    }
+    else {
+      coarse_y += 1;
+    }
+    self.v = (self.v & !0x03E0) | (coarse_y << 5);
+  }
+
+  // Copy coarse X and the horizontal nametable bit from t to v
+  fn copy_horizontal_scroll(&mut self) {
+    self.v = (self.v & !0x041F) | (self.t & 0x041F);
+  }
+
+  // Copy fine Y, coarse Y and the vertical nametable bit from t to v
+  fn copy_vertical_scroll(&mut self) {
+    self.v = (self.v & !0x7BE0) | (self.t & 0x7BE0);
+  }
+
+  // Copy pattern data into CHR starting at $0000 (anything past 8KB is dropped)
+  pub fn load_chr_data(&mut self, data: &[u8]) {
+    let len = data.len().min(0x2000);
+    self.chr_rom[..len].copy_from_slice(&data[..len]);
+  }
+
+  // Look up the tile id and its 2 bit attribute palette at a position in one of the four nametables
+  pub fn nametable_fetch(&self, coarse_x: u8, coarse_y: u8, nt_select: u8) -> (u8, u8) {
+    let name_table_base = 0x2000 + nt_select as u16 * 0x400;
+    let tile = self.read_vram(name_table_base + coarse_y as u16 * 32 + coarse_x as u16);
+
+    // Each attribute byte covers 4x4 tiles, two bits per 2x2 quadrant
+    let attribute = self.read_vram(name_table_base + 0x3C0 + (coarse_y / 4) as u16 * 8 + (coarse_x / 4) as u16);
+    let shift = ((coarse_y % 4) / 2 * 2 + (coarse_x % 4) / 2) * 2;
+    let palette = (attribute >> shift) & 0b11;
+
+    (tile, palette)
+  }
+
+  // Decode one row of a tile from the pattern table at pattern_base ($0000 or $1000)
+  fn tile_row(&self, pattern_base: u16, tile: u16, row: u16) -> [u8; 8] {
+    let lo = self.read_vram(pattern_base + tile * 16 + row);
+    let hi = self.read_vram(pattern_base + tile * 16 + row + 8);
+    Ppu::combine_bitplanes(lo, hi)
+  }
+
+  // Draw all 256 tiles of pattern table 0 or 1 as a 16x16 tile grid (128x128 pixels in the
+  // top left of the frame), coloring pixel values 0-3 with the given NES color indices
+  pub fn render_pattern_table(&self, which: usize, palette: [u8; 4]) -> Frame {
+    let mut frame = Frame::new();
+    let pattern_base = if which == 0 { 0x0000 } else { 0x1000 };
+
+    for tile in 0..256u16 {
+      let tile_x = (tile % 16) as usize * 8;
+      let tile_y = (tile / 16) as usize * 8;
+      for row in 0..8u16 {
+        for (x, pixel) in self.tile_row(pattern_base, tile, row).iter().enumerate() {
+          let color = self.palette.resolve(palette[*pixel as usize], 0);
+          frame.set_pixel(tile_x + x, tile_y + row as usize, color);
+        }
+      }
+    }
+
+    frame
+  }
+
+  // Draw the whole base nametable (PPUCTRL bits 0-1) as a static 256x240 frame, ignoring
+  // scroll, sprites and PPUMASK's show bits. Tiles come from the background pattern table and
+  // each 2x2 tile quadrant's attribute bits pick its palette
+  pub fn render_background(&self) -> Frame {
+    let mut frame = Frame::new();
+    let name_table = self.ctrl & 0b11;
+    let pattern_base: u16 = if self.ctrl & CTRL_BACKGROUND_PATTERN_ADDR != 0 { 0x1000 } else { 0x0000 };
+
+    for coarse_y in 0..(VISIBLE_SCANLINES / 8) as u8 {
+      for coarse_x in 0..(WIDTH / 8) as u8 {
+        let (tile, palette) = self.nametable_fetch(coarse_x, coarse_y, name_table);
+        for row in 0..8 {
+          for (x, pixel) in self.tile_row(pattern_base, tile as u16, row).iter().enumerate() {
+            let color = self.read_vram(Ppu::apply_palette_index(*pixel, palette, false));
+            let (frame_x, frame_y) = (coarse_x as usize * 8 + x, coarse_y as usize * 8 + row as usize);
+            frame.set_pixel(frame_x, frame_y, self.palette.resolve(color, self.mask));
+            frame.set_index(frame_x, frame_y, palette::emphasized_index(color, self.mask));
+          }
+        }
+      }
+    }
+
+    frame
+  }
+
+  // Color shown when rendering is off: the backdrop, or the palette entry v points at
+  fn backdrop_color(&self) -> u8 {
+    if self.v & 0x3F00 == 0x3F00 {
+      self.read_vram(self.v)
+    }
+    else {
+      self.palette_table[0]
+    }
+  }
+
+  // The four background palettes followed by the four sprite palettes, with mirroring applied
+  pub fn palettes(&self) -> [[PaletteColor; 4]; 8] {
+    let backdrop = self.read_vram(0x3F00);
+    let mut palettes = [[PaletteColor { index: 0, rgb: (0, 0, 0) }; 4]; 8];
+    for (palette, colors) in palettes.iter_mut().enumerate() {
+      for (entry, color) in colors.iter_mut().enumerate() {
+        let index = self.read_vram(0x3F00 + (palette * 4 + entry) as u16);
+        let shown = if entry == 0 { backdrop } else { index };
+        *color = PaletteColor { index, rgb: self.palette.resolve(shown, self.mask) };
+      }
+    }
+    palettes
+  }
+
+  // Background pixel value (0 = transparent) and attribute palette for each x on the current
+  // scanline, starting from v's scroll
+  fn background_line(&self) -> [(u8, u8); WIDTH] {
+    let mut line = [(0, 0); WIDTH];
+    let (coarse_x, coarse_y) = self.coarse_scroll();
+    let (fine_x, fine_y) = self.fine_scroll();
+    let pattern_base: u16 = if self.ctrl & CTRL_BACKGROUND_PATTERN_ADDR != 0 { 0x1000 } else { 0x0000 };
+
+    for (x, entry) in line.iter_mut().enumerate() {
+      if !self.show_background_at(x) {
+        continue;
+      }
+      let scroll_x = coarse_x as usize * 8 + fine_x as usize + x;
+      let tile_column = ((scroll_x / 8) % 32) as u16;
+      let name_table = ((self.v >> 10) & 0b11) as u8 ^ ((scroll_x / WIDTH) & 1) as u8;
+
+      let (tile, palette) = self.nametable_fetch(tile_column as u8, coarse_y, name_table);
+      let pixel = self.tile_row(pattern_base, tile as u16, fine_y as u16)[scroll_x % 8];
+      *entry = (pixel, palette);
+    }
+    line
+  }
+
+  // 8x8 or 8x16 sprites, from PPUCTRL
+  fn sprite_height(&self) -> u16 {
+    if self.ctrl & CTRL_SPRITE_SIZE != 0 { 16 } else { 8 }
+  }
+
+  // Row of OAM sprite `index` that lines up with scanline, if the sprite covers it
+  fn sprite_row(&self, index: usize, scanline: u16) -> Option<u16> {
+    let row = scanline.checked_sub(self.oam[index * 4] as u16)?;
+    if row < self.sprite_height() { Some(row) } else { None }
+  }
+
+  // Pick the first 8 sprites in OAM order that cover scanline (for drawing on the line after),
+  // setting sprite overflow if there were more
+  pub fn sprite_evaluation(&mut self, scanline: u16) -> [Option<SpriteData>; 8] {
+    let mut sprites = [None; 8];
+    let mut found = 0;
+    for index in 0..64 {
+      if let Some(row) = self.sprite_row(index, scanline) {
+        if found == sprites.len() {
+          self.status |= STATUS_SPRITE_OVERFLOW;
+          break;
+        }
+        let entry = &self.oam[index * 4 .. index * 4 + 4];
+        sprites[found] = Some(SpriteData { tile: entry[1], x: entry[3], attributes: entry[2], row: row as u8 });
+        found += 1;
+      }
+    }
+    sprites
+  }
+
+  // Pixels for one row of a sprite (0-7, or 0-15 for 8x16 sprites) with the attribute byte's
+  // flips applied
+  pub fn fetch_sprite_pattern(&self, tile: u8, row: u8, attributes: u8) -> [u8; 8] {
+    let height = self.sprite_height();
+    let tile = tile as u16;
+    let mut row = row as u16 % height;
+    if attributes & 0b1000_0000 != 0 {
+      row = height - 1 - row;
+    }
+
+    // 8x16 sprites pick their pattern table from bit 0 of the tile number, the bottom half is the next tile
+    let (pattern_base, tile) = if height == 16 {
+      ((tile & 1) * 0x1000, (tile & 0xFE) + row / 8)
+    }
+    else {
+      (if self.ctrl & CTRL_SPRITE_PATTERN_ADDR != 0 { 0x1000 } else { 0x0000 }, tile)
+    };
+    let mut pixels = self.tile_row(pattern_base, tile, row % 8);
+    if attributes & 0b0100_0000 != 0 {
+      pixels.reverse();
+    }
+    pixels
+  }
+
+  // Frontmost opaque sprite pixel for each x on a line, from the sprites evaluated for it
+  // (slot 0 holds sprite 0 whenever sprite 0 was in range)
+  fn sprite_line(&self, sprites: &[Option<SpriteData>; 8], sprite_zero_in_range: bool) -> [Option<SpritePixel>; WIDTH] {
+    let mut line = [None; WIDTH];
+
+    for (slot, sprite) in sprites.iter().enumerate() {
+      let sprite = match sprite {
+        Some(sprite) => sprite,
+        None => break
+      };
+      let (attributes, sprite_x) = (sprite.attributes, sprite.x as usize);
+      let pixels = self.fetch_sprite_pattern(sprite.tile, sprite.row, attributes);
+
+      for (i, pixel) in pixels.iter().enumerate() {
+        let x = sprite_x + i;
+        if x >= WIDTH || *pixel == 0 || line[x].is_some() || !self.show_sprites_at(x) {
+          continue;
+        }
+        line[x] = Some(SpritePixel {
+          pixel: *pixel,
+          palette: attributes & 0b11,
+          priority: if attributes & 0b0010_0000 != 0 { SpritePriority::Behind } else { SpritePriority::Front },
+          sprite_zero: slot == 0 && sprite_zero_in_range,
+        });
+      }
+    }
+    line
+  }
+
+  // Combine the background and sprite layers for one scanline into NES color indices, along
+  // with the first x where sprite 0 overlaps an opaque background pixel (a sprite 0 hit)
+  fn compose_scanline(&mut self, scanline: u16) -> ([u8; WIDTH], Option<usize>) {
+    if !self.rendering_enabled() {
+      return ([self.backdrop_color(); WIDTH], None);
+    }
+
+    let background = self.background_line();
+    // Sprites are evaluated a line ahead, so nothing is ever drawn on scanline 0
+    let sprites = match scanline.checked_sub(1) {
+      Some(previous) => {
+        let evaluated = self.sprite_evaluation(previous);
+        self.sprite_line(&evaluated, self.sprite_row(0, previous).is_some())
+      },
+      None => [None; WIDTH]
+    };
+    let mut colors = [0; WIDTH];
+    let mut hit_x = None;
+
+    for x in 0..WIDTH {
+      let (bg_pixel, bg_palette) = background[x];
+      let palette_addr = match sprites[x] {
+        Some(sprite) => {
+          if sprite.sprite_zero && bg_pixel != 0 && self.sprite_zero_hit_possible_at(x) && hit_x.is_none() {
+            hit_x = Some(x);
+          }
+          if sprite.priority == SpritePriority::Front || bg_pixel == 0 {
+            Ppu::apply_palette_index(sprite.pixel, sprite.palette, true)
+          }
+          else {
+            Ppu::apply_palette_index(bg_pixel, bg_palette, false)
+          }
+        },
+        None => Ppu::apply_palette_index(bg_pixel, bg_palette, false)
+      };
+      colors[x] = self.read_vram(palette_addr);
+    }
+    (colors, hit_x)
+  }
+
+  // Write a line of NES color indices into a frame, both as RGB and as raw indices
+  fn draw_line(frame: &mut Frame, palette: &Palette, mask: u8, y: usize, colors: &[u8; WIDTH]) {
+    for (x, color) in colors.iter().enumerate() {
+      frame.set_pixel(x, y, palette.resolve(*color, mask));
+      frame.set_index(x, y, palette::emphasized_index(*color, mask));
+    }
+  }
+
+  // Render one visible scanline (0-239) with background and sprites into the given frame,
+  // the background is fetched from wherever v currently points
+  pub fn render_scanline(&mut self, scanline: u16, frame: &mut Frame) {
+    let (colors, hit_x) = self.compose_scanline(scanline);
+    if hit_x.is_some() {
+      self.set_sprite_zero_hit();
+    }
+    Ppu::draw_line(frame, &self.palette, self.mask, scanline as usize, &colors);
+  }
+
+  // Map a nametable address ($2000-$3EFF) to an index into VRAM
+  // Horizontal:
+  //   [ A ] [ a ]
+  //   [ B ] [ b ]
+  // Vertical:
+  //   [ A ] [ B ]
+  //   [ a ] [ b ]
+  // FourScreen:
+  //   [ A ] [ B ]
+  //   [ C ] [ D ]
+  fn mirror_vram_addr(&self, addr: u16) -> u16 {
+    let vram_index = (addr & 0b0010_1111_1111_1111) - 0x2000; // $3000-$3EFF mirrors $2000-$2EFF
+    let name_table = vram_index / 0x400;
+    match (&self.mirroring, name_table) {
+      (Mirroring::Vertical, 2) | (Mirroring::Vertical, 3) => vram_index - 0x800,
+      (Mirroring::Horizontal, 1) | (Mirroring::Horizontal, 2) => vram_index - 0x400,
+      (Mirroring::Horizontal, 3) => vram_index - 0x800,
+      _ => vram_index
+    }
+  }
+
+  // Coarse scroll (coarse X, coarse Y) in tiles from the current VRAM address
+  pub fn coarse_scroll(&self) -> (u8, u8) {
+    ((self.v & 0x001F) as u8, ((self.v >> 5) & 0x001F) as u8)
+  }
+
+  // Fine scroll (fine X, fine Y) in pixels within the current tile
+  pub fn fine_scroll(&self) -> (u8, u8) {
+    (self.fine_x, ((self.v >> 12) & 0x07) as u8)
+  }
+
+  // Map a palette address ($3F00-$3FFF) to an index into palette RAM
+  // $3F10/$3F14/$3F18/$3F1C mirror $3F00/$3F04/$3F08/$3F0C (sprites share the backdrop color)
+  fn mirror_palette_addr(&self, addr: u16) -> usize {
+    let index = ((addr - 0x3F00) % 0x20) as usize;
+    if index >= 0x10 && index & 0b11 == 0 {
+      index - 0x10
+    }
+    else {
+      index
+    }
+  }
+
+  // Read from the PPU's own address space ($0000-$3FFF)
+  pub fn read_vram(&self, addr: u16) -> u8 {
+    let addr = addr & 0x3FFF;
+    match addr {
+      0x0000 ..= 0x1FFF => self.chr_rom[addr as usize],
+      0x2000 ..= 0x3EFF => self.vram[self.mirror_vram_addr(addr) as usize],
+      _ => self.palette_table[self.mirror_palette_addr(addr)]
+    }
+  }
+
+  // Write to the PPU's own address space ($0000-$3FFF)
+  pub fn write_vram(&mut self, addr: u16, data: u8) {
+    let addr = addr & 0x3FFF;
+    match addr {
+      0x0000 ..= 0x1FFF => { self.chr_rom[addr as usize] = data; },
+      0x2000 ..= 0x3EFF => { self.vram[self.mirror_vram_addr(addr) as usize] = data; },
+      _ => { self.palette_table[self.mirror_palette_addr(addr)] = data; }
+    }
+  }
+
+  // Returns true if background or sprite rendering is turned on in PPUMASK
+  pub fn rendering_enabled(&self) -> bool {
+    self.mask & (MASK_SHOW_BACKGROUND | MASK_SHOW_SPRITES) != 0
+  }
+
+  // Returns true if the background layer is drawn at column x (PPUMASK can clip the leftmost 8 pixels)
+  pub fn show_background_at(&self, x: usize) -> bool {
+    self.mask & MASK_SHOW_BACKGROUND != 0
+      && (x >= 8 || self.mask & MASK_SHOW_BACKGROUND_LEFT != 0)
+  }
+
+  // Returns true if the sprite layer is drawn at column x (PPUMASK can clip the leftmost 8 pixels)
+  pub fn show_sprites_at(&self, x: usize) -> bool {
+    self.mask & MASK_SHOW_SPRITES != 0
+      && (x >= 8 || self.mask & MASK_SHOW_SPRITES_LEFT != 0)
+  }
+
+  // Decode all 64 OAM entries in OAM order
+  pub fn sprites(&self) -> Vec<SpriteInfo> {
+    self.oam.chunks(4).map(|entry| {
+      let attributes = entry[2];
+      SpriteInfo {
+        x: entry[3],
+        y: entry[0],
+        tile: entry[1],
+        palette: attributes & 0b11,
+        priority: if attributes & 0b0010_0000 != 0 { SpritePriority::Behind } else { SpritePriority::Front },
+        flip_h: attributes & 0b0100_0000 != 0,
+        flip_v: attributes & 0b1000_0000 != 0,
+        on_screen: entry[0] < 0xEF,
+      }
+    }).collect()
+  }
+
+  // Sprite 0 hit needs both layers visible at x, and never happens on the last column
+  fn sprite_zero_hit_possible_at(&self, x: usize) -> bool {
+    self.show_background_at(x) && self.show_sprites_at(x) && x != 255
+  }
+
+  // Set the sprite 0 hit flag, remembering the PPU cycle it first happened on this frame
+  fn set_sprite_zero_hit(&mut self) {
+    if self.sprite_zero_hit_cycle.is_none() {
+      self.sprite_zero_hit_cycle = Some(self.cycles);
+    }
+    self.status |= STATUS_SPRITE_ZERO_HIT;
+  }
+
+  // PPU cycle (dots since power on) at which sprite 0 hit was first detected this frame
+  pub fn sprite_zero_hit_cycle(&self) -> Option<u64> {
+    self.sprite_zero_hit_cycle
+  }
+
+  // Returns true (once) if the PPU has asserted the NMI line
+  pub fn poll_nmi(&mut self) -> bool {
+    let nmi = self.nmi_interrupt;
+    self.nmi_interrupt = false;
+    nmi
+  }
+}
+
+impl Default for Ppu {
+  fn default() -> Self {
+    Ppu::new()
+  }
+}
+
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  // Number of dots from power on until the given position
+  fn dots_until(scanline: u16, dot: u16) -> u32 {
+    scanline as u32 * DOTS_PER_SCANLINE as u32 + dot as u32
+  }
+
+  #[test]
+  fn test_vblank_set_at_241_1() {
+    let mut ppu = Ppu::new();
+    ppu.ctrl = CTRL_GENERATE_NMI;
+
+    ppu.tick(dots_until(241, 0));
+    assert_eq!(ppu.status & STATUS_VBLANK, 0);
+    assert!(!ppu.poll_nmi());
+
+    assert_eq!(ppu.tick(1), PpuTickResult::NmiTriggered);
+    assert_ne!(ppu.status & STATUS_VBLANK, 0);
+    assert!(ppu.poll_nmi());
+    assert!(!ppu.poll_nmi());
+  }
+
+  #[test]
+  fn test_tick_signals_end_of_frame() {
+    let mut ppu = Ppu::new();
+
+    assert_eq!(ppu.tick(dots_until(261, 340)), PpuTickResult::Nothing);
+    assert_eq!(ppu.tick(1), PpuTickResult::FrameComplete);
+    assert_eq!((ppu.scanline, ppu.dot), (0, 0));
+    assert_eq!(ppu.frame_count, 1);
+  }
+
+  #[test]
+  fn test_vblank_without_nmi_enabled() {
+    let mut ppu = Ppu::new();
+    assert_eq!(ppu.tick(dots_until(241, 1)), PpuTickResult::Nothing);
+
+    assert_ne!(ppu.status & STATUS_VBLANK, 0);
+    assert!(!ppu.poll_nmi());
+  }
+
+  #[test]
+  fn test_vblank_cleared_on_pre_render_line() {
+    let mut ppu = Ppu::new();
+    ppu.tick(dots_until(261, 0));
+    assert_ne!(ppu.status & STATUS_VBLANK, 0);
+
+    ppu.tick(1);
+    assert_eq!(ppu.status & STATUS_VBLANK, 0);
+  }
+
+  #[test]
+  fn test_status_read_clears_vblank() {
+    let mut ppu = Ppu::new();
+    ppu.tick(dots_until(250, 0));
+
+    assert_ne!(ppu.read_register(0x2002) & STATUS_VBLANK, 0);
+    assert_eq!(ppu.read_register(0x2002) & STATUS_VBLANK, 0);
+  }
+
+  #[test]
+  fn test_enabling_nmi_during_vblank_fires_immediately() {
+    let mut ppu = Ppu::new();
+    ppu.tick(dots_until(245, 0));
+    assert!(!ppu.poll_nmi());
+
+    ppu.write_register(0x2000, CTRL_GENERATE_NMI);
+    assert!(ppu.poll_nmi());
+
+    // Rewriting with NMI still enabled doesn't fire again
+    ppu.write_register(0x2000, CTRL_GENERATE_NMI);
+    assert!(!ppu.poll_nmi());
+  }
+
+  #[test]
+  fn test_status_read_before_vblank_suppresses_it() {
+    let mut ppu = Ppu::new();
+    ppu.ctrl = CTRL_GENERATE_NMI;
+    ppu.tick(dots_until(241, 0));
+
+    ppu.read_register(0x2002);
+    ppu.tick(1);
+
+    assert_eq!(ppu.status & STATUS_VBLANK, 0);
+    assert!(!ppu.poll_nmi());
+  }
+
+  #[test]
+  fn test_status_read_on_vblank_set_drops_nmi() {
+    let mut ppu = Ppu::new();
+    ppu.ctrl = CTRL_GENERATE_NMI;
+    ppu.tick(dots_until(241, 1));
+
+    assert_ne!(ppu.read_register(0x2002) & STATUS_VBLANK, 0);
+    assert!(!ppu.poll_nmi());
+  }
+
+  #[test]
+  fn test_oam_dma_starts_at_oam_addr() {
+    let mut ppu = Ppu::new();
+    let mut page = [0u8; 256];
+    for (i, byte) in page.iter_mut().enumerate() {
+      *byte = i as u8;
+    }
+
+    ppu.write_register(0x2003, 0x10);
+    ppu.write_oam_dma(&page);
+
+    assert_eq!(ppu.oam[0x10], 0x00);
+    assert_eq!(ppu.oam[0xFF], 0xEF);
+    assert_eq!(ppu.oam[0x00], 0xF0);
+    assert_eq!(ppu.oam_addr, 0x10);
+  }
+
+  #[test]
+  fn test_sprite_zero_hit_cycle() {
+    let mut ppu = Ppu::new();
+    assert_eq!(ppu.sprite_zero_hit_cycle(), None);
+
+    ppu.tick(dots_until(30, 100));
+    ppu.set_sprite_zero_hit();
+    ppu.tick(10);
+    ppu.set_sprite_zero_hit();
+
+    // Only the first hit of the frame is recorded
+    assert_eq!(ppu.sprite_zero_hit_cycle(), Some(dots_until(30, 100) as u64));
+    assert_ne!(ppu.status & STATUS_SPRITE_ZERO_HIT, 0);
+
+    // Cleared along with the flag on the pre-render line
+    ppu.tick(dots_until(261, 1) - dots_until(30, 110));
+    assert_eq!(ppu.sprite_zero_hit_cycle(), None);
+    assert_eq!(ppu.status & STATUS_SPRITE_ZERO_HIT, 0);
+  }
+
+  // Tick one dot at a time until the next frame starts, returning how many dots it took
+  fn dots_in_frame(ppu: &mut Ppu) -> u32 {
+    let mut dots = 1;
+    while ppu.tick(1) != PpuTickResult::FrameComplete {
+      dots += 1;
+    }
+    dots
+  }
+
+  #[test]
+  fn test_odd_frame_skips_a_dot_when_rendering() {
+    let mut ppu = Ppu::new();
+    ppu.mask = MASK_SHOW_BACKGROUND;
+
+    assert_eq!(dots_in_frame(&mut ppu), 89342);
+    assert_eq!(dots_in_frame(&mut ppu), 89341);
+    assert_eq!(dots_in_frame(&mut ppu), 89342);
+
+    ppu.mask = MASK_SHOW_SPRITES;
+    assert_eq!(dots_in_frame(&mut ppu), 89341);
+  }
+
+  #[test]
+  fn test_odd_frame_full_length_when_not_rendering() {
+    let mut ppu = Ppu::new();
+
+    assert_eq!(dots_in_frame(&mut ppu), 89342);
+    assert_eq!(dots_in_frame(&mut ppu), 89342);
+  }
+
+  #[test]
+  fn test_horizontal_and_vertical_mirroring() {
+    let mut ppu = Ppu::new();
+    ppu.mirroring = Mirroring::Horizontal;
+    assert_eq!(ppu.mirror_vram_addr(0x2005), ppu.mirror_vram_addr(0x2405));
+    assert_eq!(ppu.mirror_vram_addr(0x2805), ppu.mirror_vram_addr(0x2C05));
+    assert_ne!(ppu.mirror_vram_addr(0x2005), ppu.mirror_vram_addr(0x2805));
+
+    ppu.mirroring = Mirroring::Vertical;
+    assert_eq!(ppu.mirror_vram_addr(0x2005), ppu.mirror_vram_addr(0x2805));
+    assert_eq!(ppu.mirror_vram_addr(0x2405), ppu.mirror_vram_addr(0x2C05));
+    assert_ne!(ppu.mirror_vram_addr(0x2005), ppu.mirror_vram_addr(0x2405));
+  }
+
+  #[test]
+  fn test_four_screen_nametables_are_distinct() {
+    let mut ppu = Ppu::new();
+    ppu.mirroring = Mirroring::FourScreen;
+
+    let addrs: Vec<u16> = [0x2005, 0x2405, 0x2805, 0x2C05].iter()
+      .map(|addr| ppu.mirror_vram_addr(*addr))
+      .collect();
+    assert_eq!(addrs, vec![0x005, 0x405, 0x805, 0xC05]);
+
+    for (i, addr) in addrs.iter().enumerate() {
+      ppu.vram[*addr as usize] = i as u8 + 1;
+    }
+    for (i, addr) in addrs.iter().enumerate() {
+      assert_eq!(ppu.vram[*addr as usize], i as u8 + 1);
+    }
+
+    // $3000-$3EFF still mirrors $2000-$2EFF
+    assert_eq!(ppu.mirror_vram_addr(0x3C05), 0xC05);
+  }
+
+  #[test]
+  fn test_scroll_decomposition() {
+    let mut ppu = Ppu::new();
+    // 0x041F is coarse X 31 in nametable 1 (bit 10 is nametable select, not coarse Y)
+    ppu.v = 0x041F;
+    ppu.fine_x = 5;
+
+    assert_eq!(ppu.coarse_scroll(), (31, 0));
+    assert_eq!(ppu.fine_scroll(), (5, 0));
+
+    // fine Y 3, nametable 2, coarse Y 29, coarse X 2
+    ppu.v = (3 << 12) | (0b10 << 10) | (29 << 5) | 2;
+    assert_eq!(ppu.coarse_scroll(), (2, 29));
+    assert_eq!(ppu.fine_scroll(), (5, 3));
+  }
+
+  #[test]
+  fn test_direct_register_setters() {
+    let mut ppu = Ppu::new();
+    ppu.set_ppuctrl(0x81);
+    ppu.set_ppumask(MASK_SHOW_BACKGROUND);
+    assert_eq!((ppu.ctrl, ppu.mask), (0x81, MASK_SHOW_BACKGROUND));
+
+    // Nametable select already in t (here from PPUADDR) survives set_scroll
+    ppu.write_register(0x2006, 0x04);
+    ppu.write_register(0x2006, 0x00);
+    ppu.set_scroll(31, 29, 5, 3);
+    assert_eq!(ppu.t, (3 << 12) | (0b01 << 10) | (29 << 5) | 31);
+
+    ppu.copy_horizontal_scroll();
+    ppu.copy_vertical_scroll();
+    assert_eq!(ppu.coarse_scroll(), (31, 29));
+    assert_eq!(ppu.fine_scroll(), (5, 3));
+  }
+
+  #[test]
+  fn test_scroll_registers_reach_v() {
+    let mut ppu = Ppu::new();
+    ppu.write_register(0x2000, 0x01); // Nametable 1
+    ppu.write_register(0x2005, (15 << 3) | 5); // Coarse X 15, fine X 5
+    ppu.write_register(0x2005, (11 << 3) | 6); // Coarse Y 11, fine Y 6
+    assert_eq!(ppu.t, (6 << 12) | (0b01 << 10) | (11 << 5) | 15);
+    ppu.mask = MASK_SHOW_BACKGROUND;
+
+    // Dot 257 brings back coarse X and the horizontal nametable bit
+    ppu.tick(dots_until(0, 258));
+    assert_eq!(ppu.coarse_scroll().0, 15);
+    assert_eq!(ppu.fine_scroll().0, 5);
+    assert_eq!(ppu.v & 0x0400, 0x0400);
+
+    // The pre-render line brings back the vertical part
+    ppu.tick(dots_until(261, 305) - dots_until(0, 258));
+    assert_eq!(ppu.coarse_scroll(), (15, 11));
+    assert_eq!(ppu.fine_scroll(), (5, 6));
+    assert_eq!(ppu.v & 0x0C00, 0x0400);
+  }
+
+  #[test]
+  fn test_status_read_resets_write_toggle() {
+    let mut ppu = Ppu::new();
+    ppu.w = true;
+
+    ppu.read_register(0x2002);
+    assert!(!ppu.w);
+  }
+
+  #[test]
+  fn test_scroll_and_address_share_write_toggle() {
+    let mut ppu = Ppu::new();
+    // A lone PPUSCROLL write leaves the toggle set, so the next PPUADDR write is taken as the low byte
+    ppu.write_register(0x2005, 0x00);
+    ppu.write_register(0x2006, 0x34);
+    assert!(!ppu.w);
+    assert_eq!(ppu.v & 0x00FF, 0x34);
+
+    // Reading PPUSTATUS in between resets it, so PPUADDR starts from the high byte again
+    ppu.write_register(0x2005, 0x00);
+    ppu.read_register(0x2002);
+    ppu.write_register(0x2006, 0x21);
+    ppu.write_register(0x2006, 0x08);
+    assert_eq!(ppu.v, 0x2108);
+  }
+
+  #[test]
+  fn test_left_edge_clipping() {
+    let mut ppu = Ppu::new();
+    ppu.mask = MASK_SHOW_BACKGROUND | MASK_SHOW_SPRITES;
+    assert!(!ppu.show_background_at(0));
+    assert!(!ppu.show_background_at(7));
+    assert!(ppu.show_background_at(8));
+    assert!(!ppu.show_sprites_at(3));
+    assert!(ppu.show_sprites_at(8));
+
+    ppu.mask |= MASK_SHOW_BACKGROUND_LEFT;
+    assert!(ppu.show_background_at(0));
+    assert!(!ppu.show_sprites_at(0));
+
+    ppu.mask |= MASK_SHOW_SPRITES_LEFT;
+    assert!(ppu.show_sprites_at(0));
+
+    // Left column bits do nothing while the layer itself is off
+    ppu.mask = MASK_SHOW_BACKGROUND_LEFT | MASK_SHOW_SPRITES_LEFT;
+    assert!(!ppu.show_background_at(0));
+    assert!(!ppu.show_sprites_at(0));
+  }
+
+  #[test]
+  fn test_sprite_zero_hit_not_possible_in_clipped_region() {
+    let mut ppu = Ppu::new();
+    ppu.mask = MASK_SHOW_BACKGROUND | MASK_SHOW_SPRITES;
+    assert!(!ppu.sprite_zero_hit_possible_at(3));
+    assert!(ppu.sprite_zero_hit_possible_at(8));
+    assert!(!ppu.sprite_zero_hit_possible_at(255));
+
+    ppu.mask |= MASK_SHOW_BACKGROUND_LEFT | MASK_SHOW_SPRITES_LEFT;
+    assert!(ppu.sprite_zero_hit_possible_at(3));
+
+    // Clipping only one of the layers is enough to block the hit
+    ppu.mask &= !MASK_SHOW_SPRITES_LEFT;
+    assert!(!ppu.sprite_zero_hit_possible_at(3));
+  }
+
+  #[test]
+  fn test_combine_bitplanes() {
+    assert_eq!(Ppu::combine_bitplanes(0xFF, 0x00), [1; 8]);
+    assert_eq!(Ppu::combine_bitplanes(0x00, 0xFF), [2; 8]);
+    assert_eq!(Ppu::combine_bitplanes(0xFF, 0xFF), [3; 8]);
+    assert_eq!(Ppu::combine_bitplanes(0x00, 0x00), [0; 8]);
+    assert_eq!(Ppu::combine_bitplanes(0b1010_0001, 0b0110_0001), [1, 2, 3, 0, 0, 0, 0, 3]);
+  }
+
+  #[test]
+  fn test_palette_backdrop_mirrors() {
+    let mut ppu = Ppu::new();
+
+    ppu.write_vram(0x3F10, 0x21);
+    assert_eq!(ppu.read_vram(0x3F00), 0x21);
+
+    ppu.write_vram(0x3F00, 0x0F);
+    assert_eq!(ppu.read_vram(0x3F10), 0x0F);
+
+    ppu.write_vram(0x3F1C, 0x30);
+    assert_eq!(ppu.read_vram(0x3F0C), 0x30);
+
+    // Other sprite palette entries are their own
+    ppu.write_vram(0x3F11, 0x16);
+    assert_eq!(ppu.read_vram(0x3F01), 0x00);
+  }
+
+  #[test]
+  fn test_palettes_accessor() {
+    let mut ppu = Ppu::new();
+    ppu.write_vram(0x3F00, 0x0F);
+    ppu.write_vram(0x3F04, 0x16);
+    ppu.write_vram(0x3F05, 0x30);
+    ppu.write_vram(0x3F1C, 0x21); // Mirrors $3F0C
+
+    let palettes = ppu.palettes();
+    assert_eq!(palettes[1][1], PaletteColor { index: 0x30, rgb: (236, 238, 236) });
+    // $3F04 keeps its own value but still draws as the backdrop
+    assert_eq!(palettes[1][0], PaletteColor { index: 0x16, rgb: (0, 0, 0) });
+    assert_eq!(palettes[3][0].index, 0x21);
+    assert_eq!(palettes[7][0].index, 0x21);
+    assert_eq!(palettes[4][0], PaletteColor { index: 0x0F, rgb: (0, 0, 0) });
+  }
+
+  #[test]
+  fn test_palette_repeats_every_32_bytes() {
+    let mut ppu = Ppu::new();
+    ppu.write_vram(0x3F00, 0x0F);
+    ppu.write_vram(0x3F05, 0x27);
+
+    assert_eq!(ppu.read_vram(0x3F20), 0x0F);
+    assert_eq!(ppu.read_vram(0x3FE5), 0x27);
+
+    ppu.write_vram(0x3FF0, 0x12);
+    assert_eq!(ppu.read_vram(0x3F00), 0x12);
+  }
+
+  // Fill every nametable with a solid tile of color 1 drawn in white over a black backdrop
+  fn solid_scene(ppu: &mut Ppu) {
+    let mut chr = [0x00; 32];
+    chr[16 .. 24].copy_from_slice(&[0xFF; 8]);
+    ppu.load_chr_data(&chr);
+    for addr in 0x2000..0x23C0 {
+      ppu.write_vram(addr, 0x01);
+    }
+    ppu.write_vram(0x3F00, 0x0F);
+    ppu.write_vram(0x3F01, 0x30);
+    ppu.mask = MASK_SHOW_BACKGROUND | MASK_SHOW_BACKGROUND_LEFT;
+  }
+
+  #[test]
+  fn test_load_chr_data_tiles_render() {
+    let mut ppu = Ppu::new();
+    let mut chr = vec![0x00; 48];
+    chr[16 .. 24].copy_from_slice(&[0xFF; 8]); // Tile 1: low plane only, color 1
+    chr[40 .. 48].copy_from_slice(&[0xFF; 8]); // Tile 2: high plane only, color 2
+    ppu.load_chr_data(&chr);
+    assert_eq!(ppu.read_vram(0x0010), 0xFF);
+
+    ppu.write_vram(0x2000, 0x01);
+    ppu.write_vram(0x2001, 0x02);
+    ppu.write_vram(0x3F00, 0x0F);
+    ppu.write_vram(0x3F01, 0x30);
+    ppu.write_vram(0x3F02, 0x16);
+    ppu.mask = MASK_SHOW_BACKGROUND | MASK_SHOW_BACKGROUND_LEFT;
+
+    ppu.tick(dots_until(0, 256));
+    let color1 = ppu.palette.resolve(0x30, 0);
+    let color2 = ppu.palette.resolve(0x16, 0);
+    assert!((0..8).all(|x| ppu.frame.get_pixel(x, 0) == color1));
+    assert!((8..16).all(|x| ppu.frame.get_pixel(x, 0) == color2));
+    assert_eq!(ppu.frame.get_pixel(16, 0), ppu.palette.resolve(0x0F, 0));
+
+    // Oversized data only fills the 8KB pattern tables
+    ppu.load_chr_data(&[0xAA; 0x3000]);
+    assert_eq!(ppu.chr_rom.len(), 0x2000);
+  }
+
+  #[test]
+  fn test_rendering_disabled_mid_frame_shows_backdrop() {
+    let mut ppu = Ppu::new();
+    solid_scene(&mut ppu);
+    let white = ppu.palette.resolve(0x30, 0);
+    let black = ppu.palette.resolve(0x0F, 0);
+
+    ppu.tick(dots_until(100, 0));
+    ppu.write_register(0x2001, 0);
+    ppu.tick(30 * 341);
+    ppu.write_register(0x2001, MASK_SHOW_BACKGROUND | MASK_SHOW_BACKGROUND_LEFT);
+    while ppu.tick(1) != PpuTickResult::FrameComplete {}
+
+    for y in [0, 99, 130, 239] {
+      assert_eq!(ppu.frame.get_pixel(0, y), white, "row {}", y);
+      assert_eq!(ppu.frame.get_pixel(255, y), white, "row {}", y);
+    }
+    for y in [100, 115, 129] {
+      assert_eq!(ppu.frame.get_pixel(0, y), black, "row {}", y);
+      assert_eq!(ppu.frame.get_pixel(255, y), black, "row {}", y);
+    }
+
+    // Scroll is restored from t on the pre-render line, so the next frame starts clean
+    assert_eq!(ppu.v, ppu.t);
+    while ppu.tick(1) != PpuTickResult::FrameComplete {}
+    assert!((0..240).all(|y| ppu.frame.get_pixel(128, y) == white));
+    assert_eq!(ppu.frame_count, 2);
+  }
+
+  #[test]
+  fn test_rendering_disabled_uses_palette_color_at_v() {
+    let mut ppu = Ppu::new();
+    solid_scene(&mut ppu);
+    ppu.mask = 0;
+    ppu.write_vram(0x3F05, 0x16);
+    ppu.v = 0x3F05;
+
+    ppu.tick(dots_until(0, 256));
+    assert_eq!(ppu.frame.get_pixel(10, 0), ppu.palette.resolve(0x16, 0));
+  }
+
+  #[test]
+  fn test_scroll_only_advances_while_rendering() {
+    let mut ppu = Ppu::new();
+    ppu.tick(dots_until(10, 300));
+    assert_eq!(ppu.v, 0);
+
+    ppu.mask = MASK_SHOW_BACKGROUND;
+    ppu.tick(dots_until(1, 0));
+    // One increment at dot 256 of scanline 11
+    assert_eq!(ppu.fine_scroll(), (0, 1));
+  }
+
+  #[test]
+  fn test_render_pattern_table() {
+    let mut ppu = Ppu::new();
+    // Tile 1 of table 0: diagonal line of color 3
+    for row in 0..8 {
+      ppu.chr_rom[16 + row] = 0b1000_0000 >> row;
+      ppu.chr_rom[16 + 8 + row] = 0b1000_0000 >> row;
+    }
+    // Last tile of table 1: solid color 2
+    for row in 0..8 {
+      ppu.chr_rom[0x1FF0 + 8 + row] = 0xFF;
+    }
+    let palette = [0x0F, 0x16, 0x2A, 0x30];
+    let color = |index: u8| ppu.palette.resolve(index, 0);
+
+    let table_0 = ppu.render_pattern_table(0, palette);
+    for i in 0..8 {
+      assert_eq!(table_0.get_pixel(8 + i, i), color(0x30));
+      assert_eq!(table_0.get_pixel(8 + (i + 1) % 8, i), color(0x0F));
+    }
+    assert_eq!(table_0.get_pixel(127, 127), color(0x0F));
+
+    let table_1 = ppu.render_pattern_table(1, palette);
+    assert_eq!(table_1.get_pixel(120, 120), color(0x2A));
+    assert_eq!(table_1.get_pixel(127, 127), color(0x2A));
+    assert_eq!(table_1.get_pixel(8, 0), color(0x0F));
+
+    // Only the decoded tiles differ from the backdrop anywhere in the 128x128 region
+    let drawn_pixels = |frame: &Frame| (0..128).flat_map(|y| (0..128).map(move |x| (x, y)))
+      .filter(|(x, y)| frame.get_pixel(*x, *y) != color(0x0F))
+      .count();
+    assert_eq!(drawn_pixels(&table_0), 8);
+    assert_eq!(drawn_pixels(&table_1), 64);
+  }
+
+  #[test]
+  fn test_render_background() {
+    let mut ppu = Ppu::new();
+    // Tile 1 of table 0: left half color 1, right half color 2
+    for row in 0..8 {
+      ppu.chr_rom[16 + row] = 0b1111_0000;
+      ppu.chr_rom[16 + 8 + row] = 0b0000_1111;
+    }
+    for i in 0..0x3C0 {
+      ppu.write_vram(0x2000 + i, 0x01);
+    }
+    // Bottom right quadrant of the first attribute block uses palette 2, the rest palette 0
+    ppu.write_vram(0x23C0, 0b1000_0000);
+    for (addr, color) in [(0x3F00, 0x0F), (0x3F01, 0x16), (0x3F02, 0x2A), (0x3F09, 0x11), (0x3F0A, 0x30)] {
+      ppu.write_vram(addr, color);
+    }
+    let color = |index: u8| ppu.palette.resolve(index, 0);
+
+    let frame = ppu.render_background();
+    assert_eq!(frame.get_pixel(0, 0), color(0x16));
+    assert_eq!(frame.get_pixel(7, 7), color(0x2A));
+    assert_eq!(frame.get_pixel(255, 239), color(0x2A));
+    assert_eq!(frame.get_pixel(16, 16), color(0x11));
+    assert_eq!(frame.get_pixel(31, 31), color(0x30));
+    assert_eq!(frame.get_pixel(32, 16), color(0x16));
+    assert_eq!(frame.indices[0], 0x16);
+
+    // PPUCTRL picks the nametable and the pattern table
+    let backdrop = color(0x0F);
+    ppu.write_register(0x2000, 0b0001_0001);
+    assert_eq!(ppu.render_background().get_pixel(100, 100), backdrop);
+  }
+
+  #[test]
+  fn test_nametable_fetch_attribute_quadrants() {
+    let mut ppu = Ppu::new();
+    ppu.mirroring = Mirroring::FourScreen;
+    // Attribute byte for tiles (4-7, 8-11) of nametable 2: quadrants TL=0, TR=1, BL=2, BR=3
+    ppu.write_vram(0x2800 + 0x3C0 + 2 * 8 + 1, 0b11_10_01_00);
+    for (coarse_x, coarse_y, tile) in [(4, 8, 0x10), (6, 8, 0x11), (5, 10, 0x12), (7, 11, 0x13)] {
+      ppu.write_vram(0x2800 + coarse_y as u16 * 32 + coarse_x as u16, tile);
+    }
+
+    assert_eq!(ppu.nametable_fetch(4, 8, 2), (0x10, 0));
+    assert_eq!(ppu.nametable_fetch(6, 8, 2), (0x11, 1));
+    assert_eq!(ppu.nametable_fetch(5, 10, 2), (0x12, 2));
+    assert_eq!(ppu.nametable_fetch(7, 11, 2), (0x13, 3));
+
+    // Same position in another nametable is untouched
+    assert_eq!(ppu.nametable_fetch(7, 11, 0), (0x00, 0));
+  }
+
+  #[test]
+  fn test_sprites_decodes_oam() {
+    let mut ppu = Ppu::new();
+    let mut page = [0xFF; 256];
+    page[0 .. 4].copy_from_slice(&[0x20, 0x05, 0b0000_0010, 0x40]);
+    page[4 .. 8].copy_from_slice(&[0x00, 0x06, 0b1110_0001, 0xF8]);
+    ppu.write_oam_dma(&page);
+
+    let sprites = ppu.sprites();
+    assert_eq!(sprites.len(), 64);
+    assert_eq!(sprites[0], SpriteInfo {
+      x: 0x40, y: 0x20, tile: 0x05, palette: 2,
+      priority: SpritePriority::Front, flip_h: false, flip_v: false, on_screen: true,
+    });
+    assert_eq!(sprites[1], SpriteInfo {
+      x: 0xF8, y: 0x00, tile: 0x06, palette: 1,
+      priority: SpritePriority::Behind, flip_h: true, flip_v: true, on_screen: true,
+    });
+    assert!(!sprites[2].on_screen);
+  }
+
+  // Solid background of color 1 (white) with one 8x8 sprite of color 1 drawn in red
+  fn sprite_scene(ppu: &mut Ppu, sprite: [u8; 4]) {
+    solid_scene(ppu);
+    ppu.write_vram(0x3F11, 0x16);
+    ppu.oam_reset();
+    ppu.oam[0 .. 4].copy_from_slice(&sprite);
+    ppu.mask |= MASK_SHOW_SPRITES | MASK_SHOW_SPRITES_LEFT;
+  }
+
+  #[test]
+  fn test_render_scanline_sprite_priority() {
+    let mut ppu = Ppu::new();
+    let white = ppu.palette.resolve(0x30, 0);
+    let red = ppu.palette.resolve(0x16, 0);
+    let mut frame = Frame::new();
+
+    // Tile 1 at x=16, top row on scanline 10
+    sprite_scene(&mut ppu, [9, 0x01, 0b0000_0000, 16]);
+    ppu.render_scanline(10, &mut frame);
+    assert_eq!(frame.get_pixel(15, 10), white);
+    assert!((16..24).all(|x| frame.get_pixel(x, 10) == red));
+    assert_eq!(frame.get_pixel(24, 10), white);
+
+    // Nothing on the line above the sprite
+    ppu.render_scanline(9, &mut frame);
+    assert_eq!(frame.get_pixel(16, 9), white);
+
+    // Behind an opaque background the sprite is hidden
+    sprite_scene(&mut ppu, [9, 0x01, 0b0010_0000, 16]);
+    ppu.render_scanline(10, &mut frame);
+    assert_eq!(frame.get_pixel(16, 10), white);
+
+    // ...but shows through a transparent one
+    ppu.write_vram(0x2002, 0x00); // Background comes from v, which is still at the top left
+    ppu.render_scanline(10, &mut frame);
+    assert_eq!(frame.get_pixel(16, 10), red);
+  }
+
+  #[test]
+  fn test_oam_reset_hides_all_sprites() {
+    let mut ppu = Ppu::new();
+    assert!(ppu.oam.iter().all(|byte| *byte == 0xFF));
+
+    sprite_scene(&mut ppu, [9, 0x01, 0b0000_0000, 16]);
+    ppu.oam[4 .. 8].copy_from_slice(&[100, 0x01, 0b0000_0000, 200]);
+    ppu.oam_reset();
+
+    let mut frame = Frame::new();
+    let white = ppu.palette.resolve(0x30, 0);
+    for scanline in 0..VISIBLE_SCANLINES {
+      assert!(ppu.sprite_evaluation(scanline).iter().all(|sprite| sprite.is_none()));
+      ppu.render_scanline(scanline, &mut frame);
+      assert!((0..WIDTH).all(|x| frame.get_pixel(x, scanline as usize) == white));
+    }
+    assert_eq!(ppu.status & STATUS_SPRITE_OVERFLOW, 0);
+  }
+
+  #[test]
+  fn test_render_scanline_sprite_flips_and_limit() {
+    let mut ppu = Ppu::new();
+    let red = ppu.palette.resolve(0x16, 0);
+    let mut frame = Frame::new();
+    let red_columns = |frame: &Frame, y: usize| -> Vec<usize> {
+      (0..WIDTH).filter(|x| frame.get_pixel(*x, y) == red).collect()
+    };
+
+    // Tile 2: row 0 is a single pixel on the left, row 7 is solid
+    sprite_scene(&mut ppu, [9, 0x02, 0b0000_0000, 16]);
+    ppu.chr_rom[32] = 0b1000_0000;
+    ppu.chr_rom[39] = 0xFF;
+    ppu.render_scanline(10, &mut frame);
+    ppu.render_scanline(17, &mut frame);
+    assert_eq!(red_columns(&frame, 10), vec![16]);
+    assert_eq!(red_columns(&frame, 17), (16..24).collect::<Vec<_>>());
+
+    // Horizontal flip mirrors the row, vertical flip swaps the top and bottom rows
+    ppu.oam[2] = 0b0100_0000;
+    ppu.render_scanline(10, &mut frame);
+    assert_eq!(red_columns(&frame, 10), vec![23]);
+    ppu.oam[2] = 0b1000_0000;
+    ppu.render_scanline(10, &mut frame);
+    ppu.render_scanline(17, &mut frame);
+    assert_eq!(red_columns(&frame, 10), (16..24).collect::<Vec<_>>());
+    assert_eq!(red_columns(&frame, 17), vec![16]);
+    ppu.oam[2] = 0b1100_0000;
+    ppu.render_scanline(17, &mut frame);
+    assert_eq!(red_columns(&frame, 17), vec![23]);
+
+    // Only the first 8 sprites on a line are drawn
+    for sprite in 0..9 {
+      ppu.oam[sprite * 4 .. sprite * 4 + 4].copy_from_slice(&[16, 0x02, 0b1000_0000, sprite as u8 * 10]);
+    }
+    ppu.render_scanline(17, &mut frame);
+    assert_eq!(red_columns(&frame, 17), (0..8).flat_map(|sprite| sprite * 10 .. sprite * 10 + 8).collect::<Vec<_>>());
+  }
+
+  #[test]
+  fn test_render_scanline_sprite_zero_hit() {
+    let mut ppu = Ppu::new();
+    let mut frame = Frame::new();
+
+    // Behind-background sprite 0 still triggers a hit
+    sprite_scene(&mut ppu, [9, 0x01, 0b0010_0000, 16]);
+    ppu.render_scanline(10, &mut frame);
+    assert_ne!(ppu.status & STATUS_SPRITE_ZERO_HIT, 0);
+
+    // No hit over a transparent background
+    ppu.status = 0;
+    for addr in 0x2000..0x23C0 {
+      ppu.write_vram(addr, 0x00);
+    }
+    ppu.render_scanline(10, &mut frame);
+    assert_eq!(ppu.status & STATUS_SPRITE_ZERO_HIT, 0);
+  }
+
+  #[test]
+  fn test_render_scanline_from_tick() {
+    let mut ppu = Ppu::new();
+    sprite_scene(&mut ppu, [9, 0x01, 0b0000_0000, 16]);
+
+    ppu.tick(dots_until(10, 256));
+    assert_eq!(ppu.frame.get_pixel(16, 10), ppu.palette.resolve(0x16, 0));
+    assert_eq!(ppu.sprite_zero_hit_cycle(), Some(dots_until(10, 17) as u64));
+  }
+
+  #[test]
+  fn test_sprite_zero_hit_flag_timing() {
+    // Pixel 16 goes out on dot 17, so PPUSTATUS only shows the hit from then on
+    let mut ppu = Ppu::new();
+    sprite_scene(&mut ppu, [9, 0x01, 0b0000_0000, 16]);
+    ppu.tick(dots_until(10, 16));
+    assert_eq!(ppu.status & STATUS_SPRITE_ZERO_HIT, 0);
+    ppu.tick(1);
+    assert_ne!(ppu.read_register(0x2002) & STATUS_SPRITE_ZERO_HIT, 0);
+
+    // With the left 8 pixels clipped, a sprite at x = 4 first hits at pixel 8 (dot 9)
+    let mut ppu = Ppu::new();
+    sprite_scene(&mut ppu, [9, 0x01, 0b0000_0000, 4]);
+    ppu.mask = MASK_SHOW_BACKGROUND | MASK_SHOW_SPRITES;
+    ppu.tick(dots_until(10, 8));
+    assert_eq!(ppu.status & STATUS_SPRITE_ZERO_HIT, 0);
+    ppu.tick(1);
+    assert_eq!(ppu.sprite_zero_hit_cycle(), Some(dots_until(10, 9) as u64));
+  }
+
+  #[test]
+  fn test_ppudata_reads_are_buffered() {
+    let mut ppu = Ppu::new();
+    ppu.write_vram(0x2305, 0x11);
+    ppu.write_vram(0x2306, 0x22);
+    ppu.write_vram(0x2307, 0x33);
+
+    ppu.write_register(0x2006, 0x23);
+    ppu.write_register(0x2006, 0x05);
+    assert_eq!(ppu.read_register(0x2007), 0x00); // Stale buffer
+    assert_eq!(ppu.read_register(0x2007), 0x11);
+    assert_eq!(ppu.read_register(0x2007), 0x22);
+    assert_eq!(ppu.read_register(0x2007), 0x33);
+  }
+
+  #[test]
+  fn test_ppudata_palette_reads_are_immediate() {
+    let mut ppu = Ppu::new();
+    ppu.write_vram(0x3F01, 0x2A);
+    ppu.write_vram(0x2F01, 0x44); // Nametable byte underneath $3F01
+
+    ppu.write_register(0x2006, 0x3F);
+    ppu.write_register(0x2006, 0x01);
+    assert_eq!(ppu.read_register(0x2007), 0x2A);
+
+    // The buffer was filled from the nametable underneath
+    ppu.write_register(0x2006, 0x20);
+    ppu.write_register(0x2006, 0x00);
+    assert_eq!(ppu.read_register(0x2007), 0x44);
+  }
+
+  #[test]
+  fn test_ppudata_write_increment() {
+    let mut ppu = Ppu::new();
+    ppu.write_register(0x2006, 0x20);
+    ppu.write_register(0x2006, 0x00);
+    ppu.write_register(0x2007, 0x01);
+    ppu.write_register(0x2007, 0x02);
+
+    ppu.write_register(0x2000, CTRL_VRAM_INCREMENT);
+    ppu.write_register(0x2007, 0x03);
+    ppu.write_register(0x2007, 0x04);
+
+    assert_eq!(ppu.read_vram(0x2000), 0x01);
+    assert_eq!(ppu.read_vram(0x2001), 0x02);
+    assert_eq!(ppu.read_vram(0x2002), 0x03);
+    assert_eq!(ppu.read_vram(0x2022), 0x04);
+  }
+
+  #[test]
+  fn test_nametable_and_palette_through_registers() {
+    let mut ppu = Ppu::new();
+    ppu.mirroring = Mirroring::Vertical;
+
+    // Write "HI" into nametable 1 through PPUADDR/PPUDATA
+    ppu.write_register(0x2006, 0x24);
+    ppu.write_register(0x2006, 0x42);
+    ppu.write_register(0x2007, 0x48);
+    ppu.write_register(0x2007, 0x49);
+
+    // Read it back through the mirror at $2C42 (vertical mirroring pairs $2400 with $2C00)
+    ppu.write_register(0x2006, 0x2C);
+    ppu.write_register(0x2006, 0x42);
+    ppu.read_register(0x2007);
+    assert_eq!(ppu.read_register(0x2007), 0x48);
+    assert_eq!(ppu.read_register(0x2007), 0x49);
+    assert_eq!(ppu.read_vram(0x2042), 0x00);
+
+    // A sprite palette write to $3F10 lands on the shared backdrop at $3F00
+    ppu.write_register(0x2006, 0x3F);
+    ppu.write_register(0x2006, 0x10);
+    ppu.write_register(0x2007, 0x2C);
+    ppu.write_register(0x2006, 0x3F);
+    ppu.write_register(0x2006, 0x00);
+    assert_eq!(ppu.read_register(0x2007), 0x2C);
+  }
+
+  #[test]
+  fn test_pre_render_scanline() {
+    let mut ppu = Ppu::new();
+    ppu.tick(dots_until(261, 0));
+    ppu.status = STATUS_VBLANK | STATUS_SPRITE_ZERO_HIT | STATUS_SPRITE_OVERFLOW;
+    ppu.mask = MASK_SHOW_BACKGROUND;
+    ppu.oam_addr = 0x40;
+    ppu.t = 0x7A83; // fine Y 7, nametable 2, coarse Y 20, coarse X 3
+    ppu.v = 0;
+
+    ppu.pre_render_scanline();
+
+    assert_eq!(ppu.status, 0);
+    assert_eq!(ppu.v, ppu.t);
+    assert_eq!(ppu.oam_addr, 0);
+    assert_eq!((ppu.scanline, ppu.dot), (0, 0));
+    assert_eq!(ppu.frame_count, 1);
+  }
+
+  #[test]
+  fn test_pre_render_scanline_rendering_disabled() {
+    let mut ppu = Ppu::new();
+    ppu.tick(dots_until(261, 0));
+    ppu.status = STATUS_VBLANK;
+    ppu.t = 0x7FFF;
+
+    ppu.pre_render_scanline();
+
+    assert_eq!(ppu.status, 0);
+    assert_eq!(ppu.v, 0);
+    assert_eq!(ppu.cycles, dots_until(262, 0) as u64);
+  }
+
+  #[test]
+  fn test_sprite_evaluation_limit_and_overflow() {
+    let mut ppu = Ppu::new();
+    ppu.oam = [0xFF; 256];
+    // Nine sprites on scanline 50, one that ends just above it
+    for i in 0..9 {
+      ppu.oam[i * 4 .. i * 4 + 4].copy_from_slice(&[48, i as u8, 0x00, i as u8 * 10]);
+    }
+    ppu.oam[40 .. 44].copy_from_slice(&[42, 0x20, 0x00, 0x00]);
+
+    let sprites = ppu.sprite_evaluation(50);
+    assert!(sprites.iter().all(|sprite| sprite.is_some()));
+    assert_eq!(sprites[0], Some(SpriteData { tile: 0, x: 0, attributes: 0, row: 2 }));
+    assert_eq!(sprites[7].unwrap().tile, 7);
+    assert_ne!(ppu.status & STATUS_SPRITE_OVERFLOW, 0);
+
+    // Eight fit without overflow, and 8x16 sprites reach further down
+    ppu.status = 0;
+    ppu.oam[28] = 0xFF;
+    ppu.oam[32] = 0xFF;
+    ppu.write_register(0x2000, CTRL_SPRITE_SIZE);
+    let sprites = ppu.sprite_evaluation(50);
+    assert_eq!(sprites[7], Some(SpriteData { tile: 0x20, x: 0, attributes: 0, row: 8 }));
+    assert_eq!(ppu.status & STATUS_SPRITE_OVERFLOW, 0);
+  }
+
+  #[test]
+  fn test_fetch_sprite_pattern_flips() {
+    let mut ppu = Ppu::new();
+    // Tile 2: row 0 is pixels 1,2,3,0,0,0,0,0, row 7 is all 3
+    let mut chr = vec![0x00; 48];
+    chr[32] = 0b1010_0000;
+    chr[40] = 0b0110_0000;
+    chr[39] = 0xFF;
+    chr[47] = 0xFF;
+    ppu.load_chr_data(&chr);
+
+    assert_eq!(ppu.fetch_sprite_pattern(2, 0, 0b0000_0000), [1, 2, 3, 0, 0, 0, 0, 0]);
+    assert_eq!(ppu.fetch_sprite_pattern(2, 0, 0b0100_0000), [0, 0, 0, 0, 0, 3, 2, 1]);
+    assert_eq!(ppu.fetch_sprite_pattern(2, 0, 0b1000_0000), [3; 8]);
+    assert_eq!(ppu.fetch_sprite_pattern(2, 7, 0b1000_0000), [1, 2, 3, 0, 0, 0, 0, 0]);
+    assert_eq!(ppu.fetch_sprite_pattern(2, 7, 0b1100_0000), [0, 0, 0, 0, 0, 3, 2, 1]);
+  }
+
+  #[test]
+  fn test_fetch_sprite_pattern_8x16() {
+    let mut ppu = Ppu::new();
+    let mut chr = vec![0x00; 0x2000];
+    chr[0x1000 + 2 * 16] = 0xFF; // Tile 2 of the right table, top row: color 1
+    chr[0x1000 + 3 * 16 + 15] = 0xFF; // Tile 3, bottom row: color 2
+    ppu.load_chr_data(&chr);
+    ppu.write_register(0x2000, CTRL_SPRITE_SIZE);
+
+    // Odd tile number picks $1000, the lower half comes from the next tile
+    assert_eq!(ppu.fetch_sprite_pattern(3, 0, 0), [1; 8]);
+    assert_eq!(ppu.fetch_sprite_pattern(3, 15, 0), [2; 8]);
+    assert_eq!(ppu.fetch_sprite_pattern(3, 0, 0b1000_0000), [2; 8]);
+    assert_eq!(ppu.fetch_sprite_pattern(2, 0, 0), [0; 8]);
+  }
+
+  #[test]
+  fn test_apply_palette_index() {
+    for pixel in 1..4u8 {
+      assert_eq!(Ppu::apply_palette_index(pixel, 0, false), 0x3F00 + pixel as u16);
+      assert_eq!(Ppu::apply_palette_index(pixel, 2, false), 0x3F08 + pixel as u16);
+      assert_eq!(Ppu::apply_palette_index(pixel, 0, true), 0x3F10 + pixel as u16);
+      assert_eq!(Ppu::apply_palette_index(pixel, 3, true), 0x3F1C + pixel as u16);
+    }
+
+    for palette in 0..4 {
+      assert_eq!(Ppu::apply_palette_index(0, palette, false), 0x3F00);
+      assert_eq!(Ppu::apply_palette_index(0, palette, true), 0x3F00);
+    }
+  }
+}
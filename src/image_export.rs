@@ -0,0 +1,228 @@
+#![allow(unused)]
+/* The following code writes frames and CHR tile sheets out as PNG and PPM images */
+
+use std::fs;
+use std::io;
+use std::path::Path;
+use crate::hardware::cartridge::Cartridge;
+use crate::hardware::frame::{Frame, HEIGHT, WIDTH};
+use crate::hardware::ppu::Ppu;
+
+const PNG_SIGNATURE: [u8; 8] = [0x89, 0x50, 0x4E, 0x47, 0x0D, 0x0A, 0x1A, 0x0A];
+const STORED_BLOCK_MAX: usize = 0xFFFF; // Largest uncompressed deflate block
+const TILES_PER_ROW: usize = 16;
+const CHR_SHADES: [u8; 4] = [0x00, 0x55, 0xAA, 0xFF]; // Pixel values 0-3 as gray levels
+
+// CRC-32 (ISO-HDLC) as used by PNG chunks
+fn crc32(data: &[u8]) -> u32 {
+  let mut crc = 0xFFFF_FFFFu32;
+  for byte in data {
+    crc ^= *byte as u32;
+    for _ in 0..8 {
+      crc = if crc & 1 != 0 { (crc >> 1) ^ 0xEDB8_8320 } else { crc >> 1 };
+    }
+  }
+  !crc
+}
+
+// Adler-32 checksum that ends a zlib stream
+fn adler32(data: &[u8]) -> u32 {
+  let (mut a, mut b) = (1u32, 0u32);
+  for byte in data {
+    a = (a + *byte as u32) % 65521;
+    b = (b + a) % 65521;
+  }
+  (b << 16) | a
+}
+
+// Wrap data in a zlib stream made of uncompressed (stored) deflate blocks
+fn zlib_stored(data: &[u8]) -> Vec<u8> {
+  let mut out = vec![0x78, 0x01];
+  let mut blocks = data.chunks(STORED_BLOCK_MAX).peekable();
+  if blocks.peek().is_none() {
+    out.extend([0x01, 0x00, 0x00, 0xFF, 0xFF]);
+  }
+  while let Some(block) = blocks.next() {
+    let last = blocks.peek().is_none() as u8;
+    let len = block.len() as u16;
+    out.push(last);
+    out.extend(len.to_le_bytes());
+    out.extend((!len).to_le_bytes());
+    out.extend(block);
+  }
+  out.extend(adler32(data).to_be_bytes());
+  out
+}
+
+fn push_chunk(png: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+  png.extend((data.len() as u32).to_be_bytes());
+  let start = png.len();
+  png.extend(kind);
+  png.extend(data);
+  let crc = crc32(&png[start..]);
+  png.extend(crc.to_be_bytes());
+}
+
+// Encode 8 bit RGB pixels (row by row, no padding) as a PNG file
+pub fn encode_png(width: usize, height: usize, rgb: &[u8]) -> Vec<u8> {
+  let mut header = vec![];
+  header.extend((width as u32).to_be_bytes());
+  header.extend((height as u32).to_be_bytes());
+  header.extend([8, 2, 0, 0, 0]); // 8 bit depth, truecolor, deflate, no filter, no interlace
+
+  // Every scanline starts with its filter type (0 = none)
+  let mut raw = Vec::with_capacity((width * 3 + 1) * height);
+  for row in rgb.chunks(width * 3).take(height) {
+    raw.push(0);
+    raw.extend(row);
+  }
+
+  let mut png = PNG_SIGNATURE.to_vec();
+  push_chunk(&mut png, b"IHDR", &header);
+  push_chunk(&mut png, b"IDAT", &zlib_stored(&raw));
+  push_chunk(&mut png, b"IEND", &[]);
+  png
+}
+
+// Save a rendered frame as a 256x240 PNG
+pub fn frame_to_png(frame: &Frame, path: &Path) -> io::Result<()> {
+  fs::write(path, encode_png(WIDTH, HEIGHT, &frame.data))
+}
+
+// Save a 256x240 RGB buffer (like Frame::data) as a binary PPM, which any image viewer reads
+// and needs no compression, handy for dumping test output
+pub fn save_frame_ppm(frame: &[u8], path: &Path) -> io::Result<()> {
+  if frame.len() != WIDTH * HEIGHT * 3 {
+    return Err(io::Error::new(io::ErrorKind::InvalidInput, "frame should be 256x240 RGB"));
+  }
+  let mut ppm = format!("P6\n{} {}\n255\n", WIDTH, HEIGHT).into_bytes();
+  ppm.extend(frame);
+  fs::write(path, ppm)
+}
+
+// Save every CHR tile in the cartridge as a gray tile sheet, 16 tiles per row
+pub fn chr_to_png(cartridge: &Cartridge, path: &Path) -> io::Result<()> {
+  if cartridge.chr_rom.is_empty() {
+    return Err(io::Error::new(io::ErrorKind::InvalidInput, "cartridge has no CHR ROM"));
+  }
+
+  let tiles = cartridge.chr_rom.len() / 16;
+  let width = TILES_PER_ROW * 8;
+  let height = tiles.div_ceil(TILES_PER_ROW) * 8;
+  let mut rgb = vec![0x00; width * height * 3];
+
+  for (tile, data) in cartridge.chr_rom.chunks(16).enumerate() {
+    let tile_x = (tile % TILES_PER_ROW) * 8;
+    let tile_y = (tile / TILES_PER_ROW) * 8;
+    for row in 0..8 {
+      for (x, pixel) in Ppu::combine_bitplanes(data[row], data[row + 8]).iter().enumerate() {
+        let base = ((tile_y + row) * width + tile_x + x) * 3;
+        rgb[base .. base + 3].copy_from_slice(&[CHR_SHADES[*pixel as usize]; 3]);
+      }
+    }
+  }
+
+  fs::write(path, encode_png(width, height, &rgb))
+}
+
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use crate::hardware::cartridge::Mirroring;
+
+  // Pull the width and height out of the IHDR chunk
+  fn png_size(png: &[u8]) -> (u32, u32) {
+    let read_u32 = |at: usize| u32::from_be_bytes([png[at], png[at + 1], png[at + 2], png[at + 3]]);
+    (read_u32(16), read_u32(20))
+  }
+
+  #[test]
+  fn test_checksums() {
+    assert_eq!(crc32(b"IEND"), 0xAE42_6082);
+    assert_eq!(adler32(b"Wikipedia"), 0x11E6_0398);
+  }
+
+  #[test]
+  fn test_frame_to_png() {
+    let mut frame = Frame::new();
+    frame.set_pixel(0, 0, (0xFF, 0x00, 0x00));
+    let file = tempfile::NamedTempFile::new().unwrap();
+
+    frame_to_png(&frame, file.path()).unwrap();
+    let png = fs::read(file.path()).unwrap();
+
+    assert_eq!(&png[..8], &PNG_SIGNATURE);
+    assert_eq!(&png[12..16], b"IHDR");
+    assert_eq!(png_size(&png), (256, 240));
+    assert_eq!(&png[png.len() - 8 .. png.len() - 4], b"IEND");
+    // First stored block starts with row 0's filter byte then the red pixel
+    let idat = 8 + 25 + 8;
+    assert_eq!(&png[idat + 7 .. idat + 11], &[0x00, 0xFF, 0x00, 0x00]);
+  }
+
+  #[test]
+  fn test_save_frame_ppm() {
+    let mut ppu = Ppu::new();
+    // Tile 0 solid color 3 across the whole nametable, with one tile of color 1 at (2, 1)
+    for row in 0..16 {
+      ppu.chr_rom[row] = 0xFF;
+      ppu.chr_rom[16 + row] = if row < 8 { 0xFF } else { 0x00 };
+    }
+    ppu.write_vram(0x2000 + 32 + 2, 0x01);
+    ppu.write_vram(0x3F01, 0x16);
+    ppu.write_vram(0x3F03, 0x2A);
+    let frame = ppu.render_background();
+    let file = tempfile::NamedTempFile::new().unwrap();
+
+    save_frame_ppm(&frame.data, file.path()).unwrap();
+    let ppm = fs::read(file.path()).unwrap();
+
+    let header = b"P6\n256 240\n255\n";
+    assert_eq!(&ppm[..header.len()], header);
+    assert_eq!(ppm.len(), header.len() + 256 * 240 * 3);
+    let pixel = |x: usize, y: usize| {
+      let base = header.len() + (y * 256 + x) * 3;
+      (ppm[base], ppm[base + 1], ppm[base + 2])
+    };
+    assert_eq!(pixel(20, 12), ppu.palette.resolve(0x16, 0));
+    assert_eq!(pixel(100, 100), ppu.palette.resolve(0x2A, 0));
+
+    assert_eq!(save_frame_ppm(&[0; 3], file.path()).unwrap_err().kind(), io::ErrorKind::InvalidInput);
+  }
+
+  #[test]
+  fn test_chr_to_png() {
+    let cartridge = Cartridge {
+      prg_rom: vec![0x00; 0x4000],
+      chr_rom: vec![0xFF; 0x2000],
+      mapper: 0,
+      screen_mirroring: Mirroring::Horizontal,
+    };
+    let file = tempfile::NamedTempFile::new().unwrap();
+
+    chr_to_png(&cartridge, file.path()).unwrap();
+    let png = fs::read(file.path()).unwrap();
+
+    // 512 tiles, 16 across
+    assert_eq!(png_size(&png), (128, 256));
+  }
+
+  #[test]
+  fn test_export_errors_propagate() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("missing").join("frame.png");
+
+    let err = frame_to_png(&Frame::new(), &path).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::NotFound);
+
+    let cartridge = Cartridge {
+      prg_rom: vec![0x00; 0x4000],
+      chr_rom: vec![],
+      mapper: 0,
+      screen_mirroring: Mirroring::Horizontal,
+    };
+    let err = chr_to_png(&cartridge, &dir.path().join("chr.png")).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+  }
+}
@@ -0,0 +1,160 @@
+#![allow(unused)]
+/* The following code maps keyboard keys to controller buttons for frontends */
+
+use std::collections::HashMap;
+use crate::hardware::joypad::{Button, Joypad};
+
+// Arrows for the d-pad, Z/X for B/A, Enter for Start and right shift for Select.
+// Keys use SDL scancode names (Scancode::name), compared without case
+const DEFAULT_BINDINGS: [(&str, Button); 8] = [
+  ("X", Button::A),
+  ("Z", Button::B),
+  ("Right Shift", Button::Select),
+  ("Return", Button::Start),
+  ("Up", Button::Up),
+  ("Down", Button::Down),
+  ("Left", Button::Left),
+  ("Right", Button::Right),
+];
+
+// Button names as written in a key map config
+fn button_from_name(name: &str) -> Option<Button> {
+  Button::ALL.iter().copied().find(|button| format!("{:?}", button).eq_ignore_ascii_case(name))
+}
+
+// One key per button. The frontend feeds its key events through here and the loop stays dumb
+pub struct KeyMap {
+  bindings: HashMap<String, Button>, // Lowercase key name to button
+}
+
+impl KeyMap {
+  // KeyMap constructor (nothing bound)
+  pub fn new() -> Self {
+    KeyMap { bindings: HashMap::new() }
+  }
+
+  // Bind key to button, replacing whatever key the button had before
+  pub fn bind(&mut self, key: &str, button: Button) {
+    self.bindings.retain(|_, bound| *bound != button);
+    self.bindings.insert(key.to_lowercase(), button);
+  }
+
+  // Button a key is bound to, if any
+  pub fn button_for(&self, key: &str) -> Option<Button> {
+    self.bindings.get(&key.to_lowercase()).copied()
+  }
+
+  // Key bound to a button, if any
+  pub fn key_for(&self, button: Button) -> Option<&str> {
+    self.bindings.iter().find(|(_, bound)| **bound == button).map(|(key, _)| key.as_str())
+  }
+
+  // Parse a config of "Button = Key" lines on top of the default bindings, # starts a comment:
+  //   Start = Space
+  //   A = Left Ctrl
+  pub fn parse(config: &str) -> Result<KeyMap, String> {
+    let mut keymap = KeyMap::default();
+    for (number, line) in config.lines().enumerate() {
+      let line = line.split('#').next().unwrap_or("").trim();
+      if line.is_empty() {
+        continue;
+      }
+      let (button, key) = line.split_once('=')
+        .ok_or(format!("line {}: expected 'Button = Key'", number + 1))?;
+      let button = button_from_name(button.trim())
+        .ok_or(format!("line {}: unknown button '{}'", number + 1, button.trim()))?;
+      let key = key.trim();
+      if key.is_empty() {
+        return Err(format!("line {}: no key for {:?}", number + 1, button));
+      }
+      keymap.bind(key, button);
+    }
+    Ok(keymap)
+  }
+
+  // Apply a key event to the joypad, returning true if the key is bound. Auto-repeated keydowns
+  // are ignored since only the press and release change what's held
+  pub fn key_event(&self, joypad: &mut Joypad, key: &str, pressed: bool, repeat: bool) -> bool {
+    match self.button_for(key) {
+      Some(button) => {
+        if !repeat {
+          joypad.set_button(button, pressed);
+        }
+        true
+      },
+      None => false
+    }
+  }
+}
+
+impl Default for KeyMap {
+  fn default() -> Self {
+    let mut keymap = KeyMap::new();
+    for (key, button) in DEFAULT_BINDINGS {
+      keymap.bind(key, button);
+    }
+    keymap
+  }
+}
+
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn test_default_bindings() {
+    let keymap = KeyMap::default();
+    assert_eq!(keymap.button_for("Up"), Some(Button::Up));
+    assert_eq!(keymap.button_for("x"), Some(Button::A));
+    assert_eq!(keymap.button_for("Return"), Some(Button::Start));
+    assert_eq!(keymap.button_for("right shift"), Some(Button::Select));
+    assert_eq!(keymap.button_for("Space"), None);
+    assert!(Button::ALL.iter().all(|button| keymap.key_for(*button).is_some()));
+  }
+
+  #[test]
+  fn test_rebinding_moves_the_button() {
+    let mut keymap = KeyMap::default();
+    keymap.bind("Space", Button::A);
+
+    assert_eq!(keymap.button_for("Space"), Some(Button::A));
+    assert_eq!(keymap.button_for("X"), None);
+    assert_eq!(keymap.key_for(Button::A), Some("space"));
+  }
+
+  #[test]
+  fn test_parse_config() {
+    let keymap = KeyMap::parse("
+      # WASD for the d-pad
+      up = W
+      Left = A   # overrides the arrow key
+      Start = Space
+    ").unwrap();
+    assert_eq!(keymap.button_for("w"), Some(Button::Up));
+    assert_eq!(keymap.button_for("Left"), None);
+    assert_eq!(keymap.button_for("Space"), Some(Button::Start));
+    assert_eq!(keymap.button_for("Down"), Some(Button::Down)); // Untouched default
+
+    assert_eq!(KeyMap::parse("Turbo = T").err(), Some(String::from("line 1: unknown button 'Turbo'")));
+    assert_eq!(KeyMap::parse("\nA X").err(), Some(String::from("line 2: expected 'Button = Key'")));
+    assert_eq!(KeyMap::parse("B =").err(), Some(String::from("line 1: no key for B")));
+  }
+
+  #[test]
+  fn test_key_events_ignore_repeat() {
+    let keymap = KeyMap::default();
+    let mut joypad = Joypad::new();
+
+    assert!(keymap.key_event(&mut joypad, "X", true, false));
+    assert!(joypad.is_pressed(Button::A));
+
+    // A repeat after the key was released (events arriving out of order) doesn't press it again
+    assert!(keymap.key_event(&mut joypad, "X", false, false));
+    assert!(keymap.key_event(&mut joypad, "X", true, true));
+    assert!(!joypad.is_pressed(Button::A));
+
+    assert!(!keymap.key_event(&mut joypad, "Q", true, false));
+    assert_eq!(joypad.buttons(), 0);
+  }
+}
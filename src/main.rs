@@ -1,6 +1,75 @@
 
 // Import hardware module containing everything we need
 mod hardware;
+mod assembler;
+mod image_export;
+mod ntsc;
+mod debugger;
+mod audio;
+mod keymap;
 
+use std::env;
+use std::path::Path;
+use std::process;
+use hardware::cartridge;
+use hardware::cpu::CPU;
+
+const DEFAULT_DUMP_FRAMES: u64 = 600;
+
+// nes_emulator --dump-audio out.wav [--frames N] (game.nes | --beep)
+// Runs the ROM headlessly and writes what it played to a WAV file. --beep plays
+// audio::BEEP_PROGRAM instead of a ROM, to check the audio path by ear
 pub fn main() {
+  let args: Vec<String> = env::args().skip(1).collect();
+  let mut dump_audio = None;
+  let mut frames = DEFAULT_DUMP_FRAMES;
+  let mut rom = None;
+  let mut beep = false;
+
+  let mut args = args.iter();
+  while let Some(arg) = args.next() {
+    match arg.as_str() {
+      "--dump-audio" => { dump_audio = args.next(); },
+      "--beep" => { beep = true; },
+      "--frames" => {
+        frames = match args.next().and_then(|count| count.parse().ok()) {
+          Some(frames) => frames,
+          None => exit_with("--frames needs a frame count")
+        };
+      },
+      _ => { rom = Some(arg); }
+    }
+  }
+
+  let usage = "usage: nes_emulator --dump-audio out.wav [--frames N] (game.nes | --beep)";
+  let Some(wav) = dump_audio else {
+    exit_with(usage);
+  };
+
+  let mut cpu = CPU::new();
+  match (rom, beep) {
+    (None, true) => {
+      match assembler::assemble(audio::BEEP_PROGRAM, 0x8000) {
+        Ok(program) => cpu.load(program),
+        Err(err) => exit_with(&format!("could not assemble the beep program: {}", err))
+      }
+    },
+    (Some(rom), false) => {
+      match cartridge::load_from_file(Path::new(rom)) {
+        Ok(cartridge) => cpu.bus.insert_cartridge(cartridge),
+        Err(err) => exit_with(&format!("could not load {}: {}", rom, err))
+      }
+    },
+    _ => exit_with(usage)
+  }
+  cpu.reset();
+  match audio::dump_audio(&mut cpu, frames, Path::new(wav)) {
+    Ok(samples) => println!("wrote {} samples to {}", samples, wav),
+    Err(err) => exit_with(&format!("could not dump audio: {}", err))
+  }
+}
+
+fn exit_with(message: &str) -> ! {
+  eprintln!("{}", message);
+  process::exit(1);
 }
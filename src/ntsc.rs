@@ -0,0 +1,175 @@
+#![allow(unused)]
+/* The following code simulates the NES's NTSC composite video signal as a post-processing filter */
+
+use std::f32::consts::PI;
+use crate::hardware::frame::{Frame, HEIGHT, WIDTH};
+
+pub const NTSC_WIDTH: usize = 602; // Output width, roughly the 8:7 pixel aspect of a real TV
+const SAMPLES_PER_PIXEL: usize = 8; // The PPU outputs 8 signal samples per dot (21.48MHz)
+const SAMPLES_PER_LINE: usize = WIDTH * SAMPLES_PER_PIXEL;
+const PHASES: usize = 12; // One color subcarrier cycle is 12 samples
+
+// Composite voltages for the four luma levels, normalized between black and white
+const LEVELS_LOW: [f32; 4] = [0.228, 0.312, 0.552, 0.880];
+const LEVELS_HIGH: [f32; 4] = [0.616, 0.840, 1.100, 1.100];
+const BLACK: f32 = 0.312;
+const WHITE: f32 = 1.100;
+const EMPHASIS_ATTENUATION: f32 = 0.746;
+const HUE_OFFSET: f32 = 3.9; // Decoder phase tweak (in samples) so hues line up with the standard palette
+
+// Returns true while the square wave for hue `color` is high at the given subcarrier phase
+fn in_color_phase(color: u16, phase: usize) -> bool {
+  (color as usize + phase) % PHASES < 6
+}
+
+// Signal level for one sample of a pixel (index is a Frame index: color + emphasis bits 6-8)
+fn signal_level(index: u16, phase: usize) -> f32 {
+  let color = index & 0x0F;
+  let level = if color > 0x0D { 1 } else { ((index >> 4) & 0b11) as usize };
+  let emphasis = index >> 6;
+
+  let mut low = LEVELS_LOW[level];
+  let mut high = LEVELS_HIGH[level];
+  if color == 0x00 {
+    low = high;
+  }
+  if color > 0x0C {
+    high = low;
+  }
+  let mut signal = if in_color_phase(color, phase) { high } else { low };
+
+  let emphasized = (emphasis & 0b001 != 0 && in_color_phase(0x0, phase))
+    || (emphasis & 0b010 != 0 && in_color_phase(0x4, phase))
+    || (emphasis & 0b100 != 0 && in_color_phase(0x8, phase));
+  if emphasized && color < 0x0E {
+    signal *= EMPHASIS_ATTENUATION;
+  }
+
+  (signal - BLACK) / (WHITE - BLACK)
+}
+
+// Turns a frame's color indices into a wider RGB image with NTSC fringing and dot crawl
+pub struct NtscFilter {
+  cos_table: [f32; PHASES],
+  sin_table: [f32; PHASES],
+  signal: Vec<f32>, // One scanline of samples, reused between lines
+}
+
+impl NtscFilter {
+  // NtscFilter constructor
+  pub fn new() -> Self {
+    let mut cos_table = [0.0; PHASES];
+    let mut sin_table = [0.0; PHASES];
+    for phase in 0..PHASES {
+      let angle = PI * (phase as f32 + HUE_OFFSET) / 6.0;
+      cos_table[phase] = angle.cos();
+      sin_table[phase] = angle.sin();
+    }
+    NtscFilter { cos_table, sin_table, signal: vec![0.0; SAMPLES_PER_LINE] }
+  }
+
+  // Filter a whole frame into NTSC_WIDTH x HEIGHT RGB triples. frame_count picks the dot crawl
+  // phase, which shifts every frame (and by 4 samples every scanline)
+  pub fn apply(&mut self, frame: &Frame, frame_count: u64) -> Vec<u8> {
+    let mut out = vec![0x00; NTSC_WIDTH * HEIGHT * 3];
+    let frame_phase = (frame_count % 3) as usize * 4;
+
+    for y in 0..HEIGHT {
+      let line_phase = (frame_phase + y * 4) % PHASES;
+
+      // Encode
+      for x in 0..WIDTH {
+        let index = frame.indices[y * WIDTH + x];
+        for sample in 0..SAMPLES_PER_PIXEL {
+          let position = x * SAMPLES_PER_PIXEL + sample;
+          self.signal[position] = signal_level(index, (line_phase + position) % PHASES);
+        }
+      }
+
+      // Decode one subcarrier cycle around each output pixel back into YIQ, then RGB
+      for x in 0..NTSC_WIDTH {
+        let center = x * SAMPLES_PER_LINE / NTSC_WIDTH;
+        let begin = center.saturating_sub(PHASES / 2);
+        let end = (center + PHASES / 2).min(SAMPLES_PER_LINE);
+
+        let (mut luma, mut i, mut q) = (0.0, 0.0, 0.0);
+        for position in begin..end {
+          let level = self.signal[position] / PHASES as f32;
+          let phase = (line_phase + position) % PHASES;
+          luma += level;
+          i += level * self.cos_table[phase];
+          q += level * self.sin_table[phase];
+        }
+
+        let to_byte = |value: f32| (value * 255.0).round().clamp(0.0, 255.0) as u8;
+        let base = (y * NTSC_WIDTH + x) * 3;
+        out[base] = to_byte(luma + 0.946_882 * i + 0.623_557 * q);
+        out[base + 1] = to_byte(luma - 0.274_788 * i - 0.635_691 * q);
+        out[base + 2] = to_byte(luma - 1.108_545 * i + 1.709_007 * q);
+      }
+    }
+
+    out
+  }
+}
+
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use std::time::Instant;
+
+  fn solid_frame(index: u16) -> Frame {
+    let mut frame = Frame::new();
+    frame.indices = vec![index; WIDTH * HEIGHT];
+    frame
+  }
+
+  fn pixel(out: &[u8], x: usize, y: usize) -> (u8, u8, u8) {
+    let base = (y * NTSC_WIDTH + x) * 3;
+    (out[base], out[base + 1], out[base + 2])
+  }
+
+  #[test]
+  fn test_black_and_white_stay_gray() {
+    let mut filter = NtscFilter::new();
+
+    let out = filter.apply(&solid_frame(0x0F), 0);
+    assert_eq!(out.len(), NTSC_WIDTH * HEIGHT * 3);
+    assert_eq!(pixel(&out, 300, 100), (0, 0, 0));
+
+    let out = filter.apply(&solid_frame(0x30), 0);
+    assert_eq!(pixel(&out, 300, 100), (255, 255, 255));
+  }
+
+  #[test]
+  fn test_hues_decode_to_their_color() {
+    let mut filter = NtscFilter::new();
+
+    // $16 is red, $1A green, $12 blue
+    let (r, g, b) = pixel(&filter.apply(&solid_frame(0x16), 0), 300, 100);
+    assert!(r > g && r > b, "{:?}", (r, g, b));
+    let (r, g, b) = pixel(&filter.apply(&solid_frame(0x1A), 0), 300, 100);
+    assert!(g > r && g > b, "{:?}", (r, g, b));
+    let (r, g, b) = pixel(&filter.apply(&solid_frame(0x12), 0), 300, 100);
+    assert!(b > r && b > g, "{:?}", (r, g, b));
+  }
+
+  #[test]
+  #[ignore] // Benchmark: cargo test --release bench_ntsc_filter -- --ignored --nocapture
+  fn bench_ntsc_filter() {
+    let mut filter = NtscFilter::new();
+    let mut frame = Frame::new();
+    for (i, index) in frame.indices.iter_mut().enumerate() {
+      *index = (i % 64) as u16;
+    }
+
+    let frames = 60;
+    let start = Instant::now();
+    for frame_count in 0..frames {
+      filter.apply(&frame, frame_count);
+    }
+    let elapsed = start.elapsed();
+    println!("{} frames in {:?} ({:?} per frame)", frames, elapsed, elapsed / frames as u32);
+  }
+}